@@ -1,114 +1,141 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, LitInt, Ident};
-
-pub fn generate_get_components(input: TokenStream) -> TokenStream {
-    let num_generics = parse_macro_input!(input as LitInt).base10_parse::<usize>().unwrap();
-
-    let methods = (1..=num_generics).map(|n| {
-        // Create identifiers for generics (C1, C2, ...)
-        let generics: Vec<Ident> = (1..=n)
-            .map(|i| Ident::new(&format!("C{}", i), proc_macro2::Span::call_site()))
-            .collect();
-
-        // Create identifiers for component variables (c1, c2, ...)
-        let c_vars: Vec<Ident> = (1..=n)
-            .map(|i| Ident::new(&format!("c{}", i), proc_macro2::Span::call_site()))
-            .collect();
-
-        // Create identifiers for indices (idx1, idx2, ...)
-        let idx_vars: Vec<Ident> = (1..=n)
-            .map(|i| Ident::new(&format!("idx{}", i), proc_macro2::Span::call_site()))
-            .collect();
-
-        // Immutable method
-        let fetch_components = quote! {
-            #(
-                let #c_vars = self.components.get(&TypeId::of::<#generics>())?.get_all::<#generics>();
-            )*
-        };
-
-        let nested_conditions = (0..n).rev().fold(quote! {
-            out.push((*en, #( *#c_vars ),*));
-        }, |acc, i| {
-            let idx_var = &idx_vars[i];
-            let c_var = &c_vars[i];
-            let generic = &generics[i];
-            quote! {
-                if let Some(#idx_var) = map.get(&TypeId::of::<#generic>()) {
-                    if let Some(#c_var) = #c_var.get(*#idx_var as usize) {
-                        #acc
-                    }
-                }
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, DeriveInput, Data, Fields, FnArg, ItemFn, LitStr, ReturnType};
+
+pub fn derive_component(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut storage = None;
+    let mut name = None;
+    let mut save = false;
+    let mut debug = false;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("component")) {
+        let result = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("storage") {
+                let value: LitStr = meta.value()?.parse()?;
+                storage = Some(match value.value().as_str() {
+                    "table" => quote! { mvengine::ecs::StorageType::Table },
+                    "sparse" => quote! { mvengine::ecs::StorageType::SparseSet },
+                    _ => return Err(meta.error("expected \"table\" or \"sparse\"")),
+                });
+                Ok(())
+            } else if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else if meta.path.is_ident("save") {
+                save = true;
+                Ok(())
+            } else if meta.path.is_ident("debug") {
+                debug = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected storage, name, save or debug"))
             }
         });
+        if let Err(e) = result {
+            return e.to_compile_error().into();
+        }
+    }
+
+    let fields: Vec<String> = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().filter_map(|f| f.ident.as_ref()).map(ToString::to_string).collect(),
+            Fields::Unnamed(fields) => (0..fields.unnamed.len()).map(|i| i.to_string()).collect(),
+            Fields::Unit => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
 
-        let method_name = Ident::new(&format!("get_components{}", n), proc_macro2::Span::call_site());
-        let immutable_method = quote! {
-            pub fn #method_name< #( #generics : Sized + 'static ),* >(&self)
-                -> Option<Vec<(EntityType, #( & #generics ),*)>> {
-
-                #fetch_components
-
-                let mut out = vec![];
-
-                for (en, map) in self.entity_components.iter() {
-                    #nested_conditions
-                }
+    let storage = storage.map(|storage| quote! {
+        const STORAGE: mvengine::ecs::StorageType = #storage;
+    });
+    let name = name.map(|name| quote! {
+        fn name() -> &'static str {
+            #name
+        }
+    });
+    let save = save.then(|| quote! {
+        world.register_savable::<Self>(<Self as mvengine::ecs::Component>::name());
+    });
+    let debug = debug.then(|| quote! {
+        world.register_debug::<Self>();
+    });
+    let register = (save.is_some() || debug.is_some()).then(|| quote! {
+        fn register(world: &mut mvengine::ecs::world::World) {
+            world.register_component::<Self>(Self::STORAGE);
+            #save
+            #debug
+        }
+    });
 
-                Some(out)
-            }
-        };
+    quote! {
+        impl #impl_generics mvengine::ecs::Component for #ident #ty_generics #where_clause {
+            #storage
 
-        // Mutable method
-        let fetch_components_mut = quote! {
-            #(
-                let mut #c_vars = self.components.get(&TypeId::of::<#generics>())?.get_all::<#generics>();
-            )*
-        };
+            #name
 
-        let nested_conditions_mut = (0..n).rev().fold(quote! {
-            out.push((*en, #( unsafe { (*#c_vars as *const #generics as *mut #generics).as_mut().unwrap() } ),*));
-        }, |acc, i| {
-            let idx_var = &idx_vars[i];
-            let c_var = &c_vars[i];
-            let generic = &generics[i];
-            quote! {
-                if let Some(#idx_var) = map.get(&TypeId::of::<#generic>()) {
-                    if let Some(#c_var) = #c_var.get_mut(*#idx_var as usize) {
-                        #acc
-                    }
-                }
+            fn fields() -> &'static [&'static str] {
+                &[#(#fields),*]
             }
-        });
 
-        let method_name_mut = Ident::new(&format!("get_components{}_mut", n), proc_macro2::Span::call_site());
-        let mutable_method = quote! {
-            pub fn #method_name_mut< #( #generics : Sized + 'static ),* >(&mut self)
-                -> Option<Vec<(EntityType, #( &mut #generics ),*)>> {
-
-                #fetch_components_mut
-
-                let mut out = vec![];
-
-                for (en, map) in self.entity_components.iter_mut() {
-                    #nested_conditions_mut
-                }
+            #register
+        }
+    }.into()
+}
 
-                Some(out)
+pub fn system(_attrib: TokenStream, item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as ItemFn);
+    let sig = &input.sig;
+    if !sig.generics.params.is_empty() || sig.asyncness.is_some() {
+        return syn::Error::new(sig.span(), "systems can not be generic or async").to_compile_error().into();
+    }
+    if let ReturnType::Type(_, ty) = &sig.output {
+        return syn::Error::new(ty.span(), "systems can not return a value").to_compile_error().into();
+    }
+
+    let mut types = Vec::new();
+    for arg in &sig.inputs {
+        match arg {
+            FnArg::Typed(arg) => types.push(arg.ty.as_ref()),
+            FnArg::Receiver(receiver) => {
+                return syn::Error::new(receiver.span(), "systems can not take self").to_compile_error().into();
             }
-        };
-
-        quote! {
-            #immutable_method
-            #mutable_method
         }
-    });
-
-    // Combine all generated methods into one TokenStream
-    let expanded = quote! {
-        #( #methods )*
-    };
-
-    TokenStream::from(expanded)
+    }
+    let indices = (0..types.len()).map(syn::Index::from);
+
+    let attrs = &input.attrs;
+    let vis = &input.vis;
+    let ident = &sig.ident;
+    let name = ident.to_string();
+    let inner = format_ident!("__{}", ident);
+    let inputs = &sig.inputs;
+    let block = &input.block;
+
+    quote! {
+        #(#attrs)*
+        #vis fn #ident(storage: mvengine::ecs::EcsStorage) -> Box<dyn mvengine::ecs::schedule::ScheduledSystem> {
+            #[allow(clippy::needless_pass_by_value)]
+            fn #inner(#inputs) #block
+
+            let accesses = [#({
+                let mut access = mvengine::ecs::schedule::Access::default();
+                <#types as mvengine::ecs::param::SystemParam>::access(&mut access);
+                access
+            }),*];
+            let state = (#(<#types as mvengine::ecs::param::SystemParam>::init(&storage),)*);
+            Box::new(mvengine::ecs::param::ParamSystem::new(
+                concat!(module_path!(), "::", #name),
+                storage,
+                &accesses,
+                state,
+                |state, storage| unsafe {
+                    #inner(#(<#types as mvengine::ecs::param::SystemParam>::get(&mut state.#indices, storage)),*);
+                },
+            ))
+        }
+    }.into()
 }
@@ -5,11 +5,6 @@ mod r;
 mod ui;
 mod uix;
 
-#[proc_macro]
-pub fn generate_get_components(input: TokenStream) -> TokenStream {
-    ecs::generate_get_components(input)
-}
-
 #[proc_macro]
 pub fn ui(input: TokenStream) -> TokenStream {
     ui::ui(input)
@@ -23,4 +18,19 @@ pub fn uix(attrib: TokenStream, input: TokenStream) -> TokenStream {
 #[proc_macro]
 pub fn r(input: TokenStream) -> TokenStream {
     r::r(input)
-}
\ No newline at end of file
+}
+
+/// Implements `mvengine::ecs::Component`. Accepts `#[component(storage = "sparse", name = "...", save, debug)]`.
+/// When the type is passed to `World::register`, `save` registers it for world snapshots, which requires it to
+/// implement `Savable`, and `debug` lets the inspector show its values, which requires it to implement `Debug`.
+#[proc_macro_derive(Component, attributes(component))]
+pub fn derive_component(input: TokenStream) -> TokenStream {
+    ecs::derive_component(input)
+}
+
+/// Turns a function taking `SystemParam`s, like `Query<(&mut Pos, &Vel)>`, `Res<Time>` or `Commands`, into a
+/// function that can be passed to `World::add_system`. The function can not return a value.
+#[proc_macro_attribute]
+pub fn system(attrib: TokenStream, input: TokenStream) -> TokenStream {
+    ecs::system(attrib, input)
+}
@@ -0,0 +1,126 @@
+//! Compares the archetype storage against the previous layout, where every entity owned a `TypeId -> index` map and
+//! every query collected a fresh `Vec` of references. Run with `cargo bench --bench ecs_storage`.
+//!
+//! The previous storage no longer exists in the crate, so the legacy numbers come from [`LegacyStorage`], a
+//! hand-written approximation of it rather than the original code. It keeps the per-entity maps and the per-query
+//! `Vec`, but stores components in typed `Vec`s behind the std hasher instead of `ContinuousBlob`s behind identity
+//! hashers, and does not collect every component of a type into a `Vec` before matching. Its numbers only show the
+//! order of magnitude of the difference.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use mvengine::ecs::entity::{Entity, NoBehavior};
+use mvengine::ecs::system::System;
+use mvengine::ecs::{Component, ECS};
+
+const ENTITIES: usize = 100_000;
+const FRAMES: usize = 100;
+
+#[derive(Default, Clone)]
+struct Position(f32, f32);
+
+#[derive(Default, Clone)]
+struct Velocity(f32, f32);
+
+#[derive(Default, Clone)]
+struct Sprite(u32);
+
+impl Component for Position {}
+impl Component for Velocity {}
+impl Component for Sprite {}
+
+/// Approximation of the per-entity map layout the archetype storage replaced, not the original code.
+struct LegacyStorage {
+    components: HashMap<TypeId, Box<dyn Any>>,
+    entity_components: HashMap<u64, HashMap<TypeId, usize>>,
+}
+
+impl LegacyStorage {
+    fn new() -> Self {
+        Self { components: HashMap::new(), entity_components: HashMap::new() }
+    }
+
+    fn set_component<T: 'static>(&mut self, entity: u64, component: T) {
+        let blob = self.components.entry(TypeId::of::<T>()).or_insert_with(|| Box::new(Vec::<T>::new()));
+        let Some(blob) = blob.downcast_mut::<Vec<T>>() else { return; };
+        blob.push(component);
+        self.entity_components.entry(entity).or_default().insert(TypeId::of::<T>(), blob.len() - 1);
+    }
+
+    fn get_components2_mut<A: 'static, B: 'static>(&mut self) -> Vec<(u64, &mut A, &mut B)> {
+        let a = self.components.get(&TypeId::of::<A>()).and_then(|b| b.downcast_ref::<Vec<A>>()).map_or(std::ptr::null_mut(), |v| v.as_ptr().cast_mut());
+        let b = self.components.get(&TypeId::of::<B>()).and_then(|b| b.downcast_ref::<Vec<B>>()).map_or(std::ptr::null_mut(), |v| v.as_ptr().cast_mut());
+        let mut out = vec![];
+        for (en, map) in &self.entity_components {
+            if let (Some(ia), Some(ib)) = (map.get(&TypeId::of::<A>()), map.get(&TypeId::of::<B>())) {
+                unsafe { out.push((*en, &mut *a.add(*ia), &mut *b.add(*ib))); }
+            }
+        }
+        out
+    }
+}
+
+fn report(name: &str, spawn: Duration, frames: Duration) {
+    println!(
+        "{name:<10} spawn {:>8.2} ms   iterate {:>8.3} ms/frame",
+        spawn.as_secs_f64() * 1000.0,
+        frames.as_secs_f64() * 1000.0 / FRAMES as f64
+    );
+}
+
+fn legacy() {
+    let start = Instant::now();
+    let mut storage = LegacyStorage::new();
+    for i in 0..ENTITIES as u64 {
+        storage.set_component(i, Position::default());
+        storage.set_component(i, Velocity(1.0, 0.5));
+        if i % 2 == 0 {
+            storage.set_component(i, Sprite(0));
+        }
+    }
+    let spawn = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        for (_, pos, vel) in storage.get_components2_mut::<Position, Velocity>() {
+            pos.0 += vel.0;
+            pos.1 += vel.1;
+        }
+    }
+    black_box(&storage);
+    report("legacy", spawn, start.elapsed());
+}
+
+fn archetype() {
+    let start = Instant::now();
+    let mut ecs = ECS::new();
+    for i in 0..ENTITIES {
+        let entity = if i % 2 == 0 {
+            ecs.world_mut().create_entity(Entity::<NoBehavior, (Position, Velocity, Sprite)>::new)
+        } else {
+            ecs.world_mut().create_entity(Entity::<NoBehavior, (Position, Velocity)>::new)
+        };
+        if let Some(entity) = entity {
+            ecs.storage().get_mut().set_component(entity, Velocity(1.0, 0.5));
+        }
+    }
+    let spawn = start.elapsed();
+
+    let mut system = System::<(Position, Velocity)>::new(ecs.storage());
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        for (_, pos, vel) in system.iter_mut() {
+            pos.0 += vel.0;
+            pos.1 += vel.1;
+        }
+    }
+    black_box(&system);
+    report("archetype", spawn, start.elapsed());
+}
+
+fn main() {
+    legacy();
+    archetype();
+}
@@ -0,0 +1,278 @@
+use std::any::TypeId;
+use std::fmt::{Debug, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use hashbrown::HashMap;
+use crate::ecs::schedule::Access;
+
+const WRITING: isize = -1;
+
+/// Runtime borrow state of every registered component type, like the flag of a `RefCell` but shared by every
+/// handle to one storage and safe to use from worker threads. A flag counts the readers of a type, or is
+/// [`WRITING`] while it is borrowed mutably.
+#[derive(Default)]
+pub(crate) struct BorrowFlags {
+    flags: HashMap<TypeId, AtomicIsize>,
+    active: AtomicUsize,
+}
+
+impl BorrowFlags {
+    pub(crate) fn register(&mut self, ty: TypeId) {
+        self.flags.entry(ty).or_default();
+    }
+
+    /// Whether any guard is alive. Storage layout must not change while one is, because guards hand out references
+    /// into the columns.
+    pub(crate) fn is_borrowed(&self) -> bool {
+        self.active.load(Ordering::Acquire) > 0
+    }
+
+    pub(crate) fn is_borrowed_any(&self, ty: TypeId) -> bool {
+        self.flags.get(&ty).is_some_and(|flag| flag.load(Ordering::Acquire) != 0)
+    }
+
+    /// Returns false if `ty` is borrowed mutably. Types that were never registered have no components to alias.
+    fn try_read(&self, ty: TypeId) -> bool {
+        let Some(flag) = self.flags.get(&ty) else { return true; };
+        flag.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n >= 0).then_some(n + 1)).is_ok()
+    }
+
+    /// Returns false if `ty` is borrowed at all.
+    fn try_write(&self, ty: TypeId) -> bool {
+        let Some(flag) = self.flags.get(&ty) else { return true; };
+        flag.compare_exchange(0, WRITING, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    fn release_read(&self, ty: TypeId) {
+        if let Some(flag) = self.flags.get(&ty) {
+            flag.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    fn release_write(&self, ty: TypeId) {
+        if let Some(flag) = self.flags.get(&ty) {
+            flag.store(0, Ordering::Release);
+        }
+    }
+}
+
+fn read_conflict(name: &str) -> ! {
+    panic!("Component {name} is already borrowed mutably!")
+}
+
+fn write_conflict(name: &str) -> ! {
+    panic!("Component {name} is already borrowed!")
+}
+
+/// The components an iterator reads and writes, borrowed until it is dropped.
+pub(crate) struct Borrow<'a> {
+    flags: &'a BorrowFlags,
+    reads: Vec<TypeId>,
+    writes: Vec<TypeId>,
+}
+
+impl<'a> Borrow<'a> {
+    /// Borrows the components of `access`, panicking if one of them conflicts with a borrow that is still alive.
+    /// Components that are both read and written are only borrowed mutably.
+    pub(crate) fn new(flags: &'a BorrowFlags, access: &Access, name: impl Fn(TypeId) -> &'static str) -> Self {
+        flags.active.fetch_add(1, Ordering::AcqRel);
+        let mut this = Self { flags, reads: Vec::new(), writes: Vec::new() };
+        for ty in access.component_writes() {
+            if !flags.try_write(*ty) {
+                write_conflict(name(*ty));
+            }
+            this.writes.push(*ty);
+        }
+        for ty in access.component_reads() {
+            if this.writes.contains(ty) {
+                continue;
+            }
+            if !flags.try_read(*ty) {
+                read_conflict(name(*ty));
+            }
+            this.reads.push(*ty);
+        }
+        this
+    }
+}
+
+impl Drop for Borrow<'_> {
+    fn drop(&mut self) {
+        self.reads.iter().for_each(|ty| self.flags.release_read(*ty));
+        self.writes.iter().for_each(|ty| self.flags.release_write(*ty));
+        self.flags.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+struct Single<'a> {
+    flags: &'a BorrowFlags,
+    ty: TypeId,
+    write: bool,
+}
+
+impl<'a> Single<'a> {
+    fn new(flags: &'a BorrowFlags, ty: TypeId, write: bool, name: &str) -> Self {
+        let acquired = if write { flags.try_write(ty) } else { flags.try_read(ty) };
+        match (acquired, write) {
+            (false, true) => write_conflict(name),
+            (false, false) => read_conflict(name),
+            _ => {}
+        }
+        flags.active.fetch_add(1, Ordering::AcqRel);
+        Self { flags, ty, write }
+    }
+}
+
+impl Drop for Single<'_> {
+    fn drop(&mut self) {
+        if self.write {
+            self.flags.release_write(self.ty);
+        } else {
+            self.flags.release_read(self.ty);
+        }
+        self.flags.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A tracked shared reference to a component. While it is alive, borrowing the same component type mutably, e.g.
+/// by iterating a [`System`](crate::ecs::system::System) mutably, panics.
+pub struct Ref<'a, T> {
+    value: &'a T,
+    _borrow: Single<'a>,
+}
+
+impl<'a, T> Ref<'a, T> {
+    /// Borrows `ty` immutably before calling `get`, which is only called if the borrow succeeded.
+    pub(crate) fn new(flags: &'a BorrowFlags, ty: TypeId, name: &str, get: impl FnOnce() -> Option<&'a T>) -> Option<Self> {
+        let borrow = Single::new(flags, ty, false, name);
+        get().map(|value| Self { value, _borrow: borrow })
+    }
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<T: Debug> Debug for Ref<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+/// A tracked mutable reference to a component. While it is alive, borrowing the same component type at all panics.
+pub struct RefMut<'a, T> {
+    value: &'a mut T,
+    _borrow: Single<'a>,
+}
+
+impl<'a, T> RefMut<'a, T> {
+    /// Borrows `ty` mutably before calling `get`, which is only called if the borrow succeeded.
+    pub(crate) fn new(flags: &'a BorrowFlags, ty: TypeId, name: &str, get: impl FnOnce() -> Option<&'a mut T>) -> Option<Self> {
+        let borrow = Single::new(flags, ty, true, name);
+        get().map(|value| Self { value, _borrow: borrow })
+    }
+}
+
+impl<T> Deref for RefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<T> DerefMut for RefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.value
+    }
+}
+
+impl<T: Debug> Debug for RefMut<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::entity::{Entity, EntityType, NoBehavior};
+    use crate::ecs::system::System;
+    use crate::ecs::testing::{Health, Mana};
+    use crate::ecs::world::World;
+
+    fn world() -> (World, EntityType) {
+        let mut world = World::new();
+        let entity = world.create_entity(Entity::<NoBehavior, (Health, Mana)>::new).expect("Entity could not be created");
+        (world, entity)
+    }
+
+    #[test]
+    fn borrows_are_released_with_their_guards() {
+        let (world, entity) = world();
+        let storage = world.storage();
+        let readers = System::<(Health,)>::new(world.storage());
+        let mut writers = System::<(Mana,)>::new(world.storage());
+        {
+            let _iter = readers.iter();
+            let health = storage.get().borrow_component::<Health>(entity).expect("Entity has no Health");
+            let mut mana = storage.get().borrow_component_mut::<Mana>(entity).expect("Entity has no Mana");
+            mana.0 = health.0 + 1;
+        }
+        for (_, mana) in writers.iter_mut() {
+            assert_eq!(mana.0, 1);
+        }
+        assert!(storage.get().borrow_component_mut::<Health>(entity).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn writing_while_an_iterator_reads_panics() {
+        let (world, entity) = world();
+        let storage = world.storage();
+        let readers = System::<(Health,)>::new(world.storage());
+        let _iter = readers.iter();
+        storage.get().borrow_component_mut::<Health>(entity);
+    }
+
+    #[test]
+    #[should_panic(expected = "already borrowed mutably")]
+    fn reading_while_a_guard_writes_panics() {
+        let (world, entity) = world();
+        let storage = world.storage();
+        let readers = System::<(Health,)>::new(world.storage());
+        let _health = storage.get().borrow_component_mut::<Health>(entity);
+        let _iter = readers.iter();
+    }
+
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn iterating_mutably_while_a_ref_is_alive_panics() {
+        let (world, entity) = world();
+        let storage = world.storage();
+        let mut writers = System::<(Health,)>::new(world.storage());
+        let _health = storage.get().borrow_component::<Health>(entity);
+        let _iter = writers.iter_mut();
+    }
+
+    #[test]
+    #[should_panic(expected = "while components are borrowed")]
+    fn structural_changes_while_borrowed_panic() {
+        let (mut world, _) = world();
+        let readers = System::<(Mana,)>::new(world.storage());
+        let _iter = readers.iter();
+        let _ = world.create_entity(Entity::<NoBehavior, (Health,)>::new);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be replaced while it is borrowed")]
+    fn replacing_a_borrowed_component_panics() {
+        let (mut world, entity) = world();
+        let storage = world.storage();
+        let _health = storage.get().borrow_component::<Health>(entity);
+        world.insert_component(entity, Health(2));
+    }
+}
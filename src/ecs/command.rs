@@ -0,0 +1,201 @@
+use std::sync::{Arc, Mutex};
+use crate::ecs::{Component, EcsStorage};
+use crate::ecs::entity::{Entity, EntityBehavior, EntityType};
+use crate::ecs::world::World;
+
+type Command = Box<dyn FnOnce(&mut World) + Send>;
+
+/// The structural changes recorded by every [`Commands`] of a world, waiting for the next sync point.
+#[derive(Default)]
+pub struct CommandQueue {
+    commands: Mutex<Vec<Command>>,
+}
+
+impl CommandQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, command: impl FnOnce(&mut World) + Send + 'static) {
+        self.commands.lock().expect("Command queue was poisoned!").push(Box::new(command));
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.lock().expect("Command queue was poisoned!").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn take(&self) -> Vec<Command> {
+        std::mem::take(&mut *self.commands.lock().expect("Command queue was poisoned!"))
+    }
+}
+
+/// Records spawns, despawns and component changes without touching the storage, so it can be used while iterating
+/// a system or from a behavior. The [`World`] applies everything in recording order after every stage of its
+/// schedule and after the behaviors were updated.
+#[derive(Clone)]
+pub struct Commands {
+    queue: Arc<CommandQueue>,
+}
+
+impl Commands {
+    pub fn new(storage: EcsStorage) -> Self {
+        Self { queue: storage.get().commands().clone() }
+    }
+
+    pub(crate) fn from_queue(queue: Arc<CommandQueue>) -> Self {
+        Self { queue }
+    }
+
+    /// Spawns an entity like [`World::create_entity`]. Components added to the returned [`EntityCommands`] are
+    /// inserted right after the entity was created.
+    pub fn spawn<B: EntityBehavior + 'static, C: 'static>(&self, entity: fn(EcsStorage) -> Entity<B, C>) -> EntityCommands {
+        EntityCommands {
+            queue: self.queue.clone(),
+            spawn: Some(Box::new(move |world| world.create_entity(entity))),
+            then: Vec::new(),
+        }
+    }
+
+    pub fn despawn(&self, entity: EntityType) {
+        self.queue.push(move |world| world.despawn(entity));
+    }
+
+    pub fn insert<T: Component>(&self, entity: EntityType, component: T) {
+        self.queue.push(move |world| world.insert_component(entity, component));
+    }
+
+    pub fn remove<T: Component>(&self, entity: EntityType) {
+        self.queue.push(move |world| {
+            world.remove_component::<T>(entity);
+        });
+    }
+
+    pub fn set_parent(&self, child: EntityType, parent: EntityType) {
+        self.queue.push(move |world| {
+            world.set_parent(child, parent);
+        });
+    }
+
+    pub fn remove_parent(&self, child: EntityType) {
+        self.queue.push(move |world| {
+            world.remove_parent(child);
+        });
+    }
+
+    /// Records an arbitrary change to the world.
+    pub fn add(&self, command: impl FnOnce(&mut World) + Send + 'static) {
+        self.queue.push(command);
+    }
+}
+
+type SpawnCommand = Box<dyn FnOnce(&mut World) -> Option<EntityType> + Send>;
+type EntityCommand = Box<dyn FnOnce(&mut World, EntityType) + Send>;
+
+/// An entity spawned through [`Commands::spawn`]. It is recorded when dropped.
+pub struct EntityCommands {
+    queue: Arc<CommandQueue>,
+    spawn: Option<SpawnCommand>,
+    then: Vec<EntityCommand>,
+}
+
+impl EntityCommands {
+    pub fn insert<T: Component>(mut self, component: T) -> Self {
+        self.then.push(Box::new(move |world, entity| world.insert_component(entity, component)));
+        self
+    }
+
+    /// Runs `command` with the spawned entity once it exists.
+    pub fn with(mut self, command: impl FnOnce(&mut World, EntityType) + Send + 'static) -> Self {
+        self.then.push(Box::new(command));
+        self
+    }
+}
+
+impl Drop for EntityCommands {
+    fn drop(&mut self) {
+        let Some(spawn) = self.spawn.take() else { return; };
+        let then = std::mem::take(&mut self.then);
+        self.queue.push(move |world| {
+            if let Some(entity) = spawn(world) {
+                for command in then {
+                    command(world, entity);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::ecs::ECS;
+    use crate::ecs::entity::{Entity, EntityType, NoBehavior};
+    use crate::ecs::system::System;
+    use crate::ecs::testing::{Health, Tag};
+
+    #[test]
+    fn commands_wait_for_the_sync_point_and_keep_their_order() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        let entity = world.create_entity(Entity::<NoBehavior, (Health,)>::new).expect("Entity could not be created");
+        let commands = world.commands();
+        commands.insert(entity, Tag);
+        commands.remove::<Tag>(entity);
+        commands.remove::<Health>(entity);
+        commands.insert(entity, Health(2));
+        assert!(world.has_component::<Health>(entity));
+
+        world.apply_commands();
+        assert!(!world.has_component::<Tag>(entity));
+        assert_eq!(world.remove_component::<Health>(entity), Some(Health(2)));
+        assert!(ecs.storage().get().commands().is_empty());
+    }
+
+    #[test]
+    fn despawning_while_iterating_is_deferred() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        let entities: Vec<EntityType> = (0..4)
+            .map(|_| world.create_entity(Entity::<NoBehavior, (Health,)>::new).expect("Entity could not be created"))
+            .collect();
+        world.insert_component(entities[1], Health(5));
+
+        let mut system = System::<(Health,)>::new(ecs.storage());
+        let commands = system.commands();
+        for (entity, health) in system.iter_mut() {
+            if health.0 == 0 {
+                commands.despawn(entity);
+            }
+        }
+        let world = ecs.world_mut();
+        assert!(entities.iter().all(|entity| world.is_alive(*entity)));
+        world.apply_commands();
+        assert_eq!(entities.iter().filter(|entity| world.is_alive(**entity)).collect::<Vec<_>>(), [&entities[1]]);
+    }
+
+    #[test]
+    fn spawned_entities_get_their_components_and_follow_up_commands() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        let spawned = Arc::new(Mutex::new(None));
+        let record = spawned.clone();
+        world.commands().spawn(Entity::<NoBehavior, (Health,)>::new)
+            .insert(Health(3))
+            .with(move |world, entity| {
+                world.commands().insert(entity, Tag);
+                *record.lock().expect("Lock was poisoned") = Some(entity);
+            });
+        assert_eq!(ecs.storage().get().entity_count(), 0);
+
+        let world = ecs.world_mut();
+        world.apply_commands();
+        let entity = spawned.lock().expect("Lock was poisoned").expect("Entity was not spawned");
+        assert!(world.is_alive(entity));
+        assert!(world.has_component::<Tag>(entity), "Commands recorded while applying were not applied");
+        assert_eq!(world.remove_component::<Health>(entity), Some(Health(3)));
+    }
+}
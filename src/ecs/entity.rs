@@ -1,65 +1,265 @@
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
-use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
-use mvutils::once::CreateOnce;
-use mvutils::unsafe_utils::{DangerousCell, Unsafe, UnsafeRef};
-use mvutils::utils;
-use crate::ecs::{EcsStorage, ECS};
+use crate::ecs::{Component, EcsStorage};
+use crate::ecs::borrow::{Ref, RefMut};
+use crate::ecs::command::Commands;
+use crate::ecs::event::{EventReader, Events};
+use crate::ecs::hierarchy::{Children, Parent};
 use crate::ecs::mem::storage::ComponentStorage;
+use crate::ecs::resource::Resource;
+use crate::ecs::world::World;
+
+/// Generational entity handle. The index identifies a slot in the component storage, the generation is bumped
+/// every time that slot is freed, so a handle to a despawned entity never resolves to the entity that reuses its slot.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub struct EntityType {
+    index: u32,
+    generation: u32
+}
+
+impl EntityType {
+    pub(crate) fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn to_bits(&self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self { index: bits as u32, generation: (bits >> 32) as u32 }
+    }
+}
+
+impl Hash for EntityType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.to_bits());
+    }
+}
+
+impl Display for EntityType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+/// Stores and starts a behavior that was spawned from a [`BehaviorContext`].
+type SpawnedBehavior = Box<dyn FnOnce(&mut World)>;
+
+/// What a behavior can access while one of its hooks runs. Entities spawned with [`BehaviorContext::spawn`] exist
+/// right away, every other structural change, like despawning, adding and removing components or changing the
+/// hierarchy, is recorded through [`BehaviorContext::commands`] and applied once every behavior has run.
+pub struct BehaviorContext<'a> {
+    storage: &'a EcsStorage,
+    commands: Commands,
+    spawned: Vec<SpawnedBehavior>,
+}
+
+impl<'a> BehaviorContext<'a> {
+    pub(crate) fn new(storage: &'a EcsStorage) -> Self {
+        Self {
+            storage,
+            commands: Commands::new(storage.clone()),
+            spawned: Vec::new(),
+        }
+    }
+
+    /// Hands out the behaviors spawned through this context, for the world to store and start them.
+    pub(crate) fn into_spawned(mut self) -> Vec<SpawnedBehavior> {
+        std::mem::take(&mut self.spawned)
+    }
+
+    pub fn storage(&self) -> EcsStorage {
+        self.storage.clone()
+    }
+
+    pub fn commands(&self) -> &Commands {
+        &self.commands
+    }
+
+    /// Creates an entity like [`World::create_entity`] and returns its handle. The entity and its components exist
+    /// right away, its behavior is stored and started by the world once the current hook has returned. Panics if
+    /// components are borrowed.
+    pub fn spawn<B: EntityBehavior + 'static, C>(&mut self, entity: fn(EcsStorage) -> Entity<B, C>) -> EntityType {
+        let entity = entity(self.storage.clone());
+        let id = entity.ty;
+        if let Some(behavior) = entity.behavior {
+            self.spawned.push(Box::new(move |world: &mut World| {
+                world.attach_behavior(id, behavior);
+            }));
+        }
+        id
+    }
+
+    /// Records that `entity` and its descendants are despawned.
+    pub fn despawn(&self, entity: EntityType) {
+        self.commands.despawn(entity);
+    }
+
+    pub fn is_alive(&self, entity: EntityType) -> bool {
+        self.storage.get().is_alive(entity)
+    }
+
+    /// Panics if `T` is borrowed mutably, e.g. by a system iterator that is still alive.
+    pub fn get<T: Component>(&self, entity: EntityType) -> Option<Ref<'_, T>> {
+        self.storage.get().borrow_component::<T>(entity)
+    }
+
+    /// Panics if `T` is borrowed.
+    pub fn get_mut<T: Component>(&mut self, entity: EntityType) -> Option<RefMut<'_, T>> {
+        self.storage.get().borrow_component_mut::<T>(entity)
+    }
+
+    pub fn has<T: Component>(&self, entity: EntityType) -> bool {
+        self.storage.get().has_component::<T>(entity)
+    }
+
+    pub fn parent(&self, entity: EntityType) -> Option<EntityType> {
+        self.get::<Parent>(entity).map(|parent| parent.get())
+    }
+
+    pub fn children(&self, entity: EntityType) -> Vec<EntityType> {
+        self.get::<Children>(entity).map_or_else(Vec::new, |children| children.to_vec())
+    }
+
+    /// Records that `child` becomes a child of `parent`, see [`World::set_parent`].
+    pub fn set_parent(&self, child: EntityType, parent: EntityType) {
+        self.commands.set_parent(child, parent);
+    }
 
-pub type EntityType = u64;
+    /// Records that `child` is detached from its parent, see [`World::remove_parent`].
+    pub fn remove_parent(&self, child: EntityType) {
+        self.commands.remove_parent(child);
+    }
+
+    pub fn resource<R: Resource>(&self) -> Option<&R> {
+        self.storage.get().resource::<R>()
+    }
+
+    pub fn resource_mut<R: Resource>(&mut self) -> Option<&mut R> {
+        // SAFETY: behaviors run on the main thread while no system does, and `&mut self` keeps the context from
+        // handing out another reference to `R`.
+        unsafe { self.storage.get().resources().get_unchecked_mut::<R>() }
+    }
+
+    /// Sends `event` to the readers of `Events<E>`. Does nothing if [`World::add_event`] was not called for `E`.
+    pub fn send_event<E: Send + Sync + 'static>(&mut self, event: E) {
+        if let Some(events) = self.resource_mut::<Events<E>>() {
+            events.send(event);
+        }
+    }
+
+    /// Creates a reader that only sees events sent from now on, or `None` if `E` was not added to the world.
+    pub fn event_reader<E: Send + Sync + 'static>(&self) -> Option<EventReader<E>> {
+        self.resource::<Events<E>>().map(Events::reader)
+    }
+
+    /// Returns the events `reader` has not seen yet, a behavior usually keeps its reader in a field.
+    pub fn read_events<'s, E: Send + Sync + 'static>(&'s self, reader: &mut EventReader<E>) -> impl Iterator<Item = &'s E> {
+        self.resource::<Events<E>>().map(|events| reader.read(events)).into_iter().flatten()
+    }
+}
 
+impl Drop for BehaviorContext<'_> {
+    fn drop(&mut self) {
+        if !self.spawned.is_empty() {
+            log::warn!("Dropping the behaviors of {} entities spawned outside of a world", self.spawned.len());
+        }
+    }
+}
+
+/// Per-entity logic. A world calls the hooks of every behavior in this order: [`EntityBehavior::start`] once after
+/// the entity was created, [`EntityBehavior::fixed_update`] from [`World::fixed_update`], [`EntityBehavior::update`]
+/// and [`EntityBehavior::late_update`] from [`World::update`] and [`EntityBehavior::destroy`] once before the entity
+/// is despawned.
+///
+/// [`World::fixed_update`]: crate::ecs::world::World::fixed_update
+/// [`World::update`]: crate::ecs::world::World::update
 pub trait EntityBehavior {
     fn new(storage: EcsStorage) -> Self where Self: Sized;
-    fn start(&mut self, entity: EntityType);
-    fn update(&mut self, entity: EntityType);
+
+    fn start(&mut self, entity: EntityType, ctx: &mut BehaviorContext);
+
+    fn update(&mut self, entity: EntityType, ctx: &mut BehaviorContext);
+
+    /// Runs at a fixed rate, `dt` is the time between two calls in seconds.
+    fn fixed_update(&mut self, _entity: EntityType, _dt: f32, _ctx: &mut BehaviorContext) {}
+
+    /// Runs after every system of the update stages.
+    fn late_update(&mut self, _entity: EntityType, _ctx: &mut BehaviorContext) {}
+
+    /// Runs while the entity and its components still exist.
+    fn destroy(&mut self, _entity: EntityType, _ctx: &mut BehaviorContext) {}
+
+    /// Runs when the entity moved to another world, see
+    /// [`World::move_entity_to`](crate::ecs::world::World::move_entity_to). Behaviors that keep handles to the storage
+    /// they were created with, like the one passed to [`EntityBehavior::new`] or a [`LocalComponent`], have to replace
+    /// them with handles to `storage`. The entity has a new handle as well, which [`EntityBehavior::start`] receives
+    /// right after.
+    fn rebind(&mut self, _storage: EcsStorage) {}
 }
 
 #[derive(Clone)]
 pub struct NoBehavior;
 
 impl EntityBehavior for NoBehavior {
-    fn new(storage: EcsStorage) -> Self
+    fn new(_storage: EcsStorage) -> Self
     where
         Self: Sized
     { Self {} }
 
-    fn start(&mut self, entity: EntityType) {}
+    fn start(&mut self, _entity: EntityType, _ctx: &mut BehaviorContext) {}
 
-    fn update(&mut self, entity: EntityType) {}
+    fn update(&mut self, _entity: EntityType, _ctx: &mut BehaviorContext) {}
 }
 
+/// Handle to one component of one entity, resolved on every access. Components move whenever their entity changes
+/// archetype, so references into the storage must not be cached.
+///
+/// Like [`Entity::get_component`], the returned guards keep the component borrowed until they are dropped.
 #[derive(Clone)]
-pub struct LocalComponent<C: Sized + 'static> {
-    component: Option<&'static C>,
+pub struct LocalComponent<C: Component> {
+    phantom: PhantomData<C>,
+    entity: Option<EntityType>,
     storage: EcsStorage
 }
 
-impl<C: Sized + 'static> LocalComponent<C> {
+impl<C: Component> LocalComponent<C> {
     pub fn new(storage: EcsStorage) -> Self {
-        Self { component: None, storage }
+        Self { phantom: PhantomData, entity: None, storage }
     }
 
     pub fn aquire(&mut self, entity: EntityType) {
-        let c = self.storage.get().get_component::<C>(entity).expect("Entity does not have component X, but it was aquired from LocalComponent!");
-        unsafe { self.component = Some(Unsafe::cast_static(c)) }
+        assert!(self.storage.get().has_component::<C>(entity), "Entity does not have component X, but it was aquired from LocalComponent!");
+        self.entity = Some(entity);
     }
-}
-
-impl<C> Deref for LocalComponent<C> {
-    type Target = C;
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { self.component.unwrap() }
+    /// Panics if the component is borrowed mutably, the handle was not aquired or the entity no longer has the
+    /// component.
+    pub fn get(&self) -> Ref<'_, C> {
+        let entity = self.entity.expect("LocalComponent was used before it was aquired!");
+        self.storage.get().borrow_component::<C>(entity).expect("Entity of LocalComponent no longer has the component!")
     }
-}
 
-impl<C> DerefMut for LocalComponent<C> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { (*self.component.as_ref().unwrap() as *const C as *mut C).as_mut().unwrap() }
+    /// Marks the component as changed. Panics if the component is borrowed, the handle was not aquired or the entity
+    /// no longer has the component.
+    pub fn get_mut(&mut self) -> RefMut<'_, C> {
+        let entity = self.entity.expect("LocalComponent was used before it was aquired!");
+        self.storage.get().borrow_component_mut::<C>(entity).expect("Entity of LocalComponent no longer has the component!")
     }
 }
 
+/// An entity with the behavior `B`, created by [`World::create_entity`]. `Entity::<B, (C1, C2, ...)>::new` attaches
+/// the default of every component in the [`Bundle`] `C`.
 pub struct Entity<B, C> {
     phantom: PhantomData<C>,
     pub(crate) ty: EntityType,
@@ -68,14 +268,18 @@ pub struct Entity<B, C> {
 }
 
 impl<B, C> Entity<B, C> {
-    pub fn get_component<T: Sized + 'static>(&self) -> Option<&T> {
-        let mut st = self.storage.get_mut();
-        st.get_component::<T>(self.ty)
+    pub fn id(&self) -> EntityType {
+        self.ty
+    }
+
+    /// Panics if `T` is borrowed mutably.
+    pub fn get_component<T: Component>(&self) -> Option<Ref<'_, T>> {
+        self.storage.get().borrow_component::<T>(self.ty)
     }
 
-    pub fn get_component_mut<T: Sized + 'static>(&mut self) -> Option<&mut T> {
-        let mut st = self.storage.get_mut();
-        st.get_component_mut::<T>(self.ty)
+    /// Panics if `T` is borrowed.
+    pub fn get_component_mut<T: Component>(&mut self) -> Option<RefMut<'_, T>> {
+        self.storage.get().borrow_component_mut::<T>(self.ty)
     }
 }
 
@@ -83,7 +287,7 @@ impl<B: EntityBehavior, C> Entity<B, C> {
     fn new_internal(storage: EcsStorage, behavior: Option<B>) -> Self {
         Self {
             phantom: PhantomData::default(),
-            ty: utils::next_id("MVEngine::ecs::entity"),
+            ty: storage.get_mut().spawn(),
             storage,
             behavior,
         }
@@ -91,13 +295,13 @@ impl<B: EntityBehavior, C> Entity<B, C> {
 
     pub fn start(&mut self) {
         if let Some(ref mut behavior) = self.behavior {
-            behavior.start(self.ty);
+            behavior.start(self.ty, &mut BehaviorContext::new(&self.storage));
         }
     }
 
     pub fn update(&mut self) {
         if let Some(ref mut behavior) = self.behavior {
-            behavior.update(self.ty);
+            behavior.update(self.ty, &mut BehaviorContext::new(&self.storage));
         }
     }
 
@@ -110,71 +314,76 @@ impl<B: EntityBehavior, C> Entity<B, C> {
     }
 }
 
-macro_rules! impl_entity_tuples {
-    ($first:ident) => {};
-
-    ($first:ident $($rest:ident)*) => {
-        impl_entity_tuples!($($rest)*);
+/// The components [`Entity::new`] attaches to a new entity, each set to its default. Implemented for every
+/// [`Component`] and for tuples of up to 15 bundles. Tuples are bundles themselves, so longer sets are written as
+/// nested tuples, like `((A, B, C), D)`.
+pub trait Bundle: 'static {
+    fn insert_default(storage: &mut ComponentStorage, entity: EntityType);
+}
 
-        impl<B: EntityBehavior, $first: Sized + Default + 'static, $($rest: Sized + Default + 'static),*> Entity<B, ($first, $($rest),*)> {
-            pub fn new(storage: EcsStorage) -> Self {
-                let mut this = Self::new_internal(storage.clone(), Some(B::new(storage.clone())));
+/// A [`Bundle`] of components that implement [`Clone`], which makes its [`Entity`] cloneable.
+pub trait CloneBundle: Bundle {
+    /// Copies the components of `from` to `to`. Panics if `from` lacks one of them.
+    fn clone_components(storage: &mut ComponentStorage, from: EntityType, to: EntityType);
+}
 
-                #[allow(non_snake_case)]
-                let ($first, $($rest),*) = ($first::default(), $($rest::default()),*);
+impl<T: Component + Default> Bundle for T {
+    fn insert_default(storage: &mut ComponentStorage, entity: EntityType) {
+        storage.set_component(entity, T::default());
+    }
+}
 
-                this.storage.get_mut().set_component(this.ty, $first);
-                $( this.storage.get_mut().set_component(this.ty, $rest); )*
+impl<T: Component + Default + Clone> CloneBundle for T {
+    fn clone_components(storage: &mut ComponentStorage, from: EntityType, to: EntityType) {
+        let component = storage.get_component::<T>(from).expect("Cloned entity lost a component!").clone();
+        storage.set_component(to, component);
+    }
+}
 
-                this
-            }
-        }
+impl Bundle for () {
+    fn insert_default(_: &mut ComponentStorage, _: EntityType) {}
+}
 
-        impl<B: EntityBehavior + Clone, $first: Sized + Default + 'static + Clone, $($rest: Sized + Default + 'static + Clone),*> Clone for Entity<B, ($first, $($rest),*)> {
-            fn clone(&self) -> Self {
-                let component = self.get_component::<$first>().unwrap();
+impl CloneBundle for () {
+    fn clone_components(_: &mut ComponentStorage, _: EntityType, _: EntityType) {}
+}
 
-                let mut new = Self::new(self.storage.clone());
+macro_rules! impl_bundle_tuples {
+    () => {};
 
-                let mut new_component = new.get_component_mut::<$first>().unwrap();
-                component.clone_into(&mut new_component);
+    ($first:ident $($rest:ident)*) => {
+        impl_bundle_tuples!($($rest)*);
 
-                $(
-                    let mut component = self.get_component::<$rest>().unwrap();
-                    let mut new_component = new.get_component_mut::<$rest>().unwrap();
-                    component.clone_into(&mut new_component);
-                )*
+        impl<$first: Bundle, $($rest: Bundle),*> Bundle for ($first, $($rest),*) {
+            fn insert_default(storage: &mut ComponentStorage, entity: EntityType) {
+                $first::insert_default(storage, entity);
+                $( $rest::insert_default(storage, entity); )*
+            }
+        }
 
-                new
+        impl<$first: CloneBundle, $($rest: CloneBundle),*> CloneBundle for ($first, $($rest),*) {
+            fn clone_components(storage: &mut ComponentStorage, from: EntityType, to: EntityType) {
+                $first::clone_components(storage, from, to);
+                $( $rest::clone_components(storage, from, to); )*
             }
         }
     };
 }
 
-impl_entity_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11 C12 C13 C14 C15);
+impl_bundle_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11 C12 C13 C14 C15);
 
-impl<B: EntityBehavior, C: Sized + Default + 'static> Entity<B, (C,)> {
+impl<B: EntityBehavior, C: Bundle> Entity<B, C> {
     pub fn new(storage: EcsStorage) -> Self {
-        let mut this = Self::new_internal(storage.clone(), Some(B::new(storage.clone())));
-
-        #[allow(non_snake_case)]
-        let (c) = (C::default());
-
-        this.storage.get_mut().set_component(this.ty, c);
-
+        let this = Self::new_internal(storage.clone(), Some(B::new(storage.clone())));
+        C::insert_default(this.storage.get_mut(), this.ty);
         this
     }
 }
 
-impl<B: EntityBehavior + Clone, C: Sized + Clone + Default + 'static> Clone for Entity<B, (C,)> {
+impl<B: EntityBehavior + Clone, C: CloneBundle> Clone for Entity<B, C> {
     fn clone(&self) -> Self {
-        let component = self.get_component::<C>().unwrap();
-
-        let mut new = Self::new(self.storage.clone());
-
-        let mut new_component = new.get_component_mut::<C>().unwrap();
-        *new_component = component.clone();
-
+        let new = Self::new(self.storage.clone());
+        C::clone_components(self.storage.get_mut(), self.ty, new.ty);
         new
     }
-}
\ No newline at end of file
+}
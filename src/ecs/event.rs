@@ -0,0 +1,244 @@
+use std::marker::PhantomData;
+use crate::ecs::EcsStorage;
+use crate::ecs::resource::LocalResource;
+
+/// A double-buffered channel of events of type `E`, stored as a resource. Every event stays readable for the update
+/// it was sent in and the next one, so every reader that runs once per update sees it, regardless of whether it
+/// runs before or after the writer.
+///
+/// Register it with [`World::add_event`](crate::ecs::world::World::add_event), which swaps the buffers at the start
+/// of every [`World::update`](crate::ecs::world::World::update).
+pub struct Events<E> {
+    previous: Vec<E>,
+    current: Vec<E>,
+    previous_start: usize,
+    current_start: usize,
+}
+
+impl<E> Default for Events<E> {
+    fn default() -> Self {
+        Self {
+            previous: Vec::new(),
+            current: Vec::new(),
+            previous_start: 0,
+            current_start: 0,
+        }
+    }
+}
+
+impl<E> Events<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send(&mut self, event: E) {
+        self.current.push(event);
+    }
+
+    pub fn send_batch(&mut self, events: impl IntoIterator<Item = E>) {
+        self.current.extend(events);
+    }
+
+    /// Drops the events of the previous update and starts a new buffer.
+    pub fn update(&mut self) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+        self.current.clear();
+        self.previous_start = self.current_start;
+        self.current_start += self.previous.len();
+    }
+
+    /// Drops every event, readers skip what they have not read yet.
+    pub fn clear(&mut self) {
+        self.update();
+        self.update();
+    }
+
+    /// The number of events still stored.
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.previous.is_empty() && self.current.is_empty()
+    }
+
+    /// Creates a reader that only sees events sent from now on.
+    pub fn reader(&self) -> EventReader<E> {
+        EventReader {
+            phantom: PhantomData,
+            cursor: self.end(),
+        }
+    }
+
+    fn end(&self) -> usize {
+        self.current_start + self.current.len()
+    }
+
+    fn events_since(&self, cursor: usize) -> impl Iterator<Item = &E> {
+        let skip = cursor.saturating_sub(self.previous_start);
+        self.previous.iter().chain(self.current.iter()).skip(skip)
+    }
+}
+
+/// Reads the events of one [`Events`] channel. Every reader keeps its own cursor, so multiple systems and behaviors
+/// each see every event once. A reader that is not read for more than one update misses the events that were
+/// dropped in the meantime.
+///
+/// Readers only hold their cursor and can be moved into system closures. Systems reading events have to declare
+/// [`SystemConfig::reads_resource`](crate::ecs::schedule::SystemConfig::reads_resource) for `Events<E>`, a
+/// [`#[system]`](macro@crate::ecs::system) takes `&mut EventReader<E>` and `Res<Events<E>>` instead.
+pub struct EventReader<E> {
+    phantom: PhantomData<fn() -> E>,
+    cursor: usize,
+}
+
+impl<E> Default for EventReader<E> {
+    /// Creates a reader that starts at the oldest event still stored.
+    fn default() -> Self {
+        Self {
+            phantom: PhantomData,
+            cursor: 0,
+        }
+    }
+}
+
+impl<E> Clone for EventReader<E> {
+    fn clone(&self) -> Self {
+        Self {
+            phantom: PhantomData,
+            cursor: self.cursor,
+        }
+    }
+}
+
+impl<E> EventReader<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns every event this reader has not seen yet and advances its cursor.
+    pub fn read<'a>(&mut self, events: &'a Events<E>) -> impl Iterator<Item = &'a E> {
+        let cursor = self.cursor;
+        self.cursor = events.end();
+        events.events_since(cursor)
+    }
+
+    /// The number of events this reader has not seen yet.
+    pub fn len(&self, events: &Events<E>) -> usize {
+        events.end() - self.cursor.max(events.previous_start)
+    }
+
+    pub fn is_empty(&self, events: &Events<E>) -> bool {
+        self.len(events) == 0
+    }
+
+    /// Skips every event sent so far.
+    pub fn clear(&mut self, events: &Events<E>) {
+        self.cursor = events.end();
+    }
+}
+
+/// Sends events into the [`Events`] resource of a world. Behaviors can create it in
+/// [`EntityBehavior::new`](crate::ecs::entity::EntityBehavior::new), a [`#[system]`](macro@crate::ecs::system) takes
+/// it as a parameter.
+pub struct EventWriter<E: Send + Sync + 'static> {
+    events: LocalResource<Events<E>>,
+}
+
+impl<E: Send + Sync + 'static> EventWriter<E> {
+    pub fn new(storage: EcsStorage) -> Self {
+        Self { events: LocalResource::new(storage) }
+    }
+
+    pub fn send(&mut self, event: E) {
+        self.events.get_mut().expect("Events were not added to the world!").send(event);
+    }
+
+    pub fn send_batch(&mut self, events: impl IntoIterator<Item = E>) {
+        self.events.get_mut().expect("Events were not added to the world!").send_batch(events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::ECS;
+    use crate::ecs::event::{EventReader, EventWriter, Events};
+    use crate::ecs::param::SystemParam;
+    use crate::ecs::schedule::Access;
+    use crate::ecs::world::World;
+
+    fn read(reader: &mut EventReader<u32>, events: &Events<u32>) -> Vec<u32> {
+        reader.read(events).copied().collect()
+    }
+
+    #[test]
+    fn events_stay_readable_for_two_updates() {
+        let mut events = Events::new();
+        let mut early = events.reader();
+        let mut late = events.reader();
+        let mut slow = events.reader();
+        events.send(1);
+        assert_eq!(read(&mut early, &events), [1]);
+
+        events.update();
+        events.send_batch([2, 3]);
+        assert_eq!(early.len(&events), 2);
+        assert_eq!(read(&mut early, &events), [2, 3]);
+        assert_eq!(read(&mut late, &events), [1, 2, 3]);
+        assert!(read(&mut late, &events).is_empty());
+
+        events.update();
+        events.update();
+        events.send(4);
+        assert_eq!(read(&mut slow, &events), [4]);
+        assert_eq!(read(&mut EventReader::new(), &events), [4]);
+        assert_eq!(events.len(), 1);
+
+        events.clear();
+        assert!(events.is_empty());
+        assert!(early.is_empty(&events));
+    }
+
+    #[test]
+    fn world_updates_swap_the_buffers() {
+        let mut ecs = ECS::new();
+        let mut writer = EventWriter::<u32>::new(ecs.storage());
+        let world = ecs.world_mut();
+        world.add_event::<u32>();
+        let mut reader = world.event_reader::<u32>().expect("Events were not added");
+        writer.send(1);
+        world.send_event(2u32);
+
+        world.update();
+        let events = world.resource::<Events<u32>>().expect("Events were not added");
+        assert_eq!(read(&mut reader.clone(), events), [1, 2]);
+        world.update();
+        world.update();
+        let events = world.resource::<Events<u32>>().expect("Events were not added");
+        assert!(read(&mut reader, events).is_empty());
+        assert!(world.event_reader::<u64>().is_none());
+    }
+
+    #[test]
+    fn system_params_keep_their_cursor_between_runs() {
+        let mut ecs = ECS::new();
+        let storage = ecs.storage();
+        let world = ecs.world_mut();
+        world.add_event::<u32>();
+        let mut reader_state = <&mut EventReader<u32> as SystemParam>::init(&storage);
+        let mut writer_state = <EventWriter<u32> as SystemParam>::init(&storage);
+        let mut run = |world: &World, event: u32| unsafe {
+            <EventWriter<u32> as SystemParam>::get(&mut writer_state, &storage).send(event);
+            let reader = <&mut EventReader<u32> as SystemParam>::get(&mut reader_state, &storage);
+            read(reader, world.resource::<Events<u32>>().expect("Events were not added"))
+        };
+        assert_eq!(run(world, 1), [1]);
+        world.update();
+        assert_eq!(run(world, 2), [2]);
+
+        let (mut reads, mut writes) = (Access::default(), Access::default());
+        <&mut EventReader<u32> as SystemParam>::access(&mut reads);
+        <EventWriter<u32> as SystemParam>::access(&mut writes);
+        assert!(reads.is_compatible(&reads) && !reads.is_compatible(&writes));
+    }
+}
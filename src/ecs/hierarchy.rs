@@ -0,0 +1,303 @@
+use std::any::TypeId;
+use std::ops::Deref;
+use std::simd::f32x4;
+use hashbrown::HashSet;
+use crate::ecs::{Component, StorageType};
+use crate::ecs::entity::EntityType;
+use crate::ecs::mem::storage::ComponentStorage;
+use crate::ecs::query::{clamp_tick, is_newer};
+use crate::math::mat::Mat2;
+use crate::math::vec::Vec2;
+use crate::rendering::Transform;
+
+impl Component for Transform {}
+
+/// The entity this entity is attached to. Managed by [`World::set_parent`](crate::ecs::world::World::set_parent),
+/// together with the [`Children`] of the parent.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Parent(pub(crate) EntityType);
+
+impl Parent {
+    pub fn get(&self) -> EntityType {
+        self.0
+    }
+}
+
+impl Component for Parent {}
+
+/// The entities attached to this entity, in the order they were attached.
+#[derive(Clone, Default, Debug)]
+pub struct Children(pub(crate) Vec<EntityType>);
+
+impl Deref for Children {
+    type Target = [EntityType];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Component for Children {}
+
+/// The world space transform of an entity, composed from its own [`Transform`] and the transforms of all its
+/// ancestors. Written by [`World::propagate_transforms`](crate::ecs::world::World::propagate_transforms), which runs
+/// at the end of every update, and removed from entities without a [`Transform`].
+///
+/// Composing rotations with non-uniform scales can shear, so unlike [`Transform`] this is stored as a linear part
+/// and a translation.
+#[derive(Copy, Clone, Debug)]
+pub struct GlobalTransform {
+    pub matrix: Mat2,
+    pub translation: Vec2,
+}
+
+impl Default for GlobalTransform {
+    fn default() -> Self {
+        Self {
+            matrix: Mat2(f32x4::from_array([1.0, 0.0, 0.0, 1.0])),
+            translation: Vec2::default(),
+        }
+    }
+}
+
+impl PartialEq for GlobalTransform {
+    fn eq(&self, other: &Self) -> bool {
+        self.matrix.as_slice() == other.matrix.as_slice() && self.translation == other.translation
+    }
+}
+
+impl Component for GlobalTransform {}
+
+impl GlobalTransform {
+    pub fn from_transform(transform: &Transform) -> Self {
+        let (sin, cos) = transform.rotation.sin_cos();
+        let matrix = Mat2(f32x4::from_array([
+            cos * transform.scale.x, -sin * transform.scale.y,
+            sin * transform.scale.x, cos * transform.scale.y,
+        ]));
+        let origin = matrix.mul_vec(transform.origin);
+        Self {
+            matrix,
+            translation: Vec2::new(
+                transform.translation.x + transform.origin.x - origin.x,
+                transform.translation.y + transform.origin.y - origin.y,
+            ),
+        }
+    }
+
+    /// Returns the transform that applies `child` first and then this one.
+    pub fn mul(&self, child: &GlobalTransform) -> GlobalTransform {
+        let [a11, a12, a21, a22] = self.matrix.0.to_array();
+        let [b11, b12, b21, b22] = child.matrix.0.to_array();
+        let translation = self.matrix.mul_vec(child.translation);
+        Self {
+            matrix: Mat2(f32x4::from_array([
+                a11 * b11 + a12 * b21, a11 * b12 + a12 * b22,
+                a21 * b11 + a22 * b21, a21 * b12 + a22 * b22,
+            ])),
+            translation: Vec2::new(translation.x + self.translation.x, translation.y + self.translation.y),
+        }
+    }
+
+    pub fn apply(&self, point: Vec2) -> Vec2 {
+        let point = self.matrix.mul_vec(point);
+        Vec2::new(point.x + self.translation.x, point.y + self.translation.y)
+    }
+
+    pub fn rotation(&self) -> f32 {
+        self.matrix.0[2].atan2(self.matrix.0[0])
+    }
+
+    pub fn scale(&self) -> Vec2 {
+        let [m11, m12, m21, m22] = self.matrix.0.to_array();
+        Vec2::new(m11.hypot(m21), m12.hypot(m22))
+    }
+
+    /// Converts back into a [`Transform`] with its origin at zero. Shear is lost.
+    pub fn to_transform(&self) -> Transform {
+        Transform {
+            translation: self.translation,
+            origin: Vec2::default(),
+            scale: self.scale(),
+            rotation: self.rotation(),
+        }
+    }
+}
+
+/// Marks `entity` for the next [`propagate_transforms`] after it was detached from its parent, which leaves no
+/// change behind on the entity itself.
+pub(crate) fn mark_detached(storage: &mut ComponentStorage, entity: EntityType) {
+    if storage.get_component_mut::<Transform>(entity).is_none() {
+        storage.get_component_mut::<Children>(entity);
+    }
+}
+
+/// Writes the [`GlobalTransform`] of every entity whose [`Transform`], [`Parent`] or [`Children`] changed since
+/// `last_run` and of all of their descendants. Entities in the hierarchy without a [`Transform`] pass the transform
+/// of their parent on unchanged and lose their [`GlobalTransform`]. Returns the tick to pass as `last_run` next time.
+pub(crate) fn propagate_transforms(storage: &mut ComponentStorage, last_run: u32) -> u32 {
+    let this_run = storage.next_tick();
+    let last_run = clamp_tick(last_run, this_run);
+    let changes = [TypeId::of::<Transform>(), TypeId::of::<Parent>(), TypeId::of::<Children>()];
+    let types = [changes[0], changes[1], changes[2], TypeId::of::<GlobalTransform>()];
+    let sparse = types.iter().any(|ty| storage.storage_type(*ty) == StorageType::SparseSet);
+    let mut dirty = HashSet::new();
+    for archetype in storage.archetypes() {
+        if !sparse && !types.iter().any(|ty| archetype.has(*ty)) {
+            continue;
+        }
+        for entity in archetype.entities() {
+            let changed = changes.iter().any(|ty| {
+                storage.changed_tick(*entity, *ty).is_some_and(|tick| is_newer(tick, last_run, this_run))
+            });
+            let stale = storage.has_component::<GlobalTransform>(*entity) && !storage.has_component::<Transform>(*entity);
+            if changed || stale {
+                dirty.insert(*entity);
+            }
+        }
+    }
+
+    // Only the topmost dirty entities are walked, the walk reaches every dirty entity below them anyway.
+    let mut stack: Vec<(EntityType, GlobalTransform)> = dirty.iter()
+        .filter(|entity| !ancestors(storage, **entity).any(|ancestor| dirty.contains(&ancestor)))
+        .map(|entity| {
+            let parent_global = ancestors(storage, *entity)
+                .find(|ancestor| storage.has_component::<Transform>(*ancestor))
+                .and_then(|ancestor| storage.get_component::<GlobalTransform>(ancestor).copied())
+                .unwrap_or_default();
+            (*entity, parent_global)
+        })
+        .collect();
+
+    while let Some((entity, parent_global)) = stack.pop() {
+        let global = match storage.get_component::<Transform>(entity) {
+            Some(local) => {
+                let global = parent_global.mul(&GlobalTransform::from_transform(local));
+                if storage.get_component::<GlobalTransform>(entity) != Some(&global) {
+                    storage.set_component(entity, global);
+                }
+                global
+            }
+            None => {
+                if storage.has_component::<GlobalTransform>(entity) {
+                    storage.remove_component::<GlobalTransform>(entity);
+                }
+                parent_global
+            }
+        };
+        if let Some(children) = storage.get_component::<Children>(entity) {
+            stack.extend(children.iter().map(|child| (*child, global)));
+        }
+    }
+    this_run
+}
+
+fn ancestors(storage: &ComponentStorage, entity: EntityType) -> impl Iterator<Item = EntityType> + '_ {
+    let parent = |entity: &EntityType| storage.get_component::<Parent>(*entity).map(Parent::get);
+    std::iter::successors(parent(&entity), parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::TypeId;
+    use std::f32::consts::FRAC_PI_2;
+    use crate::ecs::entity::{Entity, EntityType, NoBehavior};
+    use crate::ecs::hierarchy::GlobalTransform;
+    use crate::ecs::world::World;
+    use crate::math::vec::Vec2;
+    use crate::rendering::Transform;
+
+    fn spawn(world: &mut World, transform: Option<Transform>) -> EntityType {
+        let entity = world.create_entity(Entity::<NoBehavior, ()>::new).expect("Entity could not be created");
+        if let Some(transform) = transform {
+            world.insert_component(entity, transform);
+        }
+        entity
+    }
+
+    fn translated(x: f32, y: f32) -> Transform {
+        let mut transform = Transform::new();
+        transform.translation = Vec2::new(x, y);
+        transform
+    }
+
+    fn global(world: &World, entity: EntityType) -> Option<GlobalTransform> {
+        world.storage().get().get_component::<GlobalTransform>(entity).copied()
+    }
+
+    fn assert_near(actual: Vec2, expected: Vec2) {
+        assert!((actual.x - expected.x).abs() < 1e-4 && (actual.y - expected.y).abs() < 1e-4, "{actual:?} is not {expected:?}");
+    }
+
+    #[test]
+    fn children_compose_with_their_parents() {
+        let mut world = World::new();
+        let mut turned = translated(10.0, 0.0);
+        turned.rotation = FRAC_PI_2;
+        turned.scale = Vec2::new(2.0, 2.0);
+        let parent = spawn(&mut world, Some(turned));
+        let child = spawn(&mut world, Some(translated(1.0, 2.0)));
+        let mut pivoted = translated(0.0, 0.0);
+        pivoted.origin = Vec2::new(1.0, 1.0);
+        pivoted.rotation = FRAC_PI_2;
+        let grandchild = spawn(&mut world, Some(pivoted));
+        world.set_parent(child, parent);
+        world.set_parent(grandchild, child);
+        world.update();
+
+        let parent_global = global(&world, parent).expect("Parent has no GlobalTransform");
+        assert_near(parent_global.translation, Vec2::new(10.0, 0.0));
+        assert_near(parent_global.scale(), Vec2::new(2.0, 2.0));
+        // The child is scaled and turned by a quarter around its parent.
+        let child_global = global(&world, child).expect("Child has no GlobalTransform");
+        assert_near(child_global.translation, Vec2::new(6.0, 2.0));
+        assert!((child_global.rotation() - FRAC_PI_2).abs() < 1e-4);
+        // The origin of the grandchild stays in place when it turns.
+        let grandchild_global = global(&world, grandchild).expect("Grandchild has no GlobalTransform");
+        assert_near(grandchild_global.apply(Vec2::new(1.0, 1.0)), child_global.apply(Vec2::new(1.0, 1.0)));
+        assert_near(grandchild_global.apply(Vec2::new(0.0, 0.0)), child_global.apply(Vec2::new(2.0, 0.0)));
+    }
+
+    #[test]
+    fn entities_without_a_transform_pass_their_parent_on() {
+        let mut world = World::new();
+        let root = spawn(&mut world, Some(translated(5.0, 0.0)));
+        let group = spawn(&mut world, None);
+        let leaf = spawn(&mut world, Some(translated(1.0, 0.0)));
+        world.set_parent(group, root);
+        world.set_parent(leaf, group);
+        world.update();
+        assert!(global(&world, group).is_none());
+        assert_near(global(&world, leaf).expect("Leaf has no GlobalTransform").translation, Vec2::new(6.0, 0.0));
+
+        world.remove_component::<Transform>(root);
+        world.update();
+        assert!(global(&world, root).is_none());
+        assert_near(global(&world, leaf).expect("Leaf has no GlobalTransform").translation, Vec2::new(1.0, 0.0));
+
+        world.insert_component(root, translated(2.0, 0.0));
+        world.remove_parent(group);
+        world.update();
+        assert_near(global(&world, root).expect("Root has no GlobalTransform").translation, Vec2::new(2.0, 0.0));
+        assert_near(global(&world, leaf).expect("Leaf has no GlobalTransform").translation, Vec2::new(1.0, 0.0));
+    }
+
+    #[test]
+    fn only_changed_subtrees_are_written() {
+        let mut world = World::new();
+        let still = spawn(&mut world, Some(translated(1.0, 0.0)));
+        let moving = spawn(&mut world, Some(translated(2.0, 0.0)));
+        let child = spawn(&mut world, Some(translated(0.0, 1.0)));
+        world.set_parent(child, moving);
+        world.update();
+        let storage = world.storage();
+        let written = |entity| storage.get().changed_tick(entity, TypeId::of::<GlobalTransform>());
+        let before = (written(still), written(child));
+
+        world.insert_component(moving, translated(3.0, 0.0));
+        world.update();
+        assert_eq!(written(still), before.0);
+        assert_ne!(written(child), before.1);
+        assert_near(global(&world, child).expect("Child has no GlobalTransform").translation, Vec2::new(3.0, 1.0));
+    }
+}
@@ -0,0 +1,248 @@
+use std::any::TypeId;
+use std::fmt::{Debug, Display, Formatter};
+use crate::ecs::StorageType;
+use crate::ecs::entity::EntityType;
+use crate::ecs::mem::archetype::{ArchetypeId, ComponentInfo};
+use crate::ecs::mem::storage::ComponentStorage;
+
+/// Memory use of all components of one type. `len` counts the stored components, `capacity` the components that fit
+/// into the allocated columns, summed up over all archetypes.
+#[derive(Clone, Debug)]
+pub struct ComponentStats {
+    pub name: &'static str,
+    pub type_id: TypeId,
+    pub storage: StorageType,
+    pub size: usize,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl ComponentStats {
+    pub fn bytes_used(&self) -> usize {
+        self.len * self.size
+    }
+
+    pub fn bytes_reserved(&self) -> usize {
+        self.capacity * self.size
+    }
+}
+
+/// Memory use of all behaviors of one type.
+#[derive(Clone, Debug)]
+pub struct BehaviorStats {
+    pub name: &'static str,
+    pub type_id: TypeId,
+    pub size: usize,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl BehaviorStats {
+    pub fn bytes_used(&self) -> usize {
+        self.len * self.size
+    }
+
+    pub fn bytes_reserved(&self) -> usize {
+        self.capacity * self.size
+    }
+}
+
+/// A summary of a world, returned by [`World::stats`](crate::ecs::world::World::stats). Components and behaviors are
+/// sorted by name.
+#[derive(Clone, Debug)]
+pub struct WorldStats {
+    pub entities: usize,
+    pub archetypes: usize,
+    pub components: Vec<ComponentStats>,
+    pub behaviors: Vec<BehaviorStats>,
+}
+
+impl WorldStats {
+    pub fn bytes_used(&self) -> usize {
+        self.components.iter().map(ComponentStats::bytes_used).sum::<usize>()
+            + self.behaviors.iter().map(BehaviorStats::bytes_used).sum::<usize>()
+    }
+
+    pub fn bytes_reserved(&self) -> usize {
+        self.components.iter().map(ComponentStats::bytes_reserved).sum::<usize>()
+            + self.behaviors.iter().map(BehaviorStats::bytes_reserved).sum::<usize>()
+    }
+}
+
+impl Display for WorldStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "World: {} entities, {} archetypes, {}/{} bytes", self.entities, self.archetypes, self.bytes_used(), self.bytes_reserved())?;
+        writeln!(f, "Components:")?;
+        for c in &self.components {
+            let storage = match c.storage {
+                StorageType::Table => "table",
+                StorageType::SparseSet => "sparse",
+            };
+            writeln!(f, "  {} ({storage}): {}/{}, {} bytes each, {}/{} bytes", c.name, c.len, c.capacity, c.size, c.bytes_used(), c.bytes_reserved())?;
+        }
+        writeln!(f, "Behaviors:")?;
+        for b in &self.behaviors {
+            writeln!(f, "  {}: {}/{}, {} bytes each, {}/{} bytes", b.name, b.len, b.capacity, b.size, b.bytes_used(), b.bytes_reserved())?;
+        }
+        Ok(())
+    }
+}
+
+/// One component of an [`EntityView`]. `value` is only set for types registered with
+/// [`World::register_debug`](crate::ecs::world::World::register_debug).
+#[derive(Clone, Debug)]
+pub struct ComponentView {
+    pub name: &'static str,
+    pub type_id: TypeId,
+    pub value: Option<String>,
+}
+
+/// An entity along with its components, sorted by name, and the name of its behavior.
+#[derive(Clone, Debug)]
+pub struct EntityView {
+    pub entity: EntityType,
+    pub archetype: ArchetypeId,
+    pub behavior: Option<&'static str>,
+    pub components: Vec<ComponentView>,
+}
+
+impl Display for EntityView {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (archetype {}", self.entity, self.archetype)?;
+        if let Some(behavior) = self.behavior {
+            write!(f, ", {behavior}")?;
+        }
+        writeln!(f, ")")?;
+        for component in &self.components {
+            match &component.value {
+                Some(value) => writeln!(f, "  {} = {value}", component.name)?,
+                None => writeln!(f, "  {}", component.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ErasedDebug<'a> {
+    ptr: *const u8,
+    info: &'a ComponentInfo,
+}
+
+impl Debug for ErasedDebug<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.info.debug {
+            Some(debug) => unsafe { debug(self.ptr, f) },
+            None => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn component_stats(storage: &ComponentStorage) -> Vec<ComponentStats> {
+    let mut stats: Vec<ComponentStats> = storage.component_infos().map(|(ty, info)| {
+        let storage_type = storage.storage_type(ty);
+        let columns: Vec<_> = match storage.sparse_set(ty) {
+            Some(set) => vec![set.column()],
+            None => storage.archetypes().iter().filter_map(|archetype| archetype.column(ty)).collect(),
+        };
+        ComponentStats {
+            name: info.name,
+            type_id: ty,
+            storage: storage_type,
+            size: info.layout.size(),
+            len: columns.iter().map(|column| column.len()).sum(),
+            capacity: columns.iter().map(|column| column.blob().capacity()).sum(),
+        }
+    }).collect();
+    stats.sort_by(|a, b| a.name.cmp(b.name));
+    stats
+}
+
+pub(crate) fn inspect(storage: &ComponentStorage, entity: EntityType, behavior: Option<&'static str>) -> Option<EntityView> {
+    let location = storage.location(entity)?;
+    let mut components: Vec<ComponentView> = storage.component_infos()
+        .filter(|(ty, _)| storage.has_type(entity, *ty))
+        .map(|(ty, info)| ComponentView {
+            name: info.name,
+            type_id: ty,
+            value: info.debug.and(storage.component_ptr(entity, ty)).map(|ptr| format!("{:?}", ErasedDebug { ptr, info })),
+        })
+        .collect();
+    components.sort_by(|a, b| a.name.cmp(b.name));
+    Some(EntityView {
+        entity,
+        archetype: location.archetype,
+        behavior,
+        components,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::StorageType;
+    use crate::ecs::entity::Entity;
+    use crate::ecs::inspect::ComponentStats;
+    use crate::ecs::testing::{Health, Mana, Regeneration};
+    use crate::ecs::world::World;
+
+    fn component<'a>(components: &'a [ComponentStats], name: &str) -> &'a ComponentStats {
+        components.iter().find(|stats| stats.name.ends_with(name)).expect("Component is missing from the stats")
+    }
+
+    #[test]
+    fn stats_count_what_is_stored_and_what_is_reserved() {
+        let mut world = World::new();
+        world.register_component::<Mana>(StorageType::SparseSet);
+        let entities: Vec<_> = (0..3)
+            .map(|_| world.create_entity(Entity::<Regeneration, (Health, Mana)>::new).expect("Entity could not be created"))
+            .collect();
+        world.create_entity(Entity::<Regeneration, (Health,)>::new);
+        world.despawn(entities[0]);
+
+        let stats = world.stats();
+        assert_eq!((stats.entities, stats.archetypes), (3, 2));
+        let health = component(&stats.components, "Health");
+        assert_eq!((health.storage, health.len, health.size), (StorageType::Table, 3, 4));
+        assert!(health.capacity >= 4);
+        let mana = component(&stats.components, "Mana");
+        assert_eq!((mana.storage, mana.len), (StorageType::SparseSet, 2));
+        assert!(mana.capacity >= 3 && mana.bytes_reserved() >= mana.bytes_used());
+        assert_eq!(stats.behaviors.iter().map(|behavior| (behavior.name, behavior.len)).collect::<Vec<_>>(), [("mvengine::ecs::testing::Regeneration", 3)]);
+    }
+
+    #[test]
+    fn only_debug_types_show_their_values() {
+        let mut world = World::new();
+        world.register_debug::<Health>();
+        let entity = world.create_entity(Entity::<Regeneration, (Health, Mana)>::new).expect("Entity could not be created");
+        world.insert_component(entity, Health(5));
+
+        let view = world.inspect(entity).expect("Entity is not alive");
+        let values: Vec<_> = view.components.iter().map(|component| (component.name, component.value.as_deref())).collect();
+        assert_eq!(values, [("mvengine::ecs::testing::Health", Some("Health(5)")), ("mvengine::ecs::testing::Mana", None)]);
+        assert_eq!(view.behavior, Some("mvengine::ecs::testing::Regeneration"));
+        world.despawn(entity);
+        assert!(world.inspect(entity).is_none());
+    }
+
+    #[test]
+    fn dumps_list_stats_and_entities() {
+        let mut world = World::new();
+        world.register_debug::<Health>();
+        let entity = world.create_entity(Entity::<Regeneration, (Health, Mana)>::new).expect("Entity could not be created");
+        world.insert_component(entity, Health(7));
+
+        let expected = "\
+World: 1 entities, 3 archetypes, 16/20 bytes
+Components:
+  mvengine::ecs::testing::Health (table): 1/2, 4 bytes each, 4/8 bytes
+  mvengine::ecs::testing::Mana (table): 1/1, 4 bytes each, 4/4 bytes
+Behaviors:
+  mvengine::ecs::testing::Regeneration: 1/1, 8 bytes each, 8/8 bytes
+Entities:
+  0v0 (archetype 2, mvengine::ecs::testing::Regeneration)
+    mvengine::ecs::testing::Health = Health(7)
+    mvengine::ecs::testing::Mana
+";
+        assert_eq!(world.dump(), expected);
+    }
+}
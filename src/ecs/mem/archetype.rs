@@ -0,0 +1,293 @@
+use std::alloc::Layout;
+use std::any::TypeId;
+use std::cell::UnsafeCell;
+use std::fmt::{Debug, Formatter};
+use hashbrown::HashMap;
+use crate::ecs::Component;
+use crate::ecs::entity::EntityType;
+use crate::ecs::mem::conblob::{drop_fn, ContinuousBlob};
+use crate::ecs::query::clamp_tick;
+
+pub type ArchetypeId = usize;
+
+/// The archetype every entity starts out in, it has no columns.
+pub const EMPTY_ARCHETYPE: ArchetypeId = 0;
+
+#[derive(Copy, Clone)]
+pub struct ComponentInfo {
+    pub name: &'static str,
+    pub fields: &'static [&'static str],
+    pub layout: Layout,
+    pub(crate) drop: Option<unsafe fn(*mut u8)>,
+    pub(crate) debug: Option<unsafe fn(*const u8, &mut Formatter) -> std::fmt::Result>,
+}
+
+impl ComponentInfo {
+    pub fn of<T: Component>() -> Self {
+        Self {
+            name: T::name(),
+            fields: T::fields(),
+            layout: Layout::new::<T>(),
+            drop: drop_fn::<T>(),
+            debug: None,
+        }
+    }
+}
+
+pub(crate) unsafe fn debug_fn<T: Debug>(ptr: *const u8, f: &mut Formatter) -> std::fmt::Result {
+    (*ptr.cast::<T>()).fmt(f)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct EntityLocation {
+    pub archetype: ArchetypeId,
+    pub row: usize,
+}
+
+/// One component type of an archetype, along with the ticks at which every row was added and last changed. The ticks
+/// live in [`UnsafeCell`]s because queries and `ComponentStorage::borrow_component_mut` mark rows as changed through a
+/// shared reference, guarded by the borrow flags instead of `&mut`.
+pub struct Column {
+    blob: ContinuousBlob,
+    added: Vec<UnsafeCell<u32>>,
+    changed: Vec<UnsafeCell<u32>>,
+}
+
+impl Column {
+    pub(crate) fn new(info: &ComponentInfo) -> Self {
+        Self {
+            blob: ContinuousBlob::with_drop(info.layout, info.drop),
+            added: Vec::new(),
+            changed: Vec::new(),
+        }
+    }
+
+    pub fn blob(&self) -> &ContinuousBlob {
+        &self.blob
+    }
+
+    pub fn len(&self) -> usize {
+        self.blob.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blob.is_empty()
+    }
+
+    pub fn get<T: Sized + 'static>(&self, row: usize) -> Option<&T> {
+        self.blob.get(row)
+    }
+
+    pub fn get_mut<T: Sized + 'static>(&mut self, row: usize) -> Option<&mut T> {
+        self.blob.get_mut(row)
+    }
+
+    pub fn added_tick(&self, row: usize) -> Option<u32> {
+        // SAFETY: ticks are only written while the component is mutably borrowed, which excludes this read.
+        self.added.get(row).map(|tick| unsafe { *tick.get() })
+    }
+
+    pub fn changed_tick(&self, row: usize) -> Option<u32> {
+        // SAFETY: see `added_tick`.
+        self.changed.get(row).map(|tick| unsafe { *tick.get() })
+    }
+
+    pub(crate) fn set_changed(&mut self, row: usize, tick: u32) {
+        if let Some(changed) = self.changed.get_mut(row) {
+            *changed.get_mut() = tick;
+        }
+    }
+
+    /// Clamps every tick older than [`MAX_CHANGE_AGE`] before `this_run`, see [`ComponentStorage::check_ticks`].
+    ///
+    /// [`MAX_CHANGE_AGE`]: crate::ecs::query::MAX_CHANGE_AGE
+    /// [`ComponentStorage::check_ticks`]: crate::ecs::mem::storage::ComponentStorage::check_ticks
+    pub(crate) fn clamp_ticks(&mut self, this_run: u32) {
+        for tick in self.added.iter_mut().chain(self.changed.iter_mut()) {
+            *tick.get_mut() = clamp_tick(*tick.get_mut(), this_run);
+        }
+    }
+
+    pub(crate) fn data_ptr(&self) -> *mut u8 {
+        self.blob.as_ptr()
+    }
+
+    pub(crate) fn added_ptr(&self) -> *mut u32 {
+        UnsafeCell::raw_get(self.added.as_ptr())
+    }
+
+    pub(crate) fn changed_ptr(&self) -> *mut u32 {
+        UnsafeCell::raw_get(self.changed.as_ptr())
+    }
+
+    pub(crate) fn push<T: Sized + 'static>(&mut self, value: T, tick: u32) {
+        if self.blob.push_next(value).is_some() {
+            self.added.push(UnsafeCell::new(tick));
+            self.changed.push(UnsafeCell::new(tick));
+        }
+    }
+
+    /// # Safety
+    /// Same as [`ContinuousBlob::push_raw`].
+    pub(crate) unsafe fn push_raw(&mut self, src: *const u8, added: u32, changed: u32) {
+        self.blob.push_raw(src);
+        self.added.push(UnsafeCell::new(added));
+        self.changed.push(UnsafeCell::new(changed));
+    }
+
+    pub(crate) fn swap_remove_drop(&mut self, row: usize) {
+        if self.blob.swap_remove_drop(row) {
+            self.added.swap_remove(row);
+            self.changed.swap_remove(row);
+        }
+    }
+
+    /// # Safety
+    /// Same as [`ContinuousBlob::swap_remove_forget`].
+    pub(crate) unsafe fn swap_remove_forget(&mut self, row: usize) {
+        if self.blob.swap_remove_forget(row) {
+            self.added.swap_remove(row);
+            self.changed.swap_remove(row);
+        }
+    }
+}
+
+/// A table of all entities that have exactly the same set of components. Every component type is stored in its own
+/// column and row `n` of every column belongs to `entities[n]`, so queries can walk the columns linearly.
+pub struct Archetype {
+    id: ArchetypeId,
+    types: Vec<TypeId>,
+    columns: Vec<Column>,
+    entities: Vec<EntityType>,
+    add_edges: HashMap<TypeId, ArchetypeId>,
+    remove_edges: HashMap<TypeId, ArchetypeId>,
+}
+
+impl Archetype {
+    /// `types` has to be sorted and every entry needs an [`ComponentInfo`] in `infos`.
+    pub(crate) fn new(id: ArchetypeId, types: Vec<TypeId>, infos: &HashMap<TypeId, ComponentInfo>) -> Self {
+        let columns = types.iter().map(|ty| Column::new(&infos[ty])).collect();
+
+        Self {
+            id,
+            types,
+            columns,
+            entities: Vec::new(),
+            add_edges: HashMap::new(),
+            remove_edges: HashMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> ArchetypeId {
+        self.id
+    }
+
+    pub fn types(&self) -> &[TypeId] {
+        &self.types
+    }
+
+    pub fn entities(&self) -> &[EntityType] {
+        &self.entities
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn column_index(&self, ty: TypeId) -> Option<usize> {
+        self.types.binary_search(&ty).ok()
+    }
+
+    pub fn has(&self, ty: TypeId) -> bool {
+        self.column_index(ty).is_some()
+    }
+
+    pub fn column(&self, ty: TypeId) -> Option<&Column> {
+        self.column_index(ty).map(|idx| &self.columns[idx])
+    }
+
+    pub(crate) fn columns_mut(&mut self) -> &mut [Column] {
+        &mut self.columns
+    }
+
+    pub fn column_mut(&mut self, ty: TypeId) -> Option<&mut Column> {
+        self.column_index(ty).map(|idx| &mut self.columns[idx])
+    }
+
+    pub(crate) fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub(crate) fn add_edge(&self, ty: TypeId) -> Option<ArchetypeId> {
+        self.add_edges.get(&ty).copied()
+    }
+
+    pub(crate) fn remove_edge(&self, ty: TypeId) -> Option<ArchetypeId> {
+        self.remove_edges.get(&ty).copied()
+    }
+
+    pub(crate) fn set_add_edge(&mut self, ty: TypeId, target: ArchetypeId) {
+        self.add_edges.insert(ty, target);
+    }
+
+    pub(crate) fn set_remove_edge(&mut self, ty: TypeId, target: ArchetypeId) {
+        self.remove_edges.insert(ty, target);
+    }
+
+    /// Appends a row for `entity`. The caller has to push exactly one value into every column afterward.
+    pub(crate) fn push_entity(&mut self, entity: EntityType) -> usize {
+        self.entities.push(entity);
+        self.entities.len() - 1
+    }
+
+    /// Drops every component in `row` and fills the gap with the last row. Returns the entity that was moved into
+    /// `row`, if any.
+    pub(crate) fn remove_row(&mut self, row: usize) -> Option<EntityType> {
+        for column in &mut self.columns {
+            column.swap_remove_drop(row);
+        }
+        self.entities.swap_remove(row);
+        self.entities.get(row).copied()
+    }
+
+    /// Removes `row` like [`Archetype::remove_row`], but without dropping its components.
+    ///
+    /// # Safety
+    /// The caller must have moved the components out already.
+    pub(crate) unsafe fn remove_row_forget(&mut self, row: usize) -> Option<EntityType> {
+        for column in &mut self.columns {
+            column.swap_remove_forget(row);
+        }
+        self.entities.swap_remove(row);
+        self.entities.get(row).copied()
+    }
+
+    /// Moves the components in `row` into a new row of `target`. Components `target` has no column for are dropped,
+    /// unless `drop_missing` is false, in which case the caller must have moved them out already. Columns of `target`
+    /// this archetype does not have are left for the caller to fill. Returns the new row and the entity that was moved
+    /// into `row` of this archetype, if any.
+    pub(crate) fn move_row(&mut self, row: usize, target: &mut Archetype, drop_missing: bool) -> (usize, Option<EntityType>) {
+        let entity = self.entities[row];
+        let new_row = target.push_entity(entity);
+        for (ty, column) in self.types.iter().zip(self.columns.iter_mut()) {
+            if let Some(target_column) = target.column_mut(*ty) {
+                unsafe {
+                    if let (Some(ptr), Some(added), Some(changed)) = (column.blob.get_ptr(row), column.added_tick(row), column.changed_tick(row)) {
+                        target_column.push_raw(ptr, added, changed);
+                    }
+                    column.swap_remove_forget(row);
+                }
+            } else if drop_missing {
+                column.swap_remove_drop(row);
+            } else {
+                unsafe { column.swap_remove_forget(row); }
+            }
+        }
+        self.entities.swap_remove(row);
+        (new_row, self.entities.get(row).copied())
+    }
+}
@@ -8,36 +8,71 @@ pub struct ContinuousBlob {
     data: *mut u8,
     len: usize,
     capacity: usize,
-    layout: Layout
+    layout: Layout,
+    drop: Option<unsafe fn(*mut u8)>
 }
 
 impl ContinuousBlob {
     pub fn new(layout: Layout) -> Self {
-        let ptr = unsafe { std::alloc::alloc(layout) };
+        Self::with_drop(layout, None)
+    }
 
-        let mut this = Self {
-            data: ptr,
-            len: 0,
-            capacity: layout.size(),
-            layout,
+    /// Creates a blob for values of type `T`, which runs `T`'s destructor for every value that is removed or still
+    /// stored when the blob is dropped.
+    pub fn of<T: Sized + 'static>() -> Self {
+        Self::with_drop(Layout::new::<T>(), drop_fn::<T>())
+    }
+
+    pub fn with_drop(layout: Layout, drop: Option<unsafe fn(*mut u8)>) -> Self {
+        let layout = layout.pad_to_align();
+        let (data, capacity) = if layout.size() == 0 {
+            (std::ptr::without_provenance_mut(layout.align()), usize::MAX)
+        } else {
+            let ptr = unsafe { std::alloc::alloc(layout) };
+            if ptr.is_null() {
+                std::alloc::handle_alloc_error(layout);
+            }
+            (ptr, 1)
         };
 
-        this
+        Self {
+            data,
+            len: 0,
+            capacity,
+            layout,
+            drop,
+        }
     }
 
     fn realloc(&mut self) {
-        self.capacity = (self.capacity as f64 * PHI).ceil() as usize;
+        let capacity = ((self.capacity as f64 * PHI).ceil() as usize).max(self.capacity + 1);
         unsafe {
-            self.data = std::alloc::realloc(self.data, Layout::from_size_align_unchecked(
-            self.capacity * self.layout.size(), self.layout.align()
-            ), self.capacity);
+            let old = Layout::from_size_align_unchecked(self.capacity * self.layout.size(), self.layout.align());
+            let ptr = std::alloc::realloc(self.data, old, capacity * self.layout.size());
+            if ptr.is_null() {
+                std::alloc::handle_alloc_error(Layout::from_size_align_unchecked(capacity * self.layout.size(), self.layout.align()));
+            }
+            self.data = ptr;
         }
+        self.capacity = capacity;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
     pub fn push_next<T: Sized + 'static>(&mut self, t: T) -> Option<usize> {
         unsafe {
-            if self.layout.equivalent(&Layout::for_value(&t)) {
-                if self.capacity < self.len + self.layout.size() {
+            if self.layout.equivalent(&Layout::new::<T>()) {
+                if self.len == self.capacity {
                     self.realloc();
                 }
                 let added = self.data.add(self.len * self.layout.size());
@@ -50,8 +85,94 @@ impl ContinuousBlob {
         None
     }
 
+    /// Removes the value at `idx` and moves the last value into the freed slot. If `idx < self.len()` afterward,
+    /// the value that was previously stored at index `self.len()` now lives at `idx`.
+    pub fn swap_remove<T: Sized + 'static>(&mut self, idx: usize) -> Option<T> {
+        if idx < self.len && self.layout.equivalent(&Layout::new::<T>()) {
+            unsafe {
+                let removed = (self.data.add(idx * self.layout.size()) as *mut T).read();
+                self.fill_gap(idx);
+                return Some(removed);
+            }
+        }
+        None
+    }
+
+    /// Creates an empty blob for values of the same type as this one.
+    pub fn empty_like(&self) -> Self {
+        Self::with_drop(self.layout, self.drop)
+    }
+
+    /// Copies the bytes of one value from `src` into a new slot at the end of the blob.
+    ///
+    /// # Safety
+    /// `src` must point to a valid value of the type this blob was created for. Ownership of the value moves into
+    /// the blob, the source must not be dropped afterward.
+    pub unsafe fn push_raw(&mut self, src: *const u8) -> usize {
+        if self.len == self.capacity {
+            self.realloc();
+        }
+        std::ptr::copy_nonoverlapping(src, self.data.add(self.len * self.layout.size()), self.layout.size());
+        self.len += 1;
+        self.len - 1
+    }
+
+    /// Removes the value at `idx` like [`ContinuousBlob::swap_remove_drop`], but without running its destructor.
+    ///
+    /// # Safety
+    /// The value must have been moved out of the blob beforehand, otherwise it is leaked.
+    pub unsafe fn swap_remove_forget(&mut self, idx: usize) -> bool {
+        if idx < self.len {
+            self.fill_gap(idx);
+            return true;
+        }
+        false
+    }
+
+    /// Same as [`ContinuousBlob::swap_remove`], but drops the removed value in place instead of returning it.
+    pub fn swap_remove_drop(&mut self, idx: usize) -> bool {
+        if idx < self.len {
+            unsafe {
+                if let Some(drop) = self.drop {
+                    drop(self.data.add(idx * self.layout.size()));
+                }
+                self.fill_gap(idx);
+            }
+            return true;
+        }
+        false
+    }
+
+    unsafe fn fill_gap(&mut self, idx: usize) {
+        let last = self.len - 1;
+        if idx != last {
+            std::ptr::copy_nonoverlapping(
+                self.data.add(last * self.layout.size()),
+                self.data.add(idx * self.layout.size()),
+                self.layout.size()
+            );
+        }
+        self.len -= 1;
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.data
+    }
+
+    pub fn get_ptr(&self, idx: usize) -> Option<*mut u8> {
+        if idx < self.len {
+            unsafe { Some(self.data.add(idx * self.layout.size())) }
+        } else {
+            None
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
     pub fn get<T: Sized + 'static>(&self, idx: usize) -> Option<&T> {
-        if idx >= 0 && idx < self.len {
+        if idx < self.len {
             unsafe {
                 let added = self.data.add(idx * self.layout.size());
                 let typed = added as *mut T;
@@ -62,7 +183,7 @@ impl ContinuousBlob {
     }
 
     pub fn get_mut<T: Sized + 'static>(&mut self, idx: usize) -> Option<&mut T> {
-        if idx >= 0 && idx < self.len {
+        if idx < self.len {
             unsafe {
                 let added = self.data.add(idx * self.layout.size());
                 let typed = added as *mut T;
@@ -112,4 +233,73 @@ impl ContinuousBlob {
         }
         out
     }
-}
\ No newline at end of file
+}
+
+impl Drop for ContinuousBlob {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(drop) = self.drop {
+                for i in 0..self.len {
+                    drop(self.data.add(i * self.layout.size()));
+                }
+            }
+            if self.layout.size() != 0 {
+                std::alloc::dealloc(self.data, Layout::from_size_align_unchecked(self.capacity * self.layout.size(), self.layout.align()));
+            }
+        }
+    }
+}
+
+pub(crate) fn drop_fn<T>() -> Option<unsafe fn(*mut u8)> {
+    if std::mem::needs_drop::<T>() {
+        Some(drop_value::<T> as unsafe fn(*mut u8))
+    } else {
+        None
+    }
+}
+
+unsafe fn drop_value<T>(ptr: *mut u8) {
+    std::ptr::drop_in_place(ptr as *mut T);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use crate::ecs::mem::conblob::ContinuousBlob;
+    use crate::ecs::testing::{Health, Tracked};
+
+    #[test]
+    fn swap_remove_fills_the_gap_with_the_last_value() {
+        let mut blob = ContinuousBlob::of::<Health>();
+        for value in 0..4 {
+            assert_eq!(blob.push_next(Health(value)), Some(value as usize));
+        }
+        assert_eq!(blob.swap_remove::<Health>(1), Some(Health(1)));
+        assert_eq!(blob.get_all::<Health>(), [&Health(0), &Health(3), &Health(2)]);
+        assert_eq!(blob.swap_remove::<Health>(2), Some(Health(2)));
+        assert_eq!(blob.swap_remove::<Health>(2), None);
+        assert_eq!(blob.swap_remove::<u64>(0), None);
+
+        let capacity = blob.capacity();
+        blob.push_next(Health(4));
+        assert_eq!(blob.get_all::<Health>(), [&Health(0), &Health(3), &Health(4)]);
+        assert_eq!(blob.capacity(), capacity);
+    }
+
+    #[test]
+    fn removed_and_remaining_values_are_dropped_once() {
+        let drops = Tracked::counter();
+        let mut blob = ContinuousBlob::of::<Tracked>();
+        for _ in 0..3 {
+            blob.push_next(Tracked(drops.clone()));
+        }
+        assert!(blob.swap_remove_drop(0));
+        assert!(!blob.swap_remove_drop(2));
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        let taken = blob.swap_remove::<Tracked>(0);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        drop(taken);
+        drop(blob);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::ecs::entity::EntityType;
+
+/// Hands out generational entity handles. Indices of despawned entities are recycled, and every recycle bumps the
+/// generation of that index, so old handles can be told apart from the entity that reuses their index.
+pub struct Entities {
+    generations: Vec<u32>,
+    alive: Vec<bool>,
+    free: Vec<u32>,
+}
+
+impl Entities {
+    pub fn new() -> Self {
+        Self {
+            generations: Vec::new(),
+            alive: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    pub fn alloc(&mut self) -> EntityType {
+        if let Some(index) = self.free.pop() {
+            self.alive[index as usize] = true;
+            EntityType::new(index, self.generations[index as usize])
+        } else {
+            let index = self.generations.len() as u32;
+            self.generations.push(0);
+            self.alive.push(true);
+            EntityType::new(index, 0)
+        }
+    }
+
+    /// Frees the index of `entity`. Returns false if the handle was already dead.
+    pub fn free(&mut self, entity: EntityType) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        let index = entity.index() as usize;
+        self.alive[index] = false;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        self.free.push(entity.index());
+        true
+    }
+
+    pub fn is_alive(&self, entity: EntityType) -> bool {
+        let index = entity.index() as usize;
+        index < self.generations.len() && self.alive[index] && self.generations[index] == entity.generation()
+    }
+
+    pub fn len(&self) -> usize {
+        self.generations.len() - self.free.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::entity::EntityType;
+    use crate::ecs::mem::entities::Entities;
+
+    #[test]
+    fn freed_indices_come_back_with_a_new_generation() {
+        let mut entities = Entities::new();
+        let first = entities.alloc();
+        let second = entities.alloc();
+        assert_eq!((first.index(), second.index()), (0, 1));
+
+        assert!(entities.free(first));
+        assert!(!entities.free(first));
+        assert!(!entities.is_alive(first));
+        assert_eq!(entities.len(), 1);
+
+        let reused = entities.alloc();
+        assert_eq!(reused.index(), first.index());
+        assert_eq!(reused.generation(), first.generation() + 1);
+        assert!(entities.is_alive(reused) && !entities.is_alive(first));
+        assert!(!entities.free(first));
+        assert!(entities.is_alive(reused));
+        assert!(!entities.is_alive(EntityType::new(7, 0)));
+    }
+
+    #[test]
+    fn handles_round_trip_through_bits() {
+        let entity = EntityType::new(42, 7);
+        assert_eq!(EntityType::from_bits(entity.to_bits()), entity);
+        assert_eq!(entity.to_bits(), 7 << 32 | 42);
+        assert_eq!(entity.to_string(), "42v7");
+    }
+}
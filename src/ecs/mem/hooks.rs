@@ -0,0 +1,99 @@
+use crate::ecs::Component;
+use crate::ecs::command::Commands;
+use crate::ecs::entity::EntityType;
+use crate::ecs::resource::Resources;
+
+pub(crate) type Hook = Box<dyn Fn(EntityType, *const u8, &Commands, &Resources) + Send + Sync>;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) enum HookKind {
+    Add,
+    Replace,
+    Remove,
+}
+
+/// The hooks registered for one component type.
+#[derive(Default)]
+pub(crate) struct ComponentHooks {
+    on_add: Vec<Hook>,
+    on_replace: Vec<Hook>,
+    on_remove: Vec<Hook>,
+}
+
+impl ComponentHooks {
+    pub(crate) fn push(&mut self, kind: HookKind, hook: Hook) {
+        match kind {
+            HookKind::Add => self.on_add.push(hook),
+            HookKind::Replace => self.on_replace.push(hook),
+            HookKind::Remove => self.on_remove.push(hook),
+        }
+    }
+
+    pub(crate) fn get(&self, kind: HookKind) -> &[Hook] {
+        match kind {
+            HookKind::Add => &self.on_add,
+            HookKind::Replace => &self.on_replace,
+            HookKind::Remove => &self.on_remove,
+        }
+    }
+}
+
+pub(crate) fn erase<T: Component>(hook: impl Fn(EntityType, &T, &Commands) + Send + Sync + 'static) -> Hook {
+    Box::new(move |entity, ptr, commands, _| hook(entity, unsafe { &*ptr.cast::<T>() }, commands))
+}
+
+pub(crate) fn erase_with_resources<T: Component>(hook: impl Fn(EntityType, &T, &Resources) + Send + Sync + 'static) -> Hook {
+    Box::new(move |entity, ptr, _, resources| hook(entity, unsafe { &*ptr.cast::<T>() }, resources))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::ecs::ECS;
+    use crate::ecs::command::Commands;
+    use crate::ecs::entity::{Entity, NoBehavior};
+    use crate::ecs::testing::{Health, Tag};
+
+    #[test]
+    fn hooks_see_every_change_of_their_component() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        let record = |kind: &'static str| {
+            let log = log.clone();
+            move |_, health: &Health, _: &Commands| log.lock().expect("Lock was poisoned").push(format!("{kind} {}", health.0))
+        };
+        world.on_add(record("add"));
+        world.on_replace(record("replace"));
+        world.on_remove(record("remove"));
+
+        let first = world.create_entity(Entity::<NoBehavior, (Health,)>::new).expect("Entity could not be created");
+        let second = world.create_entity(Entity::<NoBehavior, (Tag,)>::new).expect("Entity could not be created");
+        world.insert_component(second, Health(1));
+        world.insert_component(second, Health(2));
+        world.remove_component::<Health>(second);
+        world.insert_component(first, Health(3));
+        world.despawn(first);
+        world.despawn(second);
+        assert_eq!(*log.lock().expect("Lock was poisoned"), ["add 0", "add 1", "replace 1", "remove 2", "replace 0", "remove 3"]);
+    }
+
+    #[test]
+    fn hook_commands_run_at_the_next_sync_point() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        world.on_add::<Health>(|entity, _, commands| commands.insert(entity, Tag));
+        world.on_remove::<Health>(|entity, _, commands| commands.remove::<Tag>(entity));
+        let entity = world.create_entity(Entity::<NoBehavior, (Tag,)>::new).expect("Entity could not be created");
+        world.remove_component::<Tag>(entity);
+        world.insert_component(entity, Health(1));
+        assert!(!world.has_component::<Tag>(entity));
+        world.apply_commands();
+        assert!(world.has_component::<Tag>(entity));
+
+        world.remove_component::<Health>(entity);
+        assert!(world.has_component::<Tag>(entity));
+        world.apply_commands();
+        assert!(!world.has_component::<Tag>(entity));
+    }
+}
@@ -1,2 +1,6 @@
+pub mod archetype;
 pub mod conblob;
+pub mod entities;
+pub mod hooks;
+pub mod sparse;
 pub mod storage;
\ No newline at end of file
@@ -0,0 +1,110 @@
+use crate::ecs::entity::EntityType;
+use crate::ecs::mem::archetype::{Column, ComponentInfo};
+
+const EMPTY: u32 = u32::MAX;
+
+/// Storage for one component type that lives outside the archetype tables. Adding and removing the component only
+/// touches this set, so entities never change their archetype for it.
+pub struct SparseSet {
+    sparse: Vec<u32>,
+    entities: Vec<EntityType>,
+    column: Column,
+}
+
+impl SparseSet {
+    pub(crate) fn new(info: &ComponentInfo) -> Self {
+        Self {
+            sparse: Vec::new(),
+            entities: Vec::new(),
+            column: Column::new(info),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn entities(&self) -> &[EntityType] {
+        &self.entities
+    }
+
+    pub fn column(&self) -> &Column {
+        &self.column
+    }
+
+    pub(crate) fn column_mut(&mut self) -> &mut Column {
+        &mut self.column
+    }
+
+    /// The row of the component of `entity` in [`SparseSet::column`].
+    pub fn index_of(&self, entity: EntityType) -> Option<usize> {
+        let idx = *self.sparse.get(entity.index() as usize)?;
+        if idx != EMPTY && self.entities[idx as usize] == entity {
+            Some(idx as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, entity: EntityType) -> bool {
+        self.index_of(entity).is_some()
+    }
+
+    /// Adds the component of `entity`, which must not have one yet. Returns its row.
+    pub(crate) fn insert<T: Sized + 'static>(&mut self, entity: EntityType, value: T, tick: u32) -> usize {
+        let index = entity.index() as usize;
+        if index >= self.sparse.len() {
+            self.sparse.resize(index + 1, EMPTY);
+        }
+        self.sparse[index] = self.entities.len() as u32;
+        self.entities.push(entity);
+        self.column.push(value, tick);
+        self.entities.len() - 1
+    }
+
+    /// Adds the component of `entity` by copying it from `src`. Returns its row.
+    ///
+    /// # Safety
+    /// Same as [`ContinuousBlob::push_raw`](crate::ecs::mem::conblob::ContinuousBlob::push_raw).
+    pub(crate) unsafe fn insert_raw(&mut self, entity: EntityType, src: *const u8, tick: u32) -> usize {
+        let index = entity.index() as usize;
+        if index >= self.sparse.len() {
+            self.sparse.resize(index + 1, EMPTY);
+        }
+        self.sparse[index] = self.entities.len() as u32;
+        self.entities.push(entity);
+        self.column.push_raw(src, tick, tick);
+        self.entities.len() - 1
+    }
+
+    /// Removes the component of `entity` and drops it.
+    pub(crate) fn remove_drop(&mut self, entity: EntityType) -> bool {
+        let Some(idx) = self.index_of(entity) else { return false; };
+        self.column.swap_remove_drop(idx);
+        self.unlink(entity, idx);
+        true
+    }
+
+    /// Removes the component of `entity` without dropping it.
+    ///
+    /// # Safety
+    /// The caller must have moved the component out already.
+    pub(crate) unsafe fn remove_forget(&mut self, entity: EntityType) -> bool {
+        let Some(idx) = self.index_of(entity) else { return false; };
+        self.column.swap_remove_forget(idx);
+        self.unlink(entity, idx);
+        true
+    }
+
+    fn unlink(&mut self, entity: EntityType, idx: usize) {
+        self.entities.swap_remove(idx);
+        if let Some(moved) = self.entities.get(idx) {
+            self.sparse[moved.index() as usize] = idx as u32;
+        }
+        self.sparse[entity.index() as usize] = EMPTY;
+    }
+}
@@ -1,146 +1,678 @@
-use crate::ecs::mem::conblob::ContinuousBlob;
+use crate::ecs::mem::archetype::{debug_fn, Archetype, ArchetypeId, Column, ComponentInfo, EntityLocation, EMPTY_ARCHETYPE};
 use hashbrown::HashMap;
-use mvutils::hashers::{U64IdentityHasher, UsizeIdentityHasher};
-use std::alloc::Layout;
 use std::any::TypeId;
-use mvutils::unsafe_utils::Unsafe;
-use mvengine_proc_macro::generate_get_components;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::ecs::{Component, StorageType};
+use crate::ecs::borrow::{Borrow, BorrowFlags, Ref, RefMut};
+use crate::ecs::command::{CommandQueue, Commands};
 use crate::ecs::entity::EntityType;
-
-pub(crate) type ComponentIdx = u64;
+use crate::ecs::mem::entities::Entities;
+use crate::ecs::mem::hooks::{erase, erase_with_resources, ComponentHooks, HookKind};
+use crate::ecs::mem::sparse::SparseSet;
+use crate::ecs::query::CHECK_TICK_THRESHOLD;
+use crate::ecs::resource::{Resource, Resources};
+use crate::ecs::schedule::Access;
 
 pub struct ComponentStorage {
-    components: HashMap<TypeId, ContinuousBlob>,
-    entity_components: HashMap<EntityType, HashMap<TypeId, ComponentIdx, U64IdentityHasher>, U64IdentityHasher>,
+    entities: Entities,
+    locations: Vec<EntityLocation>,
+    archetypes: Vec<Archetype>,
+    archetype_index: HashMap<Vec<TypeId>, ArchetypeId>,
+    infos: HashMap<TypeId, ComponentInfo>,
+    sparse_sets: HashMap<TypeId, SparseSet>,
+    change_tick: AtomicU32,
+    last_tick_check: u32,
+    resources: Resources,
+    commands: Arc<CommandQueue>,
+    hooks: HashMap<TypeId, ComponentHooks>,
+    borrows: BorrowFlags,
 }
 
 impl ComponentStorage {
     pub fn new() -> Self {
+        let infos = HashMap::new();
+        let mut archetype_index = HashMap::new();
+        archetype_index.insert(Vec::new(), EMPTY_ARCHETYPE);
         Self {
-            components: HashMap::new(),
-            entity_components: HashMap::with_hasher(U64IdentityHasher::default()),
+            entities: Entities::new(),
+            locations: Vec::new(),
+            archetypes: vec![Archetype::new(EMPTY_ARCHETYPE, Vec::new(), &infos)],
+            archetype_index,
+            infos,
+            sparse_sets: HashMap::new(),
+            change_tick: AtomicU32::new(1),
+            last_tick_check: 1,
+            resources: Resources::new(),
+            commands: Arc::new(CommandQueue::new()),
+            hooks: HashMap::new(),
+            borrows: BorrowFlags::default(),
         }
     }
 
-    pub fn get_component<T: Sized + 'static>(&self, entity: EntityType) -> Option<&T> {
-        if let Some(map) = self.entity_components.get(&entity) {
-            if let Some(idx) = map.get(&TypeId::of::<T>()) {
-                if let Some(blob) = self.components.get(&TypeId::of::<T>()) {
-                    return blob.get(*idx as usize);
-                }
-            }
+    pub fn spawn(&mut self) -> EntityType {
+        self.assert_unborrowed();
+        let entity = self.entities.alloc();
+        let row = self.archetypes[EMPTY_ARCHETYPE].push_entity(entity);
+        let location = EntityLocation { archetype: EMPTY_ARCHETYPE, row };
+        let index = entity.index() as usize;
+        if index < self.locations.len() {
+            self.locations[index] = location;
+        } else {
+            self.locations.push(location);
         }
-        None
+        entity
     }
 
-    pub fn get_component_mut<T: Sized + 'static>(&mut self, entity: EntityType) -> Option<&mut T> {
-        if let Some(map) = self.entity_components.get_mut(&entity) {
-            if let Some(idx) = map.get_mut(&TypeId::of::<T>()) {
-                if let Some(blob) = self.components.get_mut(&TypeId::of::<T>()) {
-                    return blob.get_mut(*idx as usize);
-                }
+    pub fn is_alive(&self, entity: EntityType) -> bool {
+        self.entities.is_alive(entity)
+    }
+
+    pub fn entity_count(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn location(&self, entity: EntityType) -> Option<EntityLocation> {
+        if self.entities.is_alive(entity) {
+            Some(self.locations[entity.index() as usize])
+        } else {
+            None
+        }
+    }
+
+    /// The tick that changes made outside of systems are recorded with.
+    pub fn change_tick(&self) -> u32 {
+        self.change_tick.load(Ordering::Acquire)
+    }
+
+    /// Returns the current tick and advances it. Systems call this once per iteration, so that everything a system
+    /// changes can be told apart from what other systems changed before and after it.
+    pub fn next_tick(&self) -> u32 {
+        self.change_tick.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Clamps the added and changed ticks of every component once the change tick advanced by
+    /// [`CHECK_TICK_THRESHOLD`] since the last check, so ticks never get old enough to look new again after the change
+    /// tick wrapped around. Cheap when there is nothing to do, the world calls it every update.
+    pub(crate) fn check_ticks(&mut self) {
+        let tick = self.change_tick();
+        if tick.wrapping_sub(self.last_tick_check) < CHECK_TICK_THRESHOLD {
+            return;
+        }
+        for archetype in &mut self.archetypes {
+            for column in archetype.columns_mut() {
+                column.clamp_ticks(tick);
             }
         }
-        None
+        for set in self.sparse_sets.values_mut() {
+            set.column_mut().clamp_ticks(tick);
+        }
+        self.last_tick_check = tick;
     }
 
-    pub fn set_component<T: Sized + 'static>(&mut self, entity: EntityType, component: T) {
-        let blob = if let Some(blob) = self.components.get_mut(&TypeId::of::<T>()) {
-            blob
-        } else {
-            let blob = ContinuousBlob::new(Layout::for_value(&component));
-            self.components.insert(TypeId::of::<T>(), blob);
-            self.components.get_mut(&TypeId::of::<T>()).unwrap()
-        };
+    pub fn archetypes(&self) -> &[Archetype] {
+        &self.archetypes
+    }
 
-        if let Some(idx) = blob.push_next(component) {
-            let map = if let Some(map) = self.entity_components.get_mut(&entity) {
-                map
-            } else {
-                let map = HashMap::with_hasher(U64IdentityHasher::default());
-                self.entity_components.insert(entity, map);
-                self.entity_components.get_mut(&entity).unwrap()
-            };
+    pub fn component_info(&self, ty: TypeId) -> Option<&ComponentInfo> {
+        self.infos.get(&ty)
+    }
+
+    /// Chooses how `T` is stored. Has to be called before the first `T` is attached to any entity, returns false if
+    /// `T` is already in use with another storage type.
+    pub fn register_component<T: Component>(&mut self, storage: StorageType) -> bool {
+        let ty = TypeId::of::<T>();
+        if self.infos.contains_key(&ty) {
+            return self.storage_type(ty) == storage;
+        }
+        self.assert_unborrowed();
+        let info = ComponentInfo::of::<T>();
+        if storage == StorageType::SparseSet {
+            self.sparse_sets.insert(ty, SparseSet::new(&info));
+        }
+        self.infos.insert(ty, info);
+        self.borrows.register(ty);
+        true
+    }
 
-            map.insert(TypeId::of::<T>(), idx as ComponentIdx);
+    /// Lets the inspector format the components of type `T`.
+    pub fn register_debug<T: Component + Debug>(&mut self) {
+        let ty = TypeId::of::<T>();
+        if !self.infos.contains_key(&ty) {
+            self.register_component::<T>(T::STORAGE);
+        }
+        if let Some(info) = self.infos.get_mut(&ty) {
+            info.debug = Some(debug_fn::<T>);
         }
     }
 
-    generate_get_components!(15);
+    pub fn component_infos(&self) -> impl Iterator<Item = (TypeId, &ComponentInfo)> {
+        self.infos.iter().map(|(ty, info)| (*ty, info))
+    }
 
-    /*pub fn get_components1<C1: Sized + 'static>(&self) -> Option<Vec<(EntityType, &C1)>> {
-        let c1s = self.components.get(&TypeId::of::<C1>())?.get_all::<C1>();
+    /// The tick at which the component of type `ty` of `entity` was last changed.
+    pub fn changed_tick(&self, entity: EntityType, ty: TypeId) -> Option<u32> {
+        let location = self.location(entity)?;
+        match self.sparse_sets.get(&ty) {
+            Some(set) => set.column().changed_tick(set.index_of(entity)?),
+            None => self.archetypes[location.archetype].column(ty)?.changed_tick(location.row),
+        }
+    }
 
-        let mut out = vec![];
+    pub(crate) fn component_ptr(&self, entity: EntityType, ty: TypeId) -> Option<*const u8> {
+        let (column, row) = self.column_of(entity, ty)?;
+        column.blob().get_ptr(row).map(<*mut u8>::cast_const)
+    }
 
-        for (en, map) in self.entity_components.iter() {
-            if let Some(idx1) = map.get(&TypeId::of::<C1>()) {
-                if let Some(c1) = c1s.get(*idx1) {
-                    out.push((*en, *c1));
-                }
-            }
+    fn column_of(&self, entity: EntityType, ty: TypeId) -> Option<(&Column, usize)> {
+        let location = self.location(entity)?;
+        match self.sparse_sets.get(&ty) {
+            Some(set) => Some((set.column(), set.index_of(entity)?)),
+            None => Some((self.archetypes[location.archetype].column(ty)?, location.row)),
         }
+    }
 
-        Some(out)
+    pub(crate) fn component_name(&self, ty: TypeId) -> &'static str {
+        self.infos.get(&ty).map_or("<unregistered>", |info| info.name)
     }
 
-    pub fn get_components1_mut<C1: Sized + 'static>(&mut self) -> Option<Vec<(EntityType, &mut C1)>> {
-        let mut c1s = self.components.get_mut(&TypeId::of::<C1>())?.get_all_mut::<C1>();
+    pub fn storage_type(&self, ty: TypeId) -> StorageType {
+        if self.sparse_sets.contains_key(&ty) {
+            StorageType::SparseSet
+        } else {
+            StorageType::Table
+        }
+    }
 
-        let mut out = vec![];
+    pub fn sparse_set(&self, ty: TypeId) -> Option<&SparseSet> {
+        self.sparse_sets.get(&ty)
+    }
 
-        for (en, map) in self.entity_components.iter_mut() {
-            if let Some(idx1) = map.get_mut(&TypeId::of::<C1>()) {
-                if let Some(c1) = c1s.get_mut(*idx1) {
-                    unsafe { out.push((*en, Unsafe::cast_mut_static(*c1))); }
-                }
-            }
+    pub fn sparse_sets(&self) -> impl Iterator<Item = (TypeId, &SparseSet)> {
+        self.sparse_sets.iter().map(|(ty, set)| (*ty, set))
+    }
+
+    pub fn get_component<T: Component>(&self, entity: EntityType) -> Option<&T> {
+        let location = self.location(entity)?;
+        let ty = TypeId::of::<T>();
+        if let Some(set) = self.sparse_sets.get(&ty) {
+            return set.column().get(set.index_of(entity)?);
         }
+        self.archetypes[location.archetype].column(ty)?.get(location.row)
+    }
+
+    /// Returns the component `T` of `entity` and marks it as changed.
+    pub fn get_component_mut<T: Component>(&mut self, entity: EntityType) -> Option<&mut T> {
+        let location = self.location(entity)?;
+        let ty = TypeId::of::<T>();
+        let tick = self.change_tick.load(Ordering::Acquire);
+        let (column, row) = match self.sparse_sets.get_mut(&ty) {
+            Some(set) => {
+                let row = set.index_of(entity)?;
+                (set.column_mut(), row)
+            }
+            None => (self.archetypes[location.archetype].column_mut(ty)?, location.row),
+        };
+        column.set_changed(row, tick);
+        column.get_mut(row)
+    }
+
+    /// Like [`ComponentStorage::get_component`], but the component stays borrowed until the returned [`Ref`] is
+    /// dropped. Panics if `T` is borrowed mutably.
+    pub fn borrow_component<T: Component>(&self, entity: EntityType) -> Option<Ref<'_, T>> {
+        let ty = TypeId::of::<T>();
+        Ref::new(&self.borrows, ty, self.component_name(ty), || self.get_component::<T>(entity))
+    }
 
-        Some(out)
+    /// Like [`ComponentStorage::get_component_mut`], but only needs a shared reference, because `T` stays borrowed
+    /// mutably until the returned [`RefMut`] is dropped. Panics if `T` is borrowed at all.
+    pub fn borrow_component_mut<T: Component>(&self, entity: EntityType) -> Option<RefMut<'_, T>> {
+        let ty = TypeId::of::<T>();
+        RefMut::new(&self.borrows, ty, self.component_name(ty), || {
+            let (column, row) = self.column_of(entity, ty)?;
+            if row >= column.len() {
+                return None;
+            }
+            unsafe {
+                *column.changed_ptr().add(row) = self.change_tick();
+                Some(&mut *column.data_ptr().cast::<T>().add(row))
+            }
+        })
     }
 
-    pub fn get_components2<C1: Sized + 'static, C2: Sized + 'static>(&self) -> Option<Vec<(EntityType, &C1, &C2)>> {
-        let c1s = self.components.get(&TypeId::of::<C1>())?.get_all::<C1>();
-        let c2s = self.components.get(&TypeId::of::<C2>())?.get_all::<C2>();
+    /// Borrows the components `access` reads and writes until the returned guard is dropped. Panics if one of them
+    /// conflicts with a borrow that is still alive.
+    pub(crate) fn borrow(&self, access: &Access) -> Borrow<'_> {
+        Borrow::new(&self.borrows, access, |ty| self.component_name(ty))
+    }
 
-        let mut out = vec![];
+    /// Moving, adding or removing components may reallocate the columns that borrowed components live in.
+    fn assert_unborrowed(&self) {
+        assert!(!self.borrows.is_borrowed(), "Entities and components cannot be added or removed while components are borrowed, use Commands instead!");
+    }
 
-        for (en, map) in self.entity_components.iter() {
-            if let Some(idx1) = map.get(&TypeId::of::<C1>()) {
-                if let Some(idx2) = map.get(&TypeId::of::<C2>()) {
-                    if let Some(c1) = c1s.get(*idx1) {
-                        if let Some(c2) = c2s.get(*idx2) {
-                            out.push((*en, *c1, *c2));
-                        }
-                    }
-                }
+    pub fn has_component<T: Component>(&self, entity: EntityType) -> bool {
+        self.has_type(entity, TypeId::of::<T>())
+    }
+
+    pub fn has_type(&self, entity: EntityType, ty: TypeId) -> bool {
+        match self.sparse_sets.get(&ty) {
+            Some(set) => self.is_alive(entity) && set.contains(entity),
+            None => self.location(entity).is_some_and(|location| self.archetypes[location.archetype].has(ty)),
+        }
+    }
+
+    /// Sets the component `T` of `entity`. If the entity already has one, it is replaced in place, otherwise the
+    /// entity is moved into the archetype that additionally contains `T`, or added to the sparse set of `T`.
+    pub fn set_component<T: Component>(&mut self, entity: EntityType, component: T) {
+        let Some(location) = self.location(entity) else { return; };
+        let ty = TypeId::of::<T>();
+        assert!(!self.borrows.is_borrowed_any(ty), "Component {} cannot be replaced while it is borrowed!", self.component_name(ty));
+        if let Some(existing) = self.get_component_mut::<T>(entity) {
+            let existing: *mut T = existing;
+            self.run_hooks(HookKind::Replace, ty, entity, existing.cast());
+            unsafe { *existing = component; }
+            return;
+        }
+
+        self.assert_unborrowed();
+        if !self.infos.contains_key(&ty) {
+            self.register_component::<T>(T::STORAGE);
+        }
+        let tick = self.change_tick.load(Ordering::Acquire);
+        if let Some(set) = self.sparse_sets.get_mut(&ty) {
+            let row = set.insert(entity, component, tick);
+            if let Some(ptr) = set.column().blob().get_ptr(row) {
+                self.run_hooks(HookKind::Add, ty, entity, ptr);
             }
+            return;
         }
 
-        Some(out)
+        let target = self.add_target(location.archetype, ty);
+        let row = self.move_entity(entity, location, target, true);
+        if let Some(column) = self.archetypes[target].column_mut(ty) {
+            column.push(component, tick);
+            if let Some(ptr) = column.blob().get_ptr(row) {
+                self.run_hooks(HookKind::Add, ty, entity, ptr);
+            }
+        }
     }
 
-    pub fn get_components2_mut<C1: Sized + 'static, C2: Sized + 'static>(&mut self) -> Option<Vec<(EntityType, &mut C1, &mut C2)>> {
-        let mut c1s = self.components.get(&TypeId::of::<C1>())?.get_all::<C1>();
-        let mut c2s = self.components.get(&TypeId::of::<C2>())?.get_all::<C2>();
+    /// Removes the component `T` from `entity` and returns it. The entity is moved into the archetype without `T`, or
+    /// removed from the sparse set of `T`.
+    pub fn remove_component<T: Component>(&mut self, entity: EntityType) -> Option<T> {
+        let location = self.location(entity)?;
+        self.assert_unborrowed();
+        let ty = TypeId::of::<T>();
+        let ptr = match self.sparse_sets.get(&ty) {
+            Some(set) => set.column().blob().get_ptr(set.index_of(entity)?)?,
+            None => self.archetypes[location.archetype].column(ty)?.blob().get_ptr(location.row)?,
+        };
+        self.run_hooks(HookKind::Remove, ty, entity, ptr);
+        let component = unsafe { ptr.cast::<T>().read() };
+        if let Some(set) = self.sparse_sets.get_mut(&ty) {
+            unsafe { set.remove_forget(entity); }
+            return Some(component);
+        }
+        let target = self.remove_target(location.archetype, ty);
+        self.move_entity(entity, location, target, false);
+        Some(component)
+    }
 
-        let mut out = vec![];
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    pub fn resources_mut(&mut self) -> &mut Resources {
+        &mut self.resources
+    }
+
+    pub fn resource<R: Resource>(&self) -> Option<&R> {
+        self.resources.get::<R>()
+    }
+
+    pub fn resource_mut<R: Resource>(&mut self) -> Option<&mut R> {
+        self.resources.get_mut::<R>()
+    }
+
+    pub fn commands(&self) -> &Arc<CommandQueue> {
+        &self.commands
+    }
 
-        unsafe {
-            for (en, map) in self.entity_components.iter_mut() {
-                if let Some(idx1) = map.get(&TypeId::of::<C1>()) {
-                    if let Some(idx2) = map.get(&TypeId::of::<C2>()) {
-                        if let Some(c1) = c1s.get_mut(*idx1) {
-                            if let Some(c2) = c2s.get_mut(*idx2) {
-                                out.push((*en, (*c1 as *const C1 as *mut C1).as_mut().unwrap(), (*c2 as *const C2 as *mut C2).as_mut().unwrap()));
-                            }
+    /// Registers a hook that runs right after a `T` was attached to an entity that did not have one.
+    pub fn on_add<T: Component>(&mut self, hook: impl Fn(EntityType, &T, &Commands) + Send + Sync + 'static) {
+        self.hooks.entry(TypeId::of::<T>()).or_default().push(HookKind::Add, erase(hook));
+    }
+
+    /// Registers a hook that runs with the old value right before a `T` is overwritten by a new one.
+    pub fn on_replace<T: Component>(&mut self, hook: impl Fn(EntityType, &T, &Commands) + Send + Sync + 'static) {
+        self.hooks.entry(TypeId::of::<T>()).or_default().push(HookKind::Replace, erase(hook));
+    }
+
+    /// Registers a hook that runs right before a `T` is removed from an entity, including when the entity is
+    /// despawned.
+    pub fn on_remove<T: Component>(&mut self, hook: impl Fn(EntityType, &T, &Commands) + Send + Sync + 'static) {
+        self.hooks.entry(TypeId::of::<T>()).or_default().push(HookKind::Remove, erase(hook));
+    }
+
+    /// Like [`ComponentStorage::on_remove`], but the hook gets the resources instead of [`Commands`], for bookkeeping
+    /// that has to happen before anything else can observe the removal. Hooks only run during structural changes,
+    /// which need `&mut self`, so the hook may write resources with
+    /// [`Resources::get_unchecked_mut`].
+    pub(crate) fn on_remove_with_resources<T: Component>(&mut self, hook: impl Fn(EntityType, &T, &Resources) + Send + Sync + 'static) {
+        self.hooks.entry(TypeId::of::<T>()).or_default().push(HookKind::Remove, erase_with_resources(hook));
+    }
+
+    fn run_hooks(&self, kind: HookKind, ty: TypeId, entity: EntityType, component: *const u8) {
+        let Some(hooks) = self.hooks.get(&ty) else { return; };
+        let hooks = hooks.get(kind);
+        if hooks.is_empty() {
+            return;
+        }
+        let commands = Commands::from_queue(self.commands.clone());
+        for hook in hooks {
+            hook(entity, component, &commands, &self.resources);
+        }
+    }
+
+    /// Drops every component of `entity` and frees its handle. The row of the entity is filled by the last row of its
+    /// archetype, so the tables never contain holes.
+    pub fn remove_entity(&mut self, entity: EntityType) {
+        let Some(location) = self.location(entity) else { return; };
+        self.assert_unborrowed();
+        self.run_remove_hooks(entity, location);
+        for set in self.sparse_sets.values_mut() {
+            set.remove_drop(entity);
+        }
+        self.entities.free(entity);
+        if let Some(moved) = self.archetypes[location.archetype].remove_row(location.row) {
+            self.locations[moved.index() as usize].row = location.row;
+        }
+    }
+
+    /// Moves every component of `entity` into a new entity of `other` without dropping or cloning them, and frees the
+    /// handle of `entity`. Component types `other` does not know yet are registered with the storage type they have
+    /// here. Remove hooks run here before the move, add hooks run in `other` after it. Returns the new entity.
+    pub fn move_entity_to(&mut self, entity: EntityType, other: &mut ComponentStorage) -> Option<EntityType> {
+        let location = self.location(entity)?;
+        self.assert_unborrowed();
+        other.assert_unborrowed();
+        let mut types = self.archetypes[location.archetype].types().to_vec();
+        types.extend(self.sparse_sets.iter().filter(|(_, set)| set.contains(entity)).map(|(ty, _)| *ty));
+        for ty in &types {
+            if other.infos.contains_key(ty) {
+                continue;
+            }
+            let Some(info) = self.infos.get(ty).copied() else { continue; };
+            if self.storage_type(*ty) == StorageType::SparseSet {
+                other.sparse_sets.insert(*ty, SparseSet::new(&info));
+            }
+            other.infos.insert(*ty, info);
+            other.borrows.register(*ty);
+        }
+        self.run_remove_hooks(entity, location);
+
+        let moved = other.spawn();
+        let mut table: Vec<TypeId> = types.iter().filter(|ty| !other.sparse_sets.contains_key(*ty)).copied().collect();
+        table.sort();
+        let target = other.archetype_for(table);
+        if target != EMPTY_ARCHETYPE {
+            other.move_entity(moved, other.locations[moved.index() as usize], target, true);
+        }
+        let tick = other.change_tick.load(Ordering::Acquire);
+        for ty in &types {
+            let Some(src) = self.component_ptr(entity, *ty) else { continue; };
+            unsafe {
+                match other.sparse_sets.get_mut(ty) {
+                    Some(set) => {
+                        set.insert_raw(moved, src, tick);
+                    }
+                    None => {
+                        if let Some(column) = other.archetypes[target].column_mut(*ty) {
+                            column.push_raw(src, tick, tick);
                         }
                     }
                 }
             }
         }
 
-        Some(out)
-    }*/
-}
\ No newline at end of file
+        for set in self.sparse_sets.values_mut() {
+            unsafe { set.remove_forget(entity); }
+        }
+        self.entities.free(entity);
+        if let Some(swapped) = unsafe { self.archetypes[location.archetype].remove_row_forget(location.row) } {
+            self.locations[swapped.index() as usize].row = location.row;
+        }
+
+        for ty in &types {
+            if let Some(ptr) = other.component_ptr(moved, *ty) {
+                other.run_hooks(HookKind::Add, *ty, moved, ptr);
+            }
+        }
+        Some(moved)
+    }
+
+    fn run_remove_hooks(&self, entity: EntityType, location: EntityLocation) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let archetype = &self.archetypes[location.archetype];
+        for (ty, column) in archetype.types().iter().zip(archetype.columns()) {
+            if let Some(ptr) = column.blob().get_ptr(location.row) {
+                self.run_hooks(HookKind::Remove, *ty, entity, ptr);
+            }
+        }
+        for (ty, set) in &self.sparse_sets {
+            if let Some(ptr) = set.index_of(entity).and_then(|row| set.column().blob().get_ptr(row)) {
+                self.run_hooks(HookKind::Remove, *ty, entity, ptr);
+            }
+        }
+    }
+
+    fn add_target(&mut self, from: ArchetypeId, ty: TypeId) -> ArchetypeId {
+        if let Some(target) = self.archetypes[from].add_edge(ty) {
+            return target;
+        }
+        let mut types = self.archetypes[from].types().to_vec();
+        if let Err(pos) = types.binary_search(&ty) {
+            types.insert(pos, ty);
+        }
+        let target = self.archetype_for(types);
+        self.archetypes[from].set_add_edge(ty, target);
+        self.archetypes[target].set_remove_edge(ty, from);
+        target
+    }
+
+    fn remove_target(&mut self, from: ArchetypeId, ty: TypeId) -> ArchetypeId {
+        if let Some(target) = self.archetypes[from].remove_edge(ty) {
+            return target;
+        }
+        let mut types = self.archetypes[from].types().to_vec();
+        types.retain(|t| *t != ty);
+        let target = self.archetype_for(types);
+        self.archetypes[from].set_remove_edge(ty, target);
+        self.archetypes[target].set_add_edge(ty, from);
+        target
+    }
+
+    fn archetype_for(&mut self, types: Vec<TypeId>) -> ArchetypeId {
+        if let Some(id) = self.archetype_index.get(&types) {
+            return *id;
+        }
+        let id = self.archetypes.len();
+        self.archetypes.push(Archetype::new(id, types.clone(), &self.infos));
+        self.archetype_index.insert(types, id);
+        id
+    }
+
+    fn move_entity(&mut self, entity: EntityType, from: EntityLocation, to: ArchetypeId, drop_missing: bool) -> usize {
+        let (source, target) = pair_mut(&mut self.archetypes, from.archetype, to);
+        let (row, moved) = source.move_row(from.row, target, drop_missing);
+        if let Some(moved) = moved {
+            self.locations[moved.index() as usize].row = from.row;
+        }
+        self.locations[entity.index() as usize] = EntityLocation { archetype: to, row };
+        row
+    }
+}
+
+fn pair_mut<T>(slice: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    assert_ne!(a, b);
+    if a < b {
+        let (left, right) = slice.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = slice.split_at_mut(a);
+        (&mut right[0], &mut left[b])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::TypeId;
+    use std::sync::atomic::Ordering;
+    use crate::ecs::StorageType;
+    use crate::ecs::mem::archetype::EMPTY_ARCHETYPE;
+    use crate::ecs::mem::storage::ComponentStorage;
+    use crate::ecs::query::{CHECK_TICK_THRESHOLD, MAX_CHANGE_AGE};
+    use crate::ecs::testing::{Health, Mana, Name, Tracked};
+
+    #[test]
+    fn stale_handles_do_not_reach_the_entity_that_reuses_their_slot() {
+        let mut storage = ComponentStorage::new();
+        let stale = storage.spawn();
+        storage.set_component(stale, Health(1));
+        storage.remove_entity(stale);
+        let entity = storage.spawn();
+        storage.set_component(entity, Health(2));
+
+        assert_eq!(entity.index(), stale.index());
+        assert!(!storage.is_alive(stale));
+        assert!(storage.location(stale).is_none());
+        assert!(storage.get_component::<Health>(stale).is_none());
+        assert!(storage.get_component_mut::<Health>(stale).is_none());
+        storage.set_component(stale, Health(3));
+        storage.remove_entity(stale);
+        assert_eq!(storage.get_component::<Health>(entity), Some(&Health(2)));
+        assert_eq!(storage.entity_count(), 1);
+    }
+
+    #[test]
+    fn entities_with_the_same_components_share_an_archetype() {
+        let mut storage = ComponentStorage::new();
+        let first = storage.spawn();
+        let second = storage.spawn();
+        let third = storage.spawn();
+        assert_eq!(storage.location(first).map(|location| location.archetype), Some(EMPTY_ARCHETYPE));
+
+        storage.set_component(first, Health(1));
+        storage.set_component(first, Mana(10));
+        storage.set_component(second, Mana(20));
+        storage.set_component(second, Health(2));
+        storage.set_component(third, Health(3));
+        let archetype = storage.location(first).expect("Entity is dead!").archetype;
+        assert_eq!(storage.location(second).map(|location| location.archetype), Some(archetype));
+        assert_eq!(storage.archetypes()[archetype].entities(), [first, second]);
+        assert_eq!(storage.archetypes().iter().filter(|archetype| archetype.has(TypeId::of::<Health>())).count(), 2);
+        assert_eq!(storage.archetypes().len(), 4);
+
+        // Removing the first entity swaps the second one into its row.
+        storage.remove_entity(first);
+        assert_eq!(storage.archetypes()[archetype].entities(), [second]);
+        assert_eq!(storage.location(second).map(|location| location.row), Some(0));
+        assert_eq!(storage.get_component::<Health>(second), Some(&Health(2)));
+        assert_eq!(storage.get_component::<Mana>(second), Some(&Mana(20)));
+        assert_eq!(storage.get_component::<Health>(third), Some(&Health(3)));
+        assert!(!storage.has_component::<Mana>(third));
+    }
+
+    #[test]
+    fn replacing_a_component_keeps_the_entity_in_place() {
+        let mut storage = ComponentStorage::new();
+        let entity = storage.spawn();
+        storage.set_component(entity, Name::new("first"));
+        let location = storage.location(entity).map(|location| (location.archetype, location.row));
+        storage.set_component(entity, Name::new("second"));
+        assert_eq!(storage.location(entity).map(|location| (location.archetype, location.row)), location);
+        assert_eq!(storage.get_component::<Name>(entity), Some(&Name::new("second")));
+        storage.remove_entity(entity);
+        assert!(storage.archetypes().iter().all(|archetype| archetype.is_empty()));
+    }
+
+    #[test]
+    fn removing_a_component_moves_the_entity_back_without_dropping_it() {
+        let drops = Tracked::counter();
+        let mut storage = ComponentStorage::new();
+        let first = storage.spawn();
+        let second = storage.spawn();
+        for entity in [first, second] {
+            storage.set_component(entity, Health(entity.index()));
+            storage.set_component(entity, Tracked(drops.clone()));
+        }
+        let archetype = storage.location(first).expect("Entity is dead!").archetype;
+
+        let removed = storage.remove_component::<Tracked>(first).expect("Entity had no Tracked!");
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        drop(removed);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert!(storage.remove_component::<Tracked>(first).is_none());
+        assert_eq!(storage.archetypes()[archetype].entities(), [second]);
+        assert_eq!(storage.get_component::<Health>(first), Some(&Health(0)));
+        assert_eq!(storage.get_component::<Health>(second), Some(&Health(1)));
+
+        storage.set_component(first, Tracked(drops.clone()));
+        assert_eq!(storage.location(first).map(|location| location.archetype), Some(archetype));
+        storage.remove_entity(second);
+        assert!(storage.remove_component::<Health>(second).is_none());
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn sparse_components_leave_the_archetype_alone() {
+        let mut storage = ComponentStorage::new();
+        assert!(storage.register_component::<Mana>(StorageType::SparseSet));
+        let first = storage.spawn();
+        let second = storage.spawn();
+        storage.set_component(first, Health(1));
+        storage.set_component(second, Health(2));
+        let location = storage.location(first).map(|location| (location.archetype, location.row));
+
+        storage.set_component(first, Mana(10));
+        storage.set_component(second, Mana(20));
+        assert_eq!(storage.location(first).map(|location| (location.archetype, location.row)), location);
+        assert_eq!(storage.sparse_set(TypeId::of::<Mana>()).map(|set| set.entities().to_vec()), Some(vec![first, second]));
+
+        assert_eq!(storage.remove_component::<Mana>(first), Some(Mana(10)));
+        assert_eq!(storage.get_component::<Mana>(second), Some(&Mana(20)));
+        assert_eq!(storage.get_component::<Health>(first), Some(&Health(1)));
+        storage.remove_entity(second);
+        assert!(storage.sparse_set(TypeId::of::<Mana>()).is_some_and(|set| set.is_empty()));
+
+        assert!(storage.register_component::<Mana>(StorageType::SparseSet));
+        assert!(!storage.register_component::<Mana>(StorageType::Table));
+        assert!(!storage.register_component::<Health>(StorageType::SparseSet));
+    }
+
+    #[test]
+    fn check_ticks_clamps_old_ticks() {
+        let mut storage = ComponentStorage::new();
+        assert!(storage.register_component::<Mana>(StorageType::SparseSet));
+        let entity = storage.spawn();
+        storage.set_component(entity, Health(1));
+        storage.set_component(entity, Mana(1));
+        let changed = |storage: &ComponentStorage| {
+            (storage.changed_tick(entity, TypeId::of::<Health>()), storage.changed_tick(entity, TypeId::of::<Mana>()))
+        };
+
+        storage.change_tick.store(CHECK_TICK_THRESHOLD, Ordering::Release);
+        storage.check_ticks();
+        assert_eq!(changed(&storage), (Some(1), Some(1)));
+
+        let tick = 1 + CHECK_TICK_THRESHOLD.wrapping_mul(7);
+        storage.change_tick.store(tick, Ordering::Release);
+        storage.check_ticks();
+        let clamped = Some(tick.wrapping_sub(MAX_CHANGE_AGE));
+        assert_eq!(changed(&storage), (clamped, clamped));
+    }
+}
@@ -1,33 +1,135 @@
 #![feature(ptr_metadata)]
 
-use std::marker::PhantomData;
 use std::sync::Arc;
+use hashbrown::HashMap;
 use mvutils::unsafe_utils::DangerousCell;
-use mvutils::utils;
 use crate::ecs::mem::storage::ComponentStorage;
 use crate::ecs::world::World;
 
 mod mem;
+pub mod borrow;
+pub mod command;
+pub mod event;
+pub mod hierarchy;
+pub mod inspect;
+pub mod param;
+pub mod prefab;
+pub mod query;
+pub mod resource;
+pub mod save;
+pub mod schedule;
+pub mod spatial;
 pub mod system;
 pub mod entity;
 pub mod world;
+#[cfg(test)]
+mod testing;
+
+pub use mvengine_proc_macro::{system, Component};
 
 pub type EcsStorage = Arc<DangerousCell<ComponentStorage>>;
 
+/// Marker for types that can be attached to entities. Components have to be [`Send`] and [`Sync`], because
+/// scheduled systems access them from worker threads.
+///
+/// Every item has a default, `#[derive(Component)]` fills them in from the type and its `#[component(...)]`
+/// attribute:
+///
+/// ```ignore
+/// #[derive(Component, Debug)]
+/// #[component(storage = "sparse", name = "game::Selected", save, debug)]
+/// struct Selected { by: u32 }
+/// ```
+///
+/// # Migrating
+/// Entities, systems and queries used to accept any `'static` type as a component, now every component type needs
+/// this trait. Add `#[derive(Component)]`, or `impl Component for T {}` for types of other crates wrapped in a
+/// newtype. Types that are not `Send + Sync`, like those holding an `Rc` or a raw pointer, have to be made
+/// thread safe or kept in a behavior instead.
+pub trait Component: Sized + Send + Sync + 'static {
+    /// The storage used when the type was not registered with another one before its first use.
+    const STORAGE: StorageType = StorageType::Table;
+
+    /// The name shown in diagnostics and, when derived with `save`, the name the type is saved under.
+    fn name() -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// The names of the fields in declaration order. Fields of tuple structs are named by their index.
+    fn fields() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Registers the type with `world`, called by [`World::register`].
+    fn register(world: &mut World) {
+        world.register_component::<Self>(Self::STORAGE);
+    }
+}
+
+/// How the components of one type are stored, chosen with
+/// [`World::register_component`](crate::ecs::world::World::register_component).
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug)]
+pub enum StorageType {
+    /// Components are stored in the archetype tables, which makes iterating them fast. Adding or removing one moves
+    /// the whole entity into another table.
+    #[default]
+    Table,
+    /// Components are stored in a sparse set next to the tables, which makes adding and removing them cheap. Meant
+    /// for tag-like components that are toggled often.
+    SparseSet,
+}
+
+/// The main world along with any number of named worlds, like a loading world or an editor preview. Every world has
+/// its own storage, entities can be moved between them with [`World::move_entity_to`].
 pub struct ECS {
     pub(crate) storage: EcsStorage,
-    world: World
+    world: World,
+    worlds: HashMap<String, World>,
 }
 
 impl ECS {
     pub fn new() -> Self {
-        let st = Arc::new(DangerousCell::new(ComponentStorage::new()));
+        let world = World::new();
         Self {
-            storage: st.clone(),
-            world: World::new(st),
+            storage: world.storage(),
+            world,
+            worlds: HashMap::new(),
         }
     }
 
+    /// Creates an empty world called `name`, replacing the world with the same name.
+    pub fn create_world(&mut self, name: &str) -> &mut World {
+        self.insert_world(name, World::new())
+    }
+
+    /// Adds a world that was created elsewhere, e.g. on a loading thread, replacing the world with the same name.
+    pub fn insert_world(&mut self, name: &str, world: World) -> &mut World {
+        self.worlds.insert(name.to_string(), world);
+        self.worlds.get_mut(name).expect("World was just inserted!")
+    }
+
+    pub fn remove_world(&mut self, name: &str) -> Option<World> {
+        self.worlds.remove(name)
+    }
+
+    pub fn get_world(&self, name: &str) -> Option<&World> {
+        self.worlds.get(name)
+    }
+
+    pub fn get_world_mut(&mut self, name: &str) -> Option<&mut World> {
+        self.worlds.get_mut(name)
+    }
+
+    /// Borrows the main world and the world called `name` at the same time, e.g. to move entities between them.
+    pub fn split(&mut self, name: &str) -> Option<(&mut World, &mut World)> {
+        let other = self.worlds.get_mut(name)?;
+        Some((&mut self.world, other))
+    }
+
+    pub fn world_names(&self) -> impl Iterator<Item = &str> {
+        self.worlds.keys().map(String::as_str)
+    }
+
     pub fn storage(&self) -> EcsStorage {
         self.storage.clone()
     }
@@ -0,0 +1,406 @@
+use std::any::{type_name, TypeId};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use crate::ecs::EcsStorage;
+use crate::ecs::borrow::Borrow;
+use crate::ecs::command::Commands;
+use crate::ecs::event::{EventReader, EventWriter, Events};
+use crate::ecs::mem::storage::ComponentStorage;
+use crate::ecs::query::{clamp_tick, Filter, QueryData};
+use crate::ecs::resource::Resource;
+use crate::ecs::schedule::{Access, ScheduledSystem};
+use crate::ecs::system::Cursor;
+
+/// A parameter of a function annotated with [`#[system]`](macro@crate::ecs::system), like [`Query`], [`Res`],
+/// [`ResMut`], [`Commands`], [`EventWriter`] or `&mut EventReader`.
+pub trait SystemParam: Sized {
+    /// Data kept between two runs of the system, like the tick a query last ran at.
+    type State;
+
+    fn init(storage: &EcsStorage) -> Self::State;
+
+    fn access(access: &mut Access);
+
+    /// # Safety
+    /// The returned parameter must not outlive `storage`, and nothing else may access what [`SystemParam::access`]
+    /// declares as written while it is alive.
+    unsafe fn get(state: &mut Self::State, storage: &EcsStorage) -> Self;
+}
+
+/// Extends the lifetime of the storage to the one a parameter asks for. Parameters only ever get shared access to the
+/// storage, components are written through the column pointers of a [`Cursor`] and resources through
+/// [`Resources::get_unchecked_mut`](crate::ecs::resource::Resources::get_unchecked_mut), both of which are backed by
+/// interior mutability, so systems on different threads never hold a `&mut ComponentStorage`.
+unsafe fn storage<'w>(storage: &EcsStorage) -> &'w ComponentStorage {
+    &*std::ptr::from_ref(storage.get())
+}
+
+/// Iterates all entities that have the components in `D` and pass the filters `F`, e.g.
+/// `Query<(&mut Transform, &Velocity, Option<&Sprite>), Without<Frozen>>`. Unlike a
+/// [`System`](crate::ecs::system::System), every component chooses whether it is read or written.
+///
+/// [`Added`](crate::ecs::query::Added) and [`Changed`](crate::ecs::query::Changed) are relative to the previous run
+/// of the system.
+pub struct Query<'w, D: QueryData, F: Filter = ()> {
+    phantom: PhantomData<(D, F)>,
+    storage: &'w ComponentStorage,
+    last_run: u32,
+    this_run: u32,
+}
+
+impl<'w, D: QueryData, F: Filter> Query<'w, D, F> {
+    /// Iterates with every component borrowed immutably.
+    pub fn iter(&self) -> QueryIter<'_, D, F> {
+        let mut access = Access::default();
+        let mut written = Access::default();
+        D::access(&mut written);
+        F::access(&mut access);
+        written.component_reads().iter().chain(written.component_writes()).for_each(|ty| access.read_component(*ty));
+        QueryIter {
+            _borrow: self.storage.borrow(&access),
+            cursor: Cursor::new(self.storage, self.last_run, self.this_run),
+        }
+    }
+
+    /// Iterates with the components borrowed as written in `D`. Every mutably borrowed component is marked as
+    /// changed.
+    pub fn iter_mut(&mut self) -> QueryIterMut<'_, D, F> {
+        let mut access = Access::default();
+        <Self as SystemParam>::access(&mut access);
+        QueryIterMut {
+            _borrow: self.storage.borrow(&access),
+            cursor: Cursor::new(self.storage, self.last_run, self.this_run),
+        }
+    }
+}
+
+impl<D: QueryData, F: Filter> SystemParam for Query<'_, D, F> {
+    type State = u32;
+
+    fn init(_: &EcsStorage) -> Self::State {
+        0
+    }
+
+    fn access(access: &mut Access) {
+        D::access(access);
+        F::access(access);
+    }
+
+    unsafe fn get(state: &mut Self::State, storage: &EcsStorage) -> Self {
+        let storage = self::storage(storage);
+        let this_run = storage.next_tick();
+        Self {
+            phantom: PhantomData,
+            storage,
+            last_run: clamp_tick(std::mem::replace(state, this_run), this_run),
+            this_run,
+        }
+    }
+}
+
+/// Items borrow the [`Query`] itself. Once the iterator is dropped they can only be used alongside the other
+/// parameters of the system, which were checked to be compatible.
+pub struct QueryIter<'a, D: QueryData, F: Filter> {
+    cursor: Cursor<'a, D::Query, F>,
+    _borrow: Borrow<'a>,
+}
+
+pub struct QueryIterMut<'a, D: QueryData, F: Filter> {
+    cursor: Cursor<'a, D::Query, F>,
+    _borrow: Borrow<'a>,
+}
+
+impl<'a, D: QueryData, F: Filter> Iterator for QueryIter<'a, D, F> {
+    type Item = D::ReadItem<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (entity, columns, row) = self.cursor.next_row()?;
+            if let Some(item) = unsafe { D::fetch_read(entity, columns, row) } {
+                return Some(item);
+            }
+        }
+    }
+}
+
+impl<'a, D: QueryData, F: Filter> Iterator for QueryIterMut<'a, D, F> {
+    type Item = D::Item<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (entity, columns, row) = self.cursor.next_row()?;
+            if let Some(item) = unsafe { D::fetch(entity, columns, row, self.cursor.this_run) } {
+                return Some(item);
+            }
+        }
+    }
+}
+
+/// Reads the resource `R`. The system panics if it was not inserted, use `Option<Res<R>>` for optional resources.
+pub struct Res<'w, R: Resource> {
+    resource: &'w R,
+}
+
+impl<R: Resource> Deref for Res<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.resource
+    }
+}
+
+/// Writes the resource `R`. The system panics if it was not inserted, use `Option<ResMut<R>>` for optional
+/// resources.
+pub struct ResMut<'w, R: Resource> {
+    resource: &'w mut R,
+}
+
+impl<R: Resource> Deref for ResMut<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.resource
+    }
+}
+
+impl<R: Resource> DerefMut for ResMut<'_, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.resource
+    }
+}
+
+impl<R: Resource> SystemParam for Option<Res<'_, R>> {
+    type State = ();
+
+    fn init(_: &EcsStorage) -> Self::State {}
+
+    fn access(access: &mut Access) {
+        access.read_resource(TypeId::of::<R>());
+    }
+
+    unsafe fn get(_: &mut Self::State, storage: &EcsStorage) -> Self {
+        self::storage(storage).resource::<R>().map(|resource| Res { resource })
+    }
+}
+
+impl<R: Resource> SystemParam for Res<'_, R> {
+    type State = ();
+
+    fn init(_: &EcsStorage) -> Self::State {}
+
+    fn access(access: &mut Access) {
+        Option::<Self>::access(access);
+    }
+
+    unsafe fn get(state: &mut Self::State, storage: &EcsStorage) -> Self {
+        Option::<Self>::get(state, storage).unwrap_or_else(|| panic!("Resource {} of a system was not inserted!", type_name::<R>()))
+    }
+}
+
+impl<R: Resource> SystemParam for Option<ResMut<'_, R>> {
+    type State = ();
+
+    fn init(_: &EcsStorage) -> Self::State {}
+
+    fn access(access: &mut Access) {
+        access.write_resource(TypeId::of::<R>());
+    }
+
+    unsafe fn get(_: &mut Self::State, storage: &EcsStorage) -> Self {
+        self::storage(storage).resources().get_unchecked_mut::<R>().map(|resource| ResMut { resource })
+    }
+}
+
+impl<R: Resource> SystemParam for ResMut<'_, R> {
+    type State = ();
+
+    fn init(_: &EcsStorage) -> Self::State {}
+
+    fn access(access: &mut Access) {
+        Option::<Self>::access(access);
+    }
+
+    unsafe fn get(state: &mut Self::State, storage: &EcsStorage) -> Self {
+        Option::<Self>::get(state, storage).unwrap_or_else(|| panic!("Resource {} of a system was not inserted!", type_name::<R>()))
+    }
+}
+
+impl SystemParam for Commands {
+    type State = Commands;
+
+    fn init(storage: &EcsStorage) -> Self::State {
+        Commands::new(storage.clone())
+    }
+
+    fn access(_: &mut Access) {}
+
+    unsafe fn get(state: &mut Self::State, _: &EcsStorage) -> Self {
+        state.clone()
+    }
+}
+
+/// The reader lives as long as the system, so it sees every event once. Read it with `Res<Events<E>>`.
+impl<E: Resource> SystemParam for &mut EventReader<E> {
+    type State = EventReader<E>;
+
+    fn init(_: &EcsStorage) -> Self::State {
+        EventReader::default()
+    }
+
+    fn access(access: &mut Access) {
+        access.read_resource(TypeId::of::<Events<E>>());
+    }
+
+    unsafe fn get(state: &mut Self::State, _: &EcsStorage) -> Self {
+        &mut *std::ptr::from_mut(state)
+    }
+}
+
+impl<E: Resource> SystemParam for EventWriter<E> {
+    type State = ();
+
+    fn init(_: &EcsStorage) -> Self::State {}
+
+    fn access(access: &mut Access) {
+        access.write_resource(TypeId::of::<Events<E>>());
+    }
+
+    unsafe fn get(_: &mut Self::State, storage: &EcsStorage) -> Self {
+        EventWriter::new(storage.clone())
+    }
+}
+
+/// The [`ScheduledSystem`] generated by [`#[system]`](macro@crate::ecs::system). `state` holds the
+/// [`SystemParam::State`] of every parameter and `func` fetches the parameters and calls the annotated function.
+#[doc(hidden)]
+pub struct ParamSystem<S, Func> {
+    name: &'static str,
+    storage: EcsStorage,
+    access: Access,
+    state: S,
+    func: Func,
+}
+
+impl<S, Func: FnMut(&mut S, &EcsStorage)> ParamSystem<S, Func> {
+    /// Panics if two of the parameter `accesses` conflict, like `Query<&mut A>` and `Query<&A>` would.
+    pub fn new(name: &'static str, storage: EcsStorage, accesses: &[Access], state: S, func: Func) -> Self {
+        let mut access = Access::default();
+        for (i, param) in accesses.iter().enumerate() {
+            if accesses[..i].iter().any(|other| !param.is_compatible(other)) {
+                panic!("Parameter {} of system {name} conflicts with an earlier parameter!", i + 1);
+            }
+            access.extend(param);
+        }
+        Self { name, storage, access, state, func }
+    }
+}
+
+impl<S, Func: FnMut(&mut S, &EcsStorage)> ScheduledSystem for ParamSystem<S, Func> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn access(&self) -> Access {
+        self.access.clone()
+    }
+
+    fn run(&mut self) {
+        (self.func)(&mut self.state, &self.storage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::TypeId;
+    use crate::ecs::{system, Component, StorageType};
+    use crate::ecs::command::Commands;
+    use crate::ecs::entity::{Entity, EntityType, NoBehavior};
+    use crate::ecs::param::{Query, Res, ResMut};
+    use crate::ecs::query::Without;
+    use crate::ecs::schedule::Stage;
+    use crate::ecs::world::World;
+
+    #[derive(Component, Default, Debug, PartialEq)]
+    struct Position(i32);
+
+    #[derive(Component, Default, Debug)]
+    #[component(storage = "sparse", name = "test::Velocity", debug)]
+    struct Velocity {
+        x: i32,
+    }
+
+    #[derive(Component, Default)]
+    struct Frozen;
+
+    /// How often [`step`] ran.
+    #[derive(Default)]
+    struct Steps(u32);
+
+    /// Added to every velocity if inserted.
+    struct Gravity(i32);
+
+    #[system]
+    fn step(mut moving: Query<(&mut Position, &Velocity), Without<Frozen>>, mut steps: ResMut<Steps>, gravity: Option<Res<Gravity>>) {
+        let gravity = gravity.map_or(0, |gravity| gravity.0);
+        for (_, position, velocity) in moving.iter_mut() {
+            position.0 += velocity.x + gravity;
+        }
+        steps.0 += 1;
+    }
+
+    #[system]
+    fn spawn_frozen(steps: Res<Steps>, commands: Commands) {
+        if steps.0 == 1 {
+            commands.spawn(Entity::<NoBehavior, (Position, Frozen)>::new);
+        }
+    }
+
+    #[system]
+    fn conflicting(_: Query<(&mut Position,)>, _: Query<(&Position, &Velocity)>) {}
+
+    fn position(world: &World, entity: EntityType) -> Option<i32> {
+        world.storage().get().get_component::<Position>(entity).map(|position| position.0)
+    }
+
+    #[test]
+    fn derived_components_fill_in_their_attributes() {
+        let mut world = World::new();
+        world.register::<Velocity>();
+        assert_eq!(world.storage().get().storage_type(TypeId::of::<Velocity>()), StorageType::SparseSet);
+        assert_eq!(world.storage().get().storage_type(TypeId::of::<Position>()), StorageType::Table);
+        assert_eq!((Velocity::name(), Velocity::fields()), ("test::Velocity", &["x"][..]));
+        assert_eq!(Position::fields(), ["0"]);
+
+        let entity = world.create_entity(Entity::<NoBehavior, (Position, Velocity)>::new).expect("Entity could not be created");
+        let view = world.inspect(entity).expect("Entity is not alive").to_string();
+        assert!(view.contains("Velocity { x: 0 }"), "{view}");
+        assert!(!view.contains("Position(0)"), "{view}");
+    }
+
+    #[test]
+    fn systems_get_their_parameters() {
+        let mut world = World::new();
+        world.insert_resource(Steps::default());
+        world.add_system(Stage::Update, step);
+        world.add_system(Stage::PostUpdate, spawn_frozen);
+        let moving = world.create_entity(Entity::<NoBehavior, (Position, Velocity)>::new).expect("Entity could not be created");
+        world.insert_component(moving, Velocity { x: 2 });
+
+        world.update();
+        assert_eq!(position(&world, moving), Some(2));
+        let frozen = world.entities().into_iter().find(|entity| *entity != moving).expect("Entity was not spawned");
+        world.insert_component(frozen, Velocity { x: 5 });
+
+        world.insert_resource(Gravity(-1));
+        world.update();
+        assert_eq!((position(&world, moving), position(&world, frozen)), (Some(3), Some(0)));
+        assert_eq!(world.resource::<Steps>().map(|steps| steps.0), Some(2));
+        assert_eq!(world.entities().len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "conflicts with an earlier parameter")]
+    fn conflicting_parameters_panic() {
+        World::new().add_system(Stage::Update, conflicting);
+    }
+}
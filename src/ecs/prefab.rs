@@ -0,0 +1,254 @@
+use std::str::FromStr;
+use hashbrown::HashMap;
+use ui_parsing::xml::{parse_rsx, Entity, XmlValue};
+use crate::ecs::Component;
+use crate::ecs::entity::EntityType;
+use crate::ecs::mem::storage::ComponentStorage;
+
+/// One component element of a prefab, e.g. `<Health value="20"/>`, after inheritance was resolved.
+#[derive(Clone, Default, Debug)]
+pub struct PrefabComponent {
+    attributes: HashMap<String, String>,
+    text: Option<String>,
+}
+
+impl PrefabComponent {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(String::as_str)
+    }
+
+    pub fn attributes(&self) -> &HashMap<String, String> {
+        &self.attributes
+    }
+
+    /// The text between the opening and closing tag, e.g. `Bob` in `<Name>Bob</Name>`.
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    /// Parses the attribute `name`, failing if it is missing or malformed.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, String> {
+        let value = self.attribute(name).ok_or(format!("Missing attribute {name}"))?;
+        value.parse().map_err(|_| format!("Invalid value {value} for attribute {name}"))
+    }
+
+    /// Parses the attribute `name`, returning `default` if it is missing.
+    pub fn parse_or<T: FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        if self.attributes.contains_key(name) {
+            self.parse(name)
+        } else {
+            Ok(default)
+        }
+    }
+
+    fn merge(&mut self, other: &PrefabComponent) {
+        self.attributes.extend(other.attributes.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if other.text.is_some() {
+            self.text.clone_from(&other.text);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PrefabDef {
+    extends: Option<String>,
+    components: Vec<(String, PrefabComponent)>,
+}
+
+type Insert = Box<dyn FnOnce(&mut ComponentStorage, EntityType)>;
+type Deserializer = Box<dyn Fn(&PrefabComponent) -> Result<Insert, String> + Send + Sync>;
+
+/// Entity templates loaded from XML, like
+///
+/// ```xml
+/// <prefabs>
+///     <entity name="goblin"><Health value="20"/><Sprite tex="goblin"/></entity>
+///     <entity name="goblin_chief" extends="goblin"><Health value="50"/></entity>
+/// </prefabs>
+/// ```
+///
+/// Every component element is turned into a component by the deserializer registered for its tag. A prefab that
+/// extends another one starts out with all of its components, its own elements add components or override single
+/// attributes of inherited ones.
+#[derive(Default)]
+pub struct PrefabRegistry {
+    deserializers: HashMap<String, Deserializer>,
+    prefabs: HashMap<String, PrefabDef>,
+}
+
+impl PrefabRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the deserializer for elements with the tag `tag`.
+    pub fn register<T: Component>(&mut self, tag: &str, deserializer: fn(&PrefabComponent) -> Result<T, String>) {
+        self.deserializers.insert(tag.to_string(), Box::new(move |component| {
+            let component = deserializer(component)?;
+            Ok(Box::new(move |storage: &mut ComponentStorage, entity| storage.set_component(entity, component)) as Insert)
+        }));
+    }
+
+    /// Parses `xml`, which is either a single `<entity>` or any root element containing `<entity>` elements, and adds
+    /// every prefab in it, replacing prefabs with the same name. Returns the names of the loaded prefabs.
+    pub fn load(&mut self, xml: &str) -> Result<Vec<String>, String> {
+        let root = parse_rsx(xml.trim().to_string())?;
+        let defs: Vec<&Entity> = if root.name() == "entity" {
+            vec![&root]
+        } else {
+            match root.inner() {
+                Some(XmlValue::Entities(entities)) => entities.iter().filter(|e| e.name() == "entity").collect(),
+                _ => Vec::new(),
+            }
+        };
+
+        let mut names = Vec::with_capacity(defs.len());
+        for def in defs {
+            let name = string_attribute(def, "name").ok_or("Prefab is missing its name attribute")?;
+            let components = match def.inner() {
+                Some(XmlValue::Entities(elements)) => elements.iter().map(|element| (element.name(), to_component(element))).collect(),
+                _ => Vec::new(),
+            };
+            self.prefabs.insert(name.clone(), PrefabDef {
+                extends: string_attribute(def, "extends"),
+                components,
+            });
+            names.push(name);
+        }
+        Ok(names)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.prefabs.contains_key(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.prefabs.remove(name).is_some()
+    }
+
+    /// Returns the components of `name` with all inherited components and overrides applied, in declaration order.
+    pub fn resolve(&self, name: &str) -> Result<Vec<(String, PrefabComponent)>, String> {
+        let mut chain = Vec::new();
+        let mut current = Some(name);
+        while let Some(name) = current {
+            if chain.iter().any(|(n, _)| *n == name) {
+                return Err(format!("Prefab {name} inherits from itself"));
+            }
+            let def = self.prefabs.get(name).ok_or(format!("Unknown prefab {name}"))?;
+            chain.push((name, def));
+            current = def.extends.as_deref();
+        }
+
+        let mut components: Vec<(String, PrefabComponent)> = Vec::new();
+        for (_, def) in chain.into_iter().rev() {
+            for (tag, component) in &def.components {
+                match components.iter_mut().find(|(t, _)| t == tag) {
+                    Some((_, existing)) => existing.merge(component),
+                    None => components.push((tag.clone(), component.clone())),
+                }
+            }
+        }
+        Ok(components)
+    }
+
+    /// Deserializes every component of `name` and attaches them to a new entity.
+    pub(crate) fn spawn(&self, storage: &mut ComponentStorage, name: &str) -> Result<EntityType, String> {
+        let inserts = self.resolve(name)?.iter().map(|(tag, component)| {
+            let deserializer = self.deserializers.get(tag).ok_or(format!("Unknown prefab component {tag}"))?;
+            deserializer(component).map_err(|e| format!("Failed to deserialize {tag} of prefab {name}: {e}"))
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        let entity = storage.spawn();
+        for insert in inserts {
+            insert(storage, entity);
+        }
+        Ok(entity)
+    }
+}
+
+fn string_attribute(entity: &Entity, name: &str) -> Option<String> {
+    match entity.get_attrib(name)? {
+        XmlValue::Str(s) | XmlValue::Code(s) => Some(s.clone()),
+        XmlValue::Entities(_) => None,
+    }
+}
+
+fn to_component(element: &Entity) -> PrefabComponent {
+    PrefabComponent {
+        attributes: element.attributes().iter().filter_map(|a| match a.value() {
+            XmlValue::Str(s) | XmlValue::Code(s) => Some((a.name(), s.clone())),
+            XmlValue::Entities(_) => None,
+        }).collect(),
+        text: match element.inner() {
+            Some(XmlValue::Str(s)) => Some(s.trim().to_string()),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::ECS;
+    use crate::ecs::prefab::PrefabComponent;
+    use crate::ecs::testing::{Health, Mana, Name};
+    use crate::ecs::world::World;
+
+    /// `<Name title="Sir">Bob</Name>` becomes `Sir Bob`.
+    fn name(component: &PrefabComponent) -> Result<Name, String> {
+        let text = component.text().ok_or("Missing name")?;
+        Ok(match component.attribute("title") {
+            Some(title) => Name(format!("{title} {text}")),
+            None => Name::new(text),
+        })
+    }
+
+    fn register(world: &mut World) {
+        world.register_prefab_component("Health", |component| component.parse("value").map(Health));
+        world.register_prefab_component("Mana", |component| component.parse_or("value", 5).map(Mana));
+        world.register_prefab_component("Name", name);
+    }
+
+    #[test]
+    fn prefabs_inherit_and_override_components() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        register(world);
+        let loaded = world.load_prefabs(r#"
+            <prefabs>
+                <entity name="goblin"><Health value="20"/><Mana/><Name title="Sneaky">Goblin</Name></entity>
+                <entity name="goblin_chief" extends="goblin"><Health value="50"/><Name title="Chief"/></entity>
+            </prefabs>
+        "#).expect("Prefabs were not loaded");
+        assert_eq!(loaded, ["goblin", "goblin_chief"]);
+
+        let goblin = world.spawn_prefab("goblin").expect("Prefab was not spawned");
+        let chief = world.spawn_prefab("goblin_chief").expect("Prefab was not spawned");
+        assert_eq!(world.remove_component::<Health>(goblin), Some(Health(20)));
+        assert_eq!(world.remove_component::<Name>(goblin), Some(Name::new("Sneaky Goblin")));
+        assert_eq!(world.remove_component::<Health>(chief), Some(Health(50)));
+        assert_eq!(world.remove_component::<Mana>(chief), Some(Mana(5)));
+        assert_eq!(world.remove_component::<Name>(chief), Some(Name::new("Chief Goblin")));
+    }
+
+    #[test]
+    fn broken_prefabs_spawn_nothing() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        register(world);
+        world.load_prefabs(r#"
+            <prefabs>
+                <entity name="a" extends="b"><Health value="1"/></entity>
+                <entity name="b" extends="a"/>
+                <entity name="broken"><Health value="lots"/></entity>
+                <entity name="orphan" extends="missing"/>
+                <entity name="strange"><Stamina value="1"/></entity>
+            </prefabs>
+        "#).expect("Prefabs were not loaded");
+
+        assert!(world.prefabs().resolve("a").expect_err("Cycle was not detected").contains("inherits from itself"));
+        for name in ["a", "broken", "orphan", "strange", "unknown"] {
+            assert!(world.spawn_prefab(name).is_err(), "{name} was spawned");
+        }
+        assert_eq!(ecs.storage().get().entity_count(), 0);
+    }
+}
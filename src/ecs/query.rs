@@ -0,0 +1,752 @@
+use std::any::TypeId;
+use std::marker::PhantomData;
+use crate::ecs::{Component, StorageType};
+use crate::ecs::entity::EntityType;
+use crate::ecs::mem::archetype::{Archetype, Column};
+use crate::ecs::mem::sparse::SparseSet;
+use crate::ecs::mem::storage::ComponentStorage;
+use crate::ecs::schedule::Access;
+
+/// Raw pointers into one column of an archetype or a sparse set.
+pub struct ColumnPtr<T> {
+    data: *mut T,
+    added: *mut u32,
+    changed: *mut u32,
+    sparse: Option<*const SparseSet>,
+}
+
+impl<T> Clone for ColumnPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ColumnPtr<T> {}
+
+impl<T> ColumnPtr<T> {
+    fn new(column: &Column, sparse: Option<&SparseSet>) -> Self {
+        Self {
+            data: column.data_ptr().cast(),
+            added: column.added_ptr(),
+            changed: column.changed_ptr(),
+            sparse: sparse.map(|set| set as *const SparseSet),
+        }
+    }
+
+    /// The index of the component of `entity`, which is in `row` of the current archetype.
+    ///
+    /// # Safety
+    /// The sparse set this column was created from has to be alive.
+    unsafe fn slot(&self, entity: EntityType, row: usize) -> Option<usize> {
+        match self.sparse {
+            Some(set) => (*set).index_of(entity),
+            None => Some(row),
+        }
+    }
+}
+
+fn column_ptr<T: 'static>(archetype: &Archetype, storage: &ComponentStorage) -> Option<ColumnPtr<T>> {
+    let ty = TypeId::of::<T>();
+    match storage.sparse_set(ty) {
+        Some(set) => Some(ColumnPtr::new(set.column(), Some(set))),
+        None => archetype.column(ty).map(|column| ColumnPtr::new(column, None)),
+    }
+}
+
+fn may_contain<T: 'static>(archetype: &Archetype, storage: &ComponentStorage) -> bool {
+    let ty = TypeId::of::<T>();
+    archetype.has(ty) || storage.storage_type(ty) == StorageType::SparseSet
+}
+
+/// One element of the component tuple of a [`System`](crate::ecs::system::System).
+///
+/// Every [`Component`] `T` is fetched as `&T`, or as `&mut T` when iterating mutably. `Option<T>` matches entities
+/// with and without `T` and is fetched as `Option<&T>` or `Option<&mut T>`.
+pub trait Fetch: 'static {
+    type Item<'a>;
+    type ItemMut<'a>;
+    type Column: Copy;
+    type Slot: Copy;
+
+    /// Records the components this element reads, or writes if it is fetched `mutable`.
+    fn access(access: &mut Access, mutable: bool);
+
+    /// Whether entities of `archetype` can match. Components in sparse sets are only checked per entity.
+    fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool;
+
+    /// # Safety
+    /// `archetype` has to match this fetch.
+    unsafe fn column(archetype: &Archetype, storage: &ComponentStorage) -> Self::Column;
+
+    /// Locates the component of `entity` in `row`, or returns `None` if the entity does not match.
+    ///
+    /// # Safety
+    /// `column` has to come from an archetype that has at least `row + 1` rows and outlives the call.
+    unsafe fn slot(column: Self::Column, entity: EntityType, row: usize) -> Option<Self::Slot>;
+
+    /// # Safety
+    /// `slot` has to come from [`Fetch::slot`] on the same column, which has to outlive `'a`.
+    unsafe fn fetch<'a>(column: Self::Column, slot: Self::Slot) -> Self::Item<'a>;
+
+    /// # Safety
+    /// Same as [`Fetch::fetch`], and no other reference to the component in `slot` may exist. Marks the component
+    /// as changed at `tick`.
+    unsafe fn fetch_mut<'a>(column: Self::Column, slot: Self::Slot, tick: u32) -> Self::ItemMut<'a>;
+}
+
+impl<T: Component> Fetch for T {
+    type Item<'a> = &'a T;
+    type ItemMut<'a> = &'a mut T;
+    type Column = ColumnPtr<T>;
+    type Slot = usize;
+
+    fn access(access: &mut Access, mutable: bool) {
+        if mutable {
+            access.write_component(TypeId::of::<T>());
+        } else {
+            access.read_component(TypeId::of::<T>());
+        }
+    }
+
+    fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool {
+        may_contain::<T>(archetype, storage)
+    }
+
+    unsafe fn column(archetype: &Archetype, storage: &ComponentStorage) -> Self::Column {
+        column_ptr::<T>(archetype, storage).expect("Archetype does not contain the fetched component!")
+    }
+
+    unsafe fn slot(column: Self::Column, entity: EntityType, row: usize) -> Option<Self::Slot> {
+        column.slot(entity, row)
+    }
+
+    unsafe fn fetch<'a>(column: Self::Column, slot: Self::Slot) -> Self::Item<'a> {
+        &*column.data.add(slot)
+    }
+
+    unsafe fn fetch_mut<'a>(column: Self::Column, slot: Self::Slot, tick: u32) -> Self::ItemMut<'a> {
+        *column.changed.add(slot) = tick;
+        &mut *column.data.add(slot)
+    }
+}
+
+impl<T: Component> Fetch for Option<T> {
+    type Item<'a> = Option<&'a T>;
+    type ItemMut<'a> = Option<&'a mut T>;
+    type Column = Option<ColumnPtr<T>>;
+    type Slot = Option<usize>;
+
+    fn access(access: &mut Access, mutable: bool) {
+        T::access(access, mutable);
+    }
+
+    fn matches(_: &Archetype, _: &ComponentStorage) -> bool {
+        true
+    }
+
+    unsafe fn column(archetype: &Archetype, storage: &ComponentStorage) -> Self::Column {
+        column_ptr::<T>(archetype, storage)
+    }
+
+    unsafe fn slot(column: Self::Column, entity: EntityType, row: usize) -> Option<Self::Slot> {
+        Some(column.and_then(|column| column.slot(entity, row)))
+    }
+
+    unsafe fn fetch<'a>(column: Self::Column, slot: Self::Slot) -> Self::Item<'a> {
+        column.zip(slot).map(|(column, slot)| T::fetch(column, slot))
+    }
+
+    unsafe fn fetch_mut<'a>(column: Self::Column, slot: Self::Slot, tick: u32) -> Self::ItemMut<'a> {
+        column.zip(slot).map(|(column, slot)| T::fetch_mut(column, slot, tick))
+    }
+}
+
+/// A set of component types that can be iterated by a [`System`](crate::ecs::system::System). Implemented for tuples
+/// of up to 15 [`Fetch`] elements. Tuples are [`Fetch`] elements themselves, so longer sets are written as nested
+/// tuples, like `((A, B, C), D)`, whose items are nested the same way. Nesting is the supported way past 15 elements,
+/// the same goes for [`QueryData`] and [`Filter`] tuples.
+pub trait Query: 'static {
+    type Item<'a>;
+    type ItemMut<'a>;
+    type Columns: Copy;
+
+    fn access(access: &mut Access, mutable: bool);
+
+    fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool;
+
+    /// # Safety
+    /// `archetype` has to match this query.
+    unsafe fn columns(archetype: &Archetype, storage: &ComponentStorage) -> Self::Columns;
+
+    /// Returns `None` if `entity` lacks one of the components stored in sparse sets.
+    ///
+    /// # Safety
+    /// See [`Fetch::slot`] and [`Fetch::fetch`].
+    unsafe fn fetch<'a>(entity: EntityType, columns: Self::Columns, row: usize) -> Option<Self::Item<'a>>;
+
+    /// # Safety
+    /// See [`Fetch::slot`] and [`Fetch::fetch_mut`].
+    unsafe fn fetch_mut<'a>(entity: EntityType, columns: Self::Columns, row: usize, tick: u32) -> Option<Self::ItemMut<'a>>;
+}
+
+macro_rules! impl_query_tuples {
+    () => {};
+
+    ($first:ident $($rest:ident)*) => {
+        impl_query_tuples!($($rest)*);
+
+        #[allow(non_snake_case)]
+        impl<$first: Fetch, $($rest: Fetch),*> Fetch for ($first, $($rest),*) {
+            type Item<'a> = ($first::Item<'a>, $($rest::Item<'a>),*);
+            type ItemMut<'a> = ($first::ItemMut<'a>, $($rest::ItemMut<'a>),*);
+            type Column = ($first::Column, $($rest::Column),*);
+            type Slot = (($first::Column, $first::Slot), $(($rest::Column, $rest::Slot)),*);
+
+            fn access(access: &mut Access, mutable: bool) {
+                $first::access(access, mutable);
+                $($rest::access(access, mutable);)*
+            }
+
+            fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool {
+                $first::matches(archetype, storage) $(&& $rest::matches(archetype, storage))*
+            }
+
+            unsafe fn column(archetype: &Archetype, storage: &ComponentStorage) -> Self::Column {
+                ($first::column(archetype, storage), $($rest::column(archetype, storage)),*)
+            }
+
+            unsafe fn slot(column: Self::Column, entity: EntityType, row: usize) -> Option<Self::Slot> {
+                let ($first, $($rest),*) = column;
+                Some((($first, $first::slot($first, entity, row)?), $(($rest, $rest::slot($rest, entity, row)?)),*))
+            }
+
+            unsafe fn fetch<'a>(_: Self::Column, slot: Self::Slot) -> Self::Item<'a> {
+                let ($first, $($rest),*) = slot;
+                ($first::fetch($first.0, $first.1), $($rest::fetch($rest.0, $rest.1)),*)
+            }
+
+            unsafe fn fetch_mut<'a>(_: Self::Column, slot: Self::Slot, tick: u32) -> Self::ItemMut<'a> {
+                let ($first, $($rest),*) = slot;
+                ($first::fetch_mut($first.0, $first.1, tick), $($rest::fetch_mut($rest.0, $rest.1, tick)),*)
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$first: QueryItem, $($rest: QueryItem),*> QueryItem for ($first, $($rest),*) {
+            type Fetch = ($first::Fetch, $($rest::Fetch),*);
+            type Item<'a> = ($first::Item<'a>, $($rest::Item<'a>),*);
+            type ReadItem<'a> = ($first::ReadItem<'a>, $($rest::ReadItem<'a>),*);
+
+            fn access(access: &mut Access) {
+                $first::access(access);
+                $($rest::access(access);)*
+            }
+
+            unsafe fn fetch<'a>(slot: FetchSlot<Self>, tick: u32) -> Self::Item<'a> {
+                let (_, ($first, $($rest),*)) = slot;
+                ($first::fetch($first, tick), $($rest::fetch($rest, tick)),*)
+            }
+
+            unsafe fn fetch_read<'a>(slot: FetchSlot<Self>) -> Self::ReadItem<'a> {
+                let (_, ($first, $($rest),*)) = slot;
+                ($first::fetch_read($first), $($rest::fetch_read($rest)),*)
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$first: QueryItem, $($rest: QueryItem),*> QueryData for ($first, $($rest),*) {
+            type Query = ($first::Fetch, $($rest::Fetch),*);
+            type Item<'a> = (EntityType, $first::Item<'a>, $($rest::Item<'a>),*);
+            type ReadItem<'a> = (EntityType, $first::ReadItem<'a>, $($rest::ReadItem<'a>),*);
+
+            fn access(access: &mut Access) {
+                $first::access(access);
+                $($rest::access(access);)*
+            }
+
+            unsafe fn fetch<'a>(entity: EntityType, columns: <Self::Query as Query>::Columns, row: usize, tick: u32) -> Option<Self::Item<'a>> {
+                let ($first, $($rest),*) = columns;
+                let ($first, $($rest),*) = (($first, <$first::Fetch as Fetch>::slot($first, entity, row)?), $(($rest, <$rest::Fetch as Fetch>::slot($rest, entity, row)?)),*);
+                Some((entity, $first::fetch($first, tick), $($rest::fetch($rest, tick)),*))
+            }
+
+            unsafe fn fetch_read<'a>(entity: EntityType, columns: <Self::Query as Query>::Columns, row: usize) -> Option<Self::ReadItem<'a>> {
+                let ($first, $($rest),*) = columns;
+                let ($first, $($rest),*) = (($first, <$first::Fetch as Fetch>::slot($first, entity, row)?), $(($rest, <$rest::Fetch as Fetch>::slot($rest, entity, row)?)),*);
+                Some((entity, $first::fetch_read($first), $($rest::fetch_read($rest)),*))
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$first: Fetch, $($rest: Fetch),*> Query for ($first, $($rest),*) {
+            type Item<'a> = (EntityType, $first::Item<'a>, $($rest::Item<'a>),*);
+            type ItemMut<'a> = (EntityType, $first::ItemMut<'a>, $($rest::ItemMut<'a>),*);
+            type Columns = ($first::Column, $($rest::Column),*);
+
+            fn access(access: &mut Access, mutable: bool) {
+                $first::access(access, mutable);
+                $($rest::access(access, mutable);)*
+            }
+
+            fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool {
+                $first::matches(archetype, storage) $(&& $rest::matches(archetype, storage))*
+            }
+
+            unsafe fn columns(archetype: &Archetype, storage: &ComponentStorage) -> Self::Columns {
+                ($first::column(archetype, storage), $($rest::column(archetype, storage)),*)
+            }
+
+            unsafe fn fetch<'a>(entity: EntityType, columns: Self::Columns, row: usize) -> Option<Self::Item<'a>> {
+                let ($first, $($rest),*) = columns;
+                let ($first, $($rest),*) = (($first, $first::slot($first, entity, row)?), $(($rest, $rest::slot($rest, entity, row)?)),*);
+                Some((entity, $first::fetch($first.0, $first.1), $($rest::fetch($rest.0, $rest.1)),*))
+            }
+
+            unsafe fn fetch_mut<'a>(entity: EntityType, columns: Self::Columns, row: usize, tick: u32) -> Option<Self::ItemMut<'a>> {
+                let ($first, $($rest),*) = columns;
+                let ($first, $($rest),*) = (($first, $first::slot($first, entity, row)?), $(($rest, $rest::slot($rest, entity, row)?)),*);
+                Some((entity, $first::fetch_mut($first.0, $first.1, tick), $($rest::fetch_mut($rest.0, $rest.1, tick)),*))
+            }
+        }
+    };
+}
+
+impl_query_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11 C12 C13 C14 C15);
+
+type FetchSlot<I> = (<<I as QueryItem>::Fetch as Fetch>::Column, <<I as QueryItem>::Fetch as Fetch>::Slot);
+
+/// One element of a [`Query`](crate::ecs::param::Query) parameter: `&T` and `Option<&T>` read the component `T`,
+/// `&mut T` and `Option<&mut T>` write it. Tuples of elements are elements themselves.
+pub trait QueryItem {
+    type Fetch: Fetch;
+    type Item<'a>;
+    type ReadItem<'a>;
+
+    fn access(access: &mut Access);
+
+    /// # Safety
+    /// See [`Fetch::fetch_mut`].
+    unsafe fn fetch<'a>(slot: FetchSlot<Self>, tick: u32) -> Self::Item<'a>;
+
+    /// # Safety
+    /// See [`Fetch::fetch`].
+    unsafe fn fetch_read<'a>(slot: FetchSlot<Self>) -> Self::ReadItem<'a>;
+}
+
+impl<T: Component> QueryItem for &T {
+    type Fetch = T;
+    type Item<'a> = &'a T;
+    type ReadItem<'a> = &'a T;
+
+    fn access(access: &mut Access) {
+        T::access(access, false);
+    }
+
+    unsafe fn fetch<'a>((column, slot): FetchSlot<Self>, _: u32) -> Self::Item<'a> {
+        T::fetch(column, slot)
+    }
+
+    unsafe fn fetch_read<'a>((column, slot): FetchSlot<Self>) -> Self::ReadItem<'a> {
+        T::fetch(column, slot)
+    }
+}
+
+impl<T: Component> QueryItem for &mut T {
+    type Fetch = T;
+    type Item<'a> = &'a mut T;
+    type ReadItem<'a> = &'a T;
+
+    fn access(access: &mut Access) {
+        T::access(access, true);
+    }
+
+    unsafe fn fetch<'a>((column, slot): FetchSlot<Self>, tick: u32) -> Self::Item<'a> {
+        T::fetch_mut(column, slot, tick)
+    }
+
+    unsafe fn fetch_read<'a>((column, slot): FetchSlot<Self>) -> Self::ReadItem<'a> {
+        T::fetch(column, slot)
+    }
+}
+
+impl<T: Component> QueryItem for Option<&T> {
+    type Fetch = Option<T>;
+    type Item<'a> = Option<&'a T>;
+    type ReadItem<'a> = Option<&'a T>;
+
+    fn access(access: &mut Access) {
+        T::access(access, false);
+    }
+
+    unsafe fn fetch<'a>((column, slot): FetchSlot<Self>, _: u32) -> Self::Item<'a> {
+        Option::<T>::fetch(column, slot)
+    }
+
+    unsafe fn fetch_read<'a>((column, slot): FetchSlot<Self>) -> Self::ReadItem<'a> {
+        Option::<T>::fetch(column, slot)
+    }
+}
+
+impl<T: Component> QueryItem for Option<&mut T> {
+    type Fetch = Option<T>;
+    type Item<'a> = Option<&'a mut T>;
+    type ReadItem<'a> = Option<&'a T>;
+
+    fn access(access: &mut Access) {
+        T::access(access, true);
+    }
+
+    unsafe fn fetch<'a>((column, slot): FetchSlot<Self>, tick: u32) -> Self::Item<'a> {
+        Option::<T>::fetch_mut(column, slot, tick)
+    }
+
+    unsafe fn fetch_read<'a>((column, slot): FetchSlot<Self>) -> Self::ReadItem<'a> {
+        Option::<T>::fetch(column, slot)
+    }
+}
+
+/// The component tuple of a [`Query`](crate::ecs::param::Query) parameter, like `(&mut Transform, &Velocity)`.
+/// Implemented for tuples of up to 15 [`QueryItem`] elements, which can be nested like the tuples of a [`Query`].
+pub trait QueryData {
+    type Query: Query;
+    type Item<'a>;
+    type ReadItem<'a>;
+
+    fn access(access: &mut Access);
+
+    /// # Safety
+    /// See [`Query::fetch_mut`].
+    unsafe fn fetch<'a>(entity: EntityType, columns: <Self::Query as Query>::Columns, row: usize, tick: u32) -> Option<Self::Item<'a>>;
+
+    /// # Safety
+    /// See [`Query::fetch`].
+    unsafe fn fetch_read<'a>(entity: EntityType, columns: <Self::Query as Query>::Columns, row: usize) -> Option<Self::ReadItem<'a>>;
+}
+
+/// Restricts which entities a [`System`](crate::ecs::system::System) yields, without fetching any data.
+/// Implemented for [`With`], [`Without`], [`Added`], [`Changed`] and tuples of up to 15 filters, which can be nested.
+pub trait Filter: 'static {
+    type Columns: Copy;
+
+    /// Records the components whose change ticks this filter reads.
+    fn access(access: &mut Access);
+
+    fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool;
+
+    /// # Safety
+    /// `archetype` has to match this filter.
+    unsafe fn columns(archetype: &Archetype, storage: &ComponentStorage) -> Self::Columns;
+
+    /// Checks a single entity in `row`. `last_run` is the tick the system last iterated at, `this_run` the tick of
+    /// the current iteration.
+    ///
+    /// # Safety
+    /// `columns` has to come from an archetype that has at least `row + 1` rows.
+    unsafe fn filter(columns: Self::Columns, entity: EntityType, row: usize, last_run: u32, this_run: u32) -> bool;
+}
+
+/// Only yields entities that have the component `T`.
+pub struct With<T>(PhantomData<T>);
+
+/// Only yields entities that do not have the component `T`.
+pub struct Without<T>(PhantomData<T>);
+
+/// Only yields entities whose component `T` was added since the system last iterated.
+pub struct Added<T>(PhantomData<T>);
+
+/// Only yields entities whose component `T` was added or mutably accessed since the system last iterated.
+pub struct Changed<T>(PhantomData<T>);
+
+/// How far the change tick may advance before the ticks stored in the columns are clamped again.
+pub(crate) const CHECK_TICK_THRESHOLD: u32 = 518_400_000;
+
+/// The oldest a stored tick can get. Ticks are compared by their distance to the current tick, which wraps around,
+/// so older ticks are clamped to this age and keep comparing as older than anything a system saw since.
+pub(crate) const MAX_CHANGE_AGE: u32 = u32::MAX - (2 * CHECK_TICK_THRESHOLD - 1);
+
+pub(crate) fn is_newer(tick: u32, last_run: u32, this_run: u32) -> bool {
+    this_run.wrapping_sub(tick) < this_run.wrapping_sub(last_run)
+}
+
+/// Returns `tick`, or the tick [`MAX_CHANGE_AGE`] before `this_run` if it is older than that.
+pub(crate) fn clamp_tick(tick: u32, this_run: u32) -> u32 {
+    if this_run.wrapping_sub(tick) > MAX_CHANGE_AGE {
+        this_run.wrapping_sub(MAX_CHANGE_AGE)
+    } else {
+        tick
+    }
+}
+
+fn sparse_set<T: 'static>(storage: &ComponentStorage) -> Option<*const SparseSet> {
+    storage.sparse_set(TypeId::of::<T>()).map(|set| set as *const SparseSet)
+}
+
+impl<T: Component> Filter for With<T> {
+    type Columns = Option<*const SparseSet>;
+
+    fn access(_: &mut Access) {}
+
+    fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool {
+        may_contain::<T>(archetype, storage)
+    }
+
+    unsafe fn columns(_: &Archetype, storage: &ComponentStorage) -> Self::Columns {
+        sparse_set::<T>(storage)
+    }
+
+    unsafe fn filter(columns: Self::Columns, entity: EntityType, _: usize, _: u32, _: u32) -> bool {
+        columns.is_none_or(|set| (*set).contains(entity))
+    }
+}
+
+impl<T: Component> Filter for Without<T> {
+    type Columns = Option<*const SparseSet>;
+
+    fn access(_: &mut Access) {}
+
+    fn matches(archetype: &Archetype, _: &ComponentStorage) -> bool {
+        !archetype.has(TypeId::of::<T>())
+    }
+
+    unsafe fn columns(_: &Archetype, storage: &ComponentStorage) -> Self::Columns {
+        sparse_set::<T>(storage)
+    }
+
+    unsafe fn filter(columns: Self::Columns, entity: EntityType, _: usize, _: u32, _: u32) -> bool {
+        columns.is_none_or(|set| !(*set).contains(entity))
+    }
+}
+
+impl<T: Component> Filter for Added<T> {
+    type Columns = ColumnPtr<T>;
+
+    fn access(access: &mut Access) {
+        access.read_component(TypeId::of::<T>());
+    }
+
+    fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool {
+        may_contain::<T>(archetype, storage)
+    }
+
+    unsafe fn columns(archetype: &Archetype, storage: &ComponentStorage) -> Self::Columns {
+        T::column(archetype, storage)
+    }
+
+    unsafe fn filter(columns: Self::Columns, entity: EntityType, row: usize, last_run: u32, this_run: u32) -> bool {
+        columns.slot(entity, row).is_some_and(|slot| is_newer(*columns.added.add(slot), last_run, this_run))
+    }
+}
+
+impl<T: Component> Filter for Changed<T> {
+    type Columns = ColumnPtr<T>;
+
+    fn access(access: &mut Access) {
+        access.read_component(TypeId::of::<T>());
+    }
+
+    fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool {
+        may_contain::<T>(archetype, storage)
+    }
+
+    unsafe fn columns(archetype: &Archetype, storage: &ComponentStorage) -> Self::Columns {
+        T::column(archetype, storage)
+    }
+
+    unsafe fn filter(columns: Self::Columns, entity: EntityType, row: usize, last_run: u32, this_run: u32) -> bool {
+        columns.slot(entity, row).is_some_and(|slot| is_newer(*columns.changed.add(slot), last_run, this_run))
+    }
+}
+
+impl Filter for () {
+    type Columns = ();
+
+    fn access(_: &mut Access) {}
+
+    fn matches(_: &Archetype, _: &ComponentStorage) -> bool {
+        true
+    }
+
+    unsafe fn columns(_: &Archetype, _: &ComponentStorage) -> Self::Columns {}
+
+    unsafe fn filter(_: Self::Columns, _: EntityType, _: usize, _: u32, _: u32) -> bool {
+        true
+    }
+}
+
+macro_rules! impl_filter_tuples {
+    () => {};
+
+    ($first:ident $($rest:ident)*) => {
+        impl_filter_tuples!($($rest)*);
+
+        #[allow(non_snake_case)]
+        impl<$first: Filter, $($rest: Filter),*> Filter for ($first, $($rest),*) {
+            type Columns = ($first::Columns, $($rest::Columns),*);
+
+            fn access(access: &mut Access) {
+                $first::access(access);
+                $($rest::access(access);)*
+            }
+
+            fn matches(archetype: &Archetype, storage: &ComponentStorage) -> bool {
+                $first::matches(archetype, storage) $(&& $rest::matches(archetype, storage))*
+            }
+
+            unsafe fn columns(archetype: &Archetype, storage: &ComponentStorage) -> Self::Columns {
+                ($first::columns(archetype, storage), $($rest::columns(archetype, storage)),*)
+            }
+
+            unsafe fn filter(columns: Self::Columns, entity: EntityType, row: usize, last_run: u32, this_run: u32) -> bool {
+                let ($first, $($rest),*) = columns;
+                $first::filter($first, entity, row, last_run, this_run) $(&& $rest::filter($rest, entity, row, last_run, this_run))*
+            }
+        }
+    };
+}
+
+impl_filter_tuples!(F1 F2 F3 F4 F5 F6 F7 F8 F9 F10 F11 F12 F13 F14 F15);
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::{Component, EcsStorage, StorageType, ECS};
+    use crate::ecs::entity::{Entity, EntityType, NoBehavior};
+    use crate::ecs::query::{clamp_tick, is_newer, Added, Changed, Filter, With, Without, MAX_CHANGE_AGE};
+    use crate::ecs::system::System;
+    use crate::ecs::testing::{Health, Mana, Name};
+    use crate::ecs::world::World;
+
+    fn spawn(storage: &EcsStorage, health: u32) -> EntityType {
+        let entity = storage.get_mut().spawn();
+        storage.get_mut().set_component(entity, Health(health));
+        entity
+    }
+
+    /// The entities `system` yields, sorted because archetypes are visited in the order they were created.
+    fn entities<F: Filter>(system: &System<(Health,), F>) -> Vec<EntityType> {
+        let mut entities: Vec<EntityType> = system.iter().map(|(entity, _)| entity).collect();
+        entities.sort();
+        entities
+    }
+
+    #[test]
+    fn with_without_and_optional_components_select_archetypes() {
+        let ecs = ECS::new();
+        let storage = ecs.storage();
+        let (plain, magic, named) = (spawn(&storage, 1), spawn(&storage, 2), spawn(&storage, 3));
+        storage.get_mut().set_component(magic, Mana(20));
+        storage.get_mut().set_component(named, Mana(30));
+        storage.get_mut().set_component(named, Name::new("named"));
+
+        assert_eq!(entities(&System::<(Health,), With<Mana>>::new(ecs.storage())), [magic, named]);
+        assert_eq!(entities(&System::<(Health,), Without<Mana>>::new(ecs.storage())), [plain]);
+        assert_eq!(entities(&System::<(Health,), (With<Mana>, Without<Name>)>::new(ecs.storage())), [magic]);
+
+        let mut optional = System::<(Health, Option<Mana>)>::new(ecs.storage());
+        for (_, health, mana) in optional.iter_mut() {
+            if let Some(mana) = mana {
+                mana.0 += health.0;
+            }
+        }
+        let mut found: Vec<_> = optional.iter().map(|(entity, _, mana)| (entity, mana.map(|mana| mana.0))).collect();
+        found.sort();
+        assert_eq!(found, [(plain, None), (magic, Some(22)), (named, Some(33))]);
+    }
+
+    #[test]
+    fn added_and_changed_are_relative_to_the_last_iteration() {
+        let ecs = ECS::new();
+        let storage = ecs.storage();
+        let (first, second) = (spawn(&storage, 1), spawn(&storage, 2));
+        storage.get_mut().set_component(first, Mana(1));
+        let added = System::<(Health,), Added<Mana>>::new(ecs.storage());
+        let changed = System::<(Health,), Changed<Health>>::new(ecs.storage());
+        assert_eq!(entities(&added), [first]);
+        assert_eq!(entities(&changed), [first, second]);
+        assert!(entities(&added).is_empty());
+        assert!(entities(&changed).is_empty());
+
+        // Moving an entity into another archetype keeps its ticks.
+        storage.get_mut().set_component(second, Mana(2));
+        storage.get_mut().set_component(first, Name::new("first"));
+        let mut writer = System::<(Health,), With<Name>>::new(ecs.storage());
+        writer.iter_mut().for_each(|(_, health)| health.0 += 1);
+        assert_eq!(entities(&added), [second]);
+        assert_eq!(entities(&changed), [first]);
+
+        // Reading does not count as a change, and a component that was replaced counts as changed.
+        assert_eq!(writer.iter().count(), 1);
+        storage.get_mut().set_component(second, Health(3));
+        assert_eq!(entities(&changed), [second]);
+    }
+
+    #[test]
+    fn queries_mix_table_and_sparse_components() {
+        let mut ecs = ECS::new();
+        ecs.world_mut().register_component::<Mana>(StorageType::SparseSet);
+        let storage = ecs.storage();
+        let spawned = [0, 1, 2, 3].map(|health| spawn(&storage, health));
+        storage.get_mut().set_component(spawned[1], Mana(1));
+        storage.get_mut().set_component(spawned[3], Mana(3));
+        storage.get_mut().set_component(spawned[3], Name::new("named"));
+
+        let mut system = System::<(Health, Mana)>::new(ecs.storage());
+        for (_, health, mana) in system.iter_mut() {
+            health.0 = mana.0 * 10;
+        }
+        let both: Vec<_> = system.iter().map(|(entity, health, _)| (entity, health.0)).collect();
+        assert_eq!(both, [(spawned[1], 10), (spawned[3], 30)]);
+
+        assert_eq!(entities(&System::<(Health,), Without<Mana>>::new(ecs.storage())), [spawned[0], spawned[2]]);
+        assert_eq!(System::<(Name,), With<Mana>>::new(ecs.storage()).iter().count(), 1);
+        let optional = System::<(Health, Option<Mana>)>::new(ecs.storage());
+        assert_eq!(optional.iter().filter(|(_, _, mana)| mana.is_some()).count(), 2);
+    }
+
+    #[test]
+    fn ticks_compare_across_the_wraparound() {
+        assert!(is_newer(2, u32::MAX - 5, 10));
+        assert!(!is_newer(u32::MAX - 6, u32::MAX - 5, 10));
+        assert_eq!(clamp_tick(5, 10), 5);
+        assert_eq!(clamp_tick(u32::MAX - 2, 3), u32::MAX - 2);
+        let this_run: u32 = 100;
+        let ancient = this_run.wrapping_sub(MAX_CHANGE_AGE).wrapping_sub(1);
+        assert_eq!(clamp_tick(ancient, this_run), this_run.wrapping_sub(MAX_CHANGE_AGE));
+        assert!(!is_newer(clamp_tick(ancient, this_run), clamp_tick(ancient, this_run), this_run));
+    }
+
+    macro_rules! components {
+        ($($name:ident)*) => {
+            $(
+                #[derive(Default, Debug, PartialEq)]
+                struct $name(u32);
+                impl Component for $name {}
+            )*
+        };
+    }
+
+    components!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11 C12 C13 C14 C15 C16 C17);
+
+    type First = (C1, C2, C3, C4, C5, C6, C7, C8, C9, C10, C11, C12, C13, C14, C15);
+
+    #[test]
+    fn nested_tuples_go_past_fifteen_components() {
+        let mut world = World::new();
+        let full = world.create_entity(Entity::<NoBehavior, (First, (C16, C17))>::new).expect("Entity could not be created");
+        world.insert_component(full, C16(16));
+        world.insert_component(full, C17(17));
+        world.create_entity(Entity::<NoBehavior, First>::new);
+
+        let mut system = System::<(First, (C16, C17))>::new(world.storage());
+        let items: Vec<_> = system.iter_mut().map(|(entity, (c1, .., c15), (c16, c17))| {
+            c1.0 = 1;
+            c15.0 = 15;
+            (entity, c16.0, c17.0)
+        }).collect();
+        assert_eq!(items, vec![(full, 16, 17)]);
+
+        let filtered = System::<(C1,), ((With<C2>, With<C15>), (Without<C16>, Without<C17>))>::new(world.storage());
+        assert!(filtered.iter().all(|(entity, _)| entity != full));
+        assert_eq!(filtered.iter().count(), 1);
+        assert_eq!(world.storage().get().get_component::<C15>(full), Some(&C15(15)));
+    }
+}
@@ -0,0 +1,114 @@
+use std::any::{Any, TypeId};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use hashbrown::HashMap;
+use crate::ecs::EcsStorage;
+
+/// Anything that can be stored in [`Resources`]. Systems running on worker threads read and write resources at the
+/// same time, so every resource has to be `Send + Sync`.
+pub trait Resource: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Resource for T {}
+
+/// Wraps a resource so systems can write it through a shared reference to the storage, once the schedule made sure
+/// nothing else accesses it.
+struct ResourceCell<R>(UnsafeCell<R>);
+
+// Access to the inner value is synchronized by the schedule, like the columns of the component storage.
+unsafe impl<R: Resource> Sync for ResourceCell<R> {}
+
+/// Type-keyed singletons shared by all systems and behaviors of a world, like frame timing, the active camera or the
+/// input state.
+#[derive(Default)]
+pub struct Resources {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts `resource`, returning the previous resource of the same type.
+    pub fn insert<R: Resource>(&mut self, resource: R) -> Option<R> {
+        self.map.insert(TypeId::of::<R>(), Box::new(ResourceCell(UnsafeCell::new(resource)))).and_then(Self::unwrap)
+    }
+
+    pub fn remove<R: Resource>(&mut self) -> Option<R> {
+        self.map.remove(&TypeId::of::<R>()).and_then(Self::unwrap)
+    }
+
+    pub fn get<R: Resource>(&self) -> Option<&R> {
+        // SAFETY: writes through a shared reference only happen in `get_unchecked_mut`, whose caller guarantees that
+        // no reference returned here is alive.
+        self.cell::<R>().map(|cell| unsafe { &*cell.0.get() })
+    }
+
+    pub fn get_mut<R: Resource>(&mut self) -> Option<&mut R> {
+        self.map.get_mut(&TypeId::of::<R>()).and_then(|r| r.downcast_mut::<ResourceCell<R>>()).map(|cell| cell.0.get_mut())
+    }
+
+    /// Returns the resource `R` mutably through a shared reference, this is how systems write resources while other
+    /// systems run at the same time.
+    ///
+    /// # Safety
+    /// Nothing else may access `R` while the returned reference is alive. The schedule guarantees this for resources
+    /// declared in the [`Access`](crate::ecs::schedule::Access) of a system.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_unchecked_mut<R: Resource>(&self) -> Option<&mut R> {
+        self.cell::<R>().map(|cell| &mut *cell.0.get())
+    }
+
+    pub fn contains<R: Resource>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<R>())
+    }
+
+    fn cell<R: Resource>(&self) -> Option<&ResourceCell<R>> {
+        self.map.get(&TypeId::of::<R>()).and_then(|r| r.downcast_ref::<ResourceCell<R>>())
+    }
+
+    fn unwrap<R: Resource>(old: Box<dyn Any + Send + Sync>) -> Option<R> {
+        old.downcast::<ResourceCell<R>>().ok().map(|old| old.0.into_inner())
+    }
+}
+
+/// Handle to one resource, resolved on every access. Behaviors can create it in [`EntityBehavior::new`] and read it
+/// in every update.
+///
+/// [`EntityBehavior::new`]: crate::ecs::entity::EntityBehavior::new
+#[derive(Clone)]
+pub struct LocalResource<R: Resource> {
+    phantom: PhantomData<R>,
+    storage: EcsStorage
+}
+
+impl<R: Resource> LocalResource<R> {
+    pub fn new(storage: EcsStorage) -> Self {
+        Self { phantom: PhantomData, storage }
+    }
+
+    pub fn get(&self) -> Option<&R> {
+        self.storage.get().resource::<R>()
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut R> {
+        // SAFETY: handles are used from the main thread between schedule runs or by a system that declared writing
+        // `R`, and `&mut self` keeps this one from handing out a second reference.
+        unsafe { self.storage.get().resources().get_unchecked_mut::<R>() }
+    }
+}
+
+impl<R: Resource> Deref for LocalResource<R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        self.get().expect("Resource of LocalResource was not inserted!")
+    }
+}
+
+impl<R: Resource> DerefMut for LocalResource<R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.get_mut().expect("Resource of LocalResource was not inserted!")
+    }
+}
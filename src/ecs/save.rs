@@ -0,0 +1,332 @@
+use std::any::TypeId;
+use bytebuffer::ByteBuffer;
+use hashbrown::HashMap;
+use mvutils::save::{Loader, Savable, Saver};
+use crate::ecs::Component;
+use crate::ecs::entity::EntityType;
+use crate::ecs::hierarchy::{Children, Parent};
+use crate::ecs::mem::sparse::SparseSet;
+use crate::ecs::mem::storage::ComponentStorage;
+
+/// Version of the format written by [`World::save`](crate::ecs::world::World::save).
+pub const SAVE_VERSION: u32 = 1;
+
+impl Savable for EntityType {
+    fn save(&self, saver: &mut impl Saver) {
+        saver.push_u64(self.to_bits());
+    }
+
+    fn load(loader: &mut impl Loader) -> Result<Self, String> {
+        loader.pop_u64().map(EntityType::from_bits).ok_or("Failed to load EntityType from Loader!".to_string())
+    }
+}
+
+impl Savable for Parent {
+    fn save(&self, saver: &mut impl Saver) {
+        self.0.save(saver);
+    }
+
+    fn load(loader: &mut impl Loader) -> Result<Self, String> {
+        EntityType::load(loader).map(Parent)
+    }
+}
+
+impl Savable for Children {
+    fn save(&self, saver: &mut impl Saver) {
+        self.0.save(saver);
+    }
+
+    fn load(loader: &mut impl Loader) -> Result<Self, String> {
+        Vec::<EntityType>::load(loader).map(Children)
+    }
+}
+
+struct SavableEntry {
+    name: String,
+    save: fn(&ComponentStorage, EntityType, &mut ByteBuffer),
+    load: fn(&mut ComponentStorage, EntityType, &mut ByteBuffer) -> Result<(), String>,
+}
+
+/// The component types that are written into and read from world snapshots. Every type is identified by the name it
+/// was registered with, so snapshots stay readable when types are renamed, moved or registered in a different order.
+pub struct SaveRegistry {
+    entries: Vec<SavableEntry>,
+    by_type: HashMap<TypeId, usize>,
+    by_name: HashMap<String, usize>,
+}
+
+impl SaveRegistry {
+    /// Creates a registry that already contains [`Parent`] and [`Children`].
+    pub fn new() -> Self {
+        let mut this = Self {
+            entries: Vec::new(),
+            by_type: HashMap::new(),
+            by_name: HashMap::new(),
+        };
+        this.register::<Parent>("mvengine::Parent");
+        this.register::<Children>("mvengine::Children");
+        this
+    }
+
+    /// Registers `T` under `name`. Registering a type or a name a second time replaces the old entry.
+    pub fn register<T: Component + Savable>(&mut self, name: &str) {
+        let entry = SavableEntry {
+            name: name.to_string(),
+            save: save_component::<T>,
+            load: load_component::<T>,
+        };
+        let ty = TypeId::of::<T>();
+        let idx = self.by_type.get(&ty).or_else(|| self.by_name.get(name)).copied();
+        let idx = match idx {
+            Some(idx) => {
+                self.by_name.remove(&self.entries[idx].name);
+                self.entries[idx] = entry;
+                idx
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+        self.by_type.retain(|_, i| *i != idx);
+        self.by_type.insert(ty, idx);
+        self.by_name.insert(name.to_string(), idx);
+    }
+
+    pub fn is_registered<T: 'static>(&self) -> bool {
+        self.by_type.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn name_of(&self, ty: TypeId) -> Option<&str> {
+        self.by_type.get(&ty).map(|idx| self.entries[*idx].name.as_str())
+    }
+
+    /// Writes every entity that has at least one registered component. Other components and behaviors are not
+    /// saved.
+    pub(crate) fn save(&self, storage: &ComponentStorage, buffer: &mut ByteBuffer) {
+        let sparse: Vec<(usize, &SparseSet)> = storage.sparse_sets()
+            .filter_map(|(ty, set)| self.by_type.get(&ty).map(|idx| (*idx, set)))
+            .collect();
+        let mut entities = Vec::new();
+        for archetype in storage.archetypes() {
+            let types: Vec<usize> = archetype.types().iter().filter_map(|ty| self.by_type.get(ty).copied()).collect();
+            for entity in archetype.entities() {
+                let mut types = types.clone();
+                types.extend(sparse.iter().filter(|(_, set)| set.contains(*entity)).map(|(idx, _)| *idx));
+                if !types.is_empty() {
+                    entities.push((*entity, types));
+                }
+            }
+        }
+
+        buffer.push_u32(SAVE_VERSION);
+        buffer.push_u32(self.entries.len() as u32);
+        for entry in &self.entries {
+            buffer.push_string(&entry.name);
+        }
+        buffer.push_u32(entities.len() as u32);
+        let mut component = ByteBuffer::new();
+        for (entity, types) in entities {
+            entity.save(buffer);
+            buffer.push_u32(types.len() as u32);
+            for idx in types {
+                component.clear();
+                (self.entries[idx].save)(storage, entity, &mut component);
+                buffer.push_u32(idx as u32);
+                buffer.push_u32(component.len() as u32);
+                buffer.push_bytes(component.as_bytes());
+            }
+        }
+    }
+
+    /// Spawns every saved entity and returns the new handle of every saved handle. Components of types that are not
+    /// registered, or that fail to load, are skipped with a warning. The whole snapshot is read before anything is
+    /// spawned, so nothing changes if it is rejected.
+    pub(crate) fn load(&self, storage: &mut ComponentStorage, buffer: &mut ByteBuffer) -> Result<HashMap<EntityType, EntityType>, String> {
+        let version = buffer.pop_u32().ok_or("Snapshot is empty!")?;
+        if version > SAVE_VERSION {
+            return Err(format!("Snapshot version {version} is newer than the supported version {SAVE_VERSION}!"));
+        }
+        let type_count = buffer.pop_u32().ok_or("Snapshot type table is truncated!")?;
+        let mut types = Vec::with_capacity(type_count as usize);
+        for _ in 0..type_count {
+            let name = buffer.pop_string().ok_or("Snapshot type table is truncated!")?;
+            let entry = self.by_name.get(&name).copied();
+            if entry.is_none() {
+                log::warn!("Skipping unknown component type {name} while loading a snapshot");
+            }
+            types.push(entry);
+        }
+
+        let entity_count = buffer.pop_u32().ok_or("Snapshot entity table is truncated!")?;
+        let mut entities = Vec::with_capacity(entity_count as usize);
+        for _ in 0..entity_count {
+            let saved = EntityType::load(buffer)?;
+            let component_count = buffer.pop_u32().ok_or("Snapshot entity is truncated!")?;
+            let mut components = Vec::new();
+            for _ in 0..component_count {
+                let ty = buffer.pop_u32().ok_or("Snapshot component is truncated!")? as usize;
+                let len = buffer.pop_u32().ok_or("Snapshot component is truncated!")? as usize;
+                let bytes = buffer.pop_bytes(len).ok_or("Snapshot component is truncated!")?;
+                if let Some(Some(idx)) = types.get(ty) {
+                    components.push((*idx, bytes));
+                }
+            }
+            entities.push((saved, components));
+        }
+
+        let mut mapping = HashMap::with_capacity(entities.len());
+        for (saved, components) in entities {
+            let entity = storage.spawn();
+            mapping.insert(saved, entity);
+            for (idx, bytes) in components {
+                let entry = &self.entries[idx];
+                if let Err(e) = (entry.load)(storage, entity, &mut ByteBuffer::from_vec(bytes)) {
+                    log::warn!("Skipping component {} of entity {saved} while loading a snapshot: {e}", entry.name);
+                }
+            }
+        }
+
+        remap_hierarchy(storage, &mapping);
+        Ok(mapping)
+    }
+}
+
+impl Default for SaveRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn save_component<T: Component + Savable>(storage: &ComponentStorage, entity: EntityType, buffer: &mut ByteBuffer) {
+    if let Some(component) = storage.get_component::<T>(entity) {
+        component.save(buffer);
+    }
+}
+
+fn load_component<T: Component + Savable>(storage: &mut ComponentStorage, entity: EntityType, buffer: &mut ByteBuffer) -> Result<(), String> {
+    storage.set_component(entity, T::load(buffer)?);
+    Ok(())
+}
+
+/// Points the [`Parent`] and [`Children`] of the loaded entities to the new handles. References to entities that were
+/// not part of the snapshot are dropped.
+pub(crate) fn remap_hierarchy(storage: &mut ComponentStorage, mapping: &HashMap<EntityType, EntityType>) {
+    for entity in mapping.values() {
+        if let Some(parent) = storage.get_component::<Parent>(*entity).map(Parent::get) {
+            match mapping.get(&parent) {
+                Some(parent) => storage.set_component(*entity, Parent(*parent)),
+                None => {
+                    storage.remove_component::<Parent>(*entity);
+                }
+            }
+        }
+        if let Some(children) = storage.get_component_mut::<Children>(*entity) {
+            children.0 = children.0.iter().filter_map(|child| mapping.get(child).copied()).collect();
+            if children.is_empty() {
+                storage.remove_component::<Children>(*entity);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytebuffer::ByteBuffer;
+    use mvutils::save::{Loader, Savable, Saver};
+    use crate::ecs::ECS;
+    use crate::ecs::entity::{Entity, EntityType, NoBehavior};
+    use crate::ecs::save::SAVE_VERSION;
+    use crate::ecs::testing::{Health, Mana, Name};
+    use crate::ecs::world::World;
+
+    impl Savable for Health {
+        fn save(&self, saver: &mut impl Saver) {
+            self.0.save(saver);
+        }
+
+        fn load(loader: &mut impl Loader) -> Result<Self, String> {
+            u32::load(loader).map(Health)
+        }
+    }
+
+    impl Savable for Name {
+        fn save(&self, saver: &mut impl Saver) {
+            self.0.save(saver);
+        }
+
+        fn load(loader: &mut impl Loader) -> Result<Self, String> {
+            String::load(loader).map(Name)
+        }
+    }
+
+    fn register(world: &mut World) {
+        world.register_savable::<Health>("health");
+        world.register_savable::<Name>("name");
+    }
+
+    /// Mana is never registered, so it is not saved.
+    fn spawn(world: &mut World) -> EntityType {
+        world.create_entity(Entity::<NoBehavior, (Health, Mana)>::new).expect("Entity could not be created")
+    }
+
+    #[test]
+    fn snapshots_round_trip_with_their_hierarchy() {
+        let mut source = ECS::new();
+        let world = source.world_mut();
+        register(world);
+        let [root, child, outside] = [(); 3].map(|_| spawn(world));
+        world.insert_component(root, Health(10));
+        world.insert_component(child, Name::new("child"));
+        world.set_parent(child, root);
+        let unsaved = world.create_entity(Entity::<NoBehavior, (Mana,)>::new).expect("Entity could not be created");
+        let mut buffer = ByteBuffer::new();
+        world.save(&mut buffer);
+
+        let mut target = ECS::new();
+        let world = target.world_mut();
+        register(world);
+        let existing = spawn(world);
+        let mapping = world.load(&mut buffer).expect("Snapshot was rejected");
+        assert_eq!(mapping.len(), 3);
+        assert!(!mapping.contains_key(&unsaved));
+        assert!(!mapping.values().any(|entity| *entity == existing));
+        let (root, child, outside) = (mapping[&root], mapping[&child], mapping[&outside]);
+        assert_eq!(world.remove_component::<Health>(root), Some(Health(10)));
+        assert_eq!(world.remove_component::<Name>(child), Some(Name::new("child")));
+        assert!(!world.has_component::<Mana>(root));
+        assert_eq!(world.parent(child), Some(root));
+        assert_eq!(world.children(root), [child]);
+        assert_eq!(world.parent(outside), None);
+        assert_eq!(target.storage().get().entity_count(), 4);
+    }
+
+    #[test]
+    fn unknown_types_are_skipped_and_bad_snapshots_rejected() {
+        let mut source = ECS::new();
+        let world = source.world_mut();
+        register(world);
+        let entity = spawn(world);
+        world.insert_component(entity, Name::new("named"));
+        let mut buffer = ByteBuffer::new();
+        world.save(&mut buffer);
+        let bytes = buffer.into_vec();
+
+        let mut target = ECS::new();
+        let world = target.world_mut();
+        world.register_savable::<Health>("health");
+        let mapping = world.load(&mut ByteBuffer::from_vec(bytes.clone())).expect("Snapshot was rejected");
+        assert!(world.has_component::<Health>(mapping[&entity]));
+        assert!(!world.has_component::<Name>(mapping[&entity]));
+
+        let before = world.storage().get().entity_count();
+        let mut truncated = ByteBuffer::from_vec(bytes[..bytes.len() - 2].to_vec());
+        assert!(world.load(&mut truncated).is_err());
+        assert_eq!(world.storage().get().entity_count(), before);
+
+        let mut newer = ByteBuffer::new();
+        newer.push_u32(SAVE_VERSION + 1);
+        assert!(ECS::new().world_mut().load(&mut newer).is_err());
+        assert!(ECS::new().world_mut().load(&mut ByteBuffer::new()).is_err());
+    }
+}
@@ -0,0 +1,560 @@
+use std::any::TypeId;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use hashbrown::HashMap;
+use log::warn;
+use mvsync::task::TaskResult;
+use mvsync::{MVSync, MVSyncSpecs};
+use crate::ecs::EcsStorage;
+use crate::ecs::query::{Filter, Query};
+use crate::ecs::resource::Resource;
+use crate::ecs::system::System;
+
+/// The stages of a frame, run in declaration order. [`World::update`](crate::ecs::world::World::update) runs
+/// everything up to [`Stage::PostUpdate`], [`World::render`](crate::ecs::world::World::render) runs
+/// [`Stage::Render`].
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Stage {
+    PreUpdate,
+    Update,
+    PostUpdate,
+    Render,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::PreUpdate, Stage::Update, Stage::PostUpdate, Stage::Render];
+}
+
+/// The components and resources a scheduled system reads and writes. Two systems whose accesses are not compatible
+/// are never run at the same time.
+#[derive(Clone, Default, Debug)]
+pub struct Access {
+    component_reads: Vec<TypeId>,
+    component_writes: Vec<TypeId>,
+    resource_reads: Vec<TypeId>,
+    resource_writes: Vec<TypeId>,
+    exclusive: bool,
+}
+
+impl Access {
+    pub fn read_component(&mut self, ty: TypeId) {
+        if !self.component_reads.contains(&ty) {
+            self.component_reads.push(ty);
+        }
+    }
+
+    pub fn write_component(&mut self, ty: TypeId) {
+        if !self.component_writes.contains(&ty) {
+            self.component_writes.push(ty);
+        }
+    }
+
+    pub fn read_resource(&mut self, ty: TypeId) {
+        if !self.resource_reads.contains(&ty) {
+            self.resource_reads.push(ty);
+        }
+    }
+
+    pub fn write_resource(&mut self, ty: TypeId) {
+        if !self.resource_writes.contains(&ty) {
+            self.resource_writes.push(ty);
+        }
+    }
+
+    /// Adds everything `other` reads and writes.
+    pub fn extend(&mut self, other: &Access) {
+        other.component_reads.iter().for_each(|ty| self.read_component(*ty));
+        other.component_writes.iter().for_each(|ty| self.write_component(*ty));
+        other.resource_reads.iter().for_each(|ty| self.read_resource(*ty));
+        other.resource_writes.iter().for_each(|ty| self.write_resource(*ty));
+        self.exclusive |= other.exclusive;
+    }
+
+    /// Marks the access as conflicting with every other access.
+    pub fn set_exclusive(&mut self) {
+        self.exclusive = true;
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    pub fn component_reads(&self) -> &[TypeId] {
+        &self.component_reads
+    }
+
+    pub fn component_writes(&self) -> &[TypeId] {
+        &self.component_writes
+    }
+
+    pub fn resource_reads(&self) -> &[TypeId] {
+        &self.resource_reads
+    }
+
+    pub fn resource_writes(&self) -> &[TypeId] {
+        &self.resource_writes
+    }
+
+    pub fn is_compatible(&self, other: &Access) -> bool {
+        fn disjoint(writes: &[TypeId], reads: &[TypeId], other_writes: &[TypeId], other_reads: &[TypeId]) -> bool {
+            writes.iter().all(|ty| !other_writes.contains(ty) && !other_reads.contains(ty))
+                && other_writes.iter().all(|ty| !reads.contains(ty))
+        }
+
+        !self.exclusive && !other.exclusive
+            && disjoint(&self.component_writes, &self.component_reads, &other.component_writes, &other.component_reads)
+            && disjoint(&self.resource_writes, &self.resource_reads, &other.resource_writes, &other.resource_reads)
+    }
+}
+
+/// A system that can be run by a [`Schedule`].
+pub trait ScheduledSystem {
+    fn name(&self) -> &str;
+
+    /// The access inferred from the system's type.
+    fn access(&self) -> Access;
+
+    /// Receives the final access of the system, including everything declared through [`SystemConfig`], before the
+    /// system runs for the first time after it was added or reconfigured.
+    fn configure(&mut self, _access: &Access) {}
+
+    fn run(&mut self);
+}
+
+/// Converts a function into a [`ScheduledSystem`]. Implemented for functions taking `&System<C, F>`, which read the
+/// components `C`, `&mut System<C, F>`, which write them, and functions annotated with
+/// [`#[system]`](macro@crate::ecs::system), which take [`SystemParam`](crate::ecs::param::SystemParam)s.
+pub trait IntoScheduled<Marker> {
+    fn into_scheduled(self, storage: EcsStorage) -> Box<dyn ScheduledSystem>;
+}
+
+#[doc(hidden)]
+pub struct ReadOnly;
+
+#[doc(hidden)]
+pub struct Mutable;
+
+#[doc(hidden)]
+pub struct Params;
+
+impl<Func: FnOnce(EcsStorage) -> Box<dyn ScheduledSystem>> IntoScheduled<Params> for Func {
+    fn into_scheduled(self, storage: EcsStorage) -> Box<dyn ScheduledSystem> {
+        self(storage)
+    }
+}
+
+struct FunctionSystem<C, F, Func, Marker> {
+    phantom: PhantomData<Marker>,
+    name: &'static str,
+    system: System<C, F>,
+    func: Func,
+}
+
+impl<C: Query, F: Filter, Func: FnMut(&System<C, F>) + Send + 'static> ScheduledSystem for FunctionSystem<C, F, Func, ReadOnly> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn access(&self) -> Access {
+        let mut access = Access::default();
+        C::access(&mut access, false);
+        F::access(&mut access);
+        access
+    }
+
+    fn configure(&mut self, access: &Access) {
+        self.system.declare(access.clone());
+    }
+
+    fn run(&mut self) {
+        (self.func)(&self.system);
+    }
+}
+
+impl<C: Query, F: Filter, Func: FnMut(&mut System<C, F>) + Send + 'static> ScheduledSystem for FunctionSystem<C, F, Func, Mutable> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn access(&self) -> Access {
+        let mut access = Access::default();
+        C::access(&mut access, true);
+        F::access(&mut access);
+        access
+    }
+
+    fn configure(&mut self, access: &Access) {
+        self.system.declare(access.clone());
+    }
+
+    fn run(&mut self) {
+        (self.func)(&mut self.system);
+    }
+}
+
+impl<C: Query, F: Filter, Func: FnMut(&System<C, F>) + Send + 'static> IntoScheduled<(ReadOnly, C, F)> for Func {
+    fn into_scheduled(self, storage: EcsStorage) -> Box<dyn ScheduledSystem> {
+        Box::new(FunctionSystem::<C, F, Func, ReadOnly> {
+            phantom: PhantomData,
+            name: std::any::type_name::<Func>(),
+            system: System::new(storage),
+            func: self,
+        })
+    }
+}
+
+impl<C: Query, F: Filter, Func: FnMut(&mut System<C, F>) + Send + 'static> IntoScheduled<(Mutable, C, F)> for Func {
+    fn into_scheduled(self, storage: EcsStorage) -> Box<dyn ScheduledSystem> {
+        Box::new(FunctionSystem::<C, F, Func, Mutable> {
+            phantom: PhantomData,
+            name: std::any::type_name::<Func>(),
+            system: System::new(storage),
+            func: self,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct SystemId(usize);
+
+struct SystemEntry {
+    stage: Stage,
+    system: Box<dyn ScheduledSystem>,
+    access: Access,
+    after: Vec<SystemId>,
+    before: Vec<SystemId>,
+}
+
+/// Runs registered systems stage by stage. Within a stage, systems run in registration order unless reordered with
+/// [`SystemConfig::after`] or [`SystemConfig::before`]. In parallel mode, systems with compatible [`Access`] run on
+/// worker threads at the same time, in single threaded mode every system runs on the calling thread, in a
+/// deterministic order.
+///
+/// Systems that access resources through [`System::resource`] or [`System::resource_mut`] have to declare them with
+/// [`SystemConfig::reads_resource`] or [`SystemConfig::writes_resource`], otherwise they panic. Structural changes have to be recorded with
+/// [`Commands`](crate::ecs::command::Commands), they are applied by the [`World`](crate::ecs::world::World) once the
+/// stage has finished.
+pub struct Schedule {
+    storage: EcsStorage,
+    systems: Vec<SystemEntry>,
+    batches: Option<HashMap<Stage, Vec<Vec<usize>>>>,
+    executor: Option<Arc<MVSync>>,
+}
+
+impl Schedule {
+    pub fn new(storage: EcsStorage) -> Self {
+        Self {
+            storage,
+            systems: Vec::new(),
+            batches: None,
+            executor: None,
+        }
+    }
+
+    /// Runs every system on the calling thread. This is the default.
+    pub fn single_threaded(&mut self) {
+        self.executor = None;
+    }
+
+    /// Runs systems with compatible access on `threads` worker threads.
+    pub fn parallel(&mut self, threads: u32) {
+        self.executor = Some(MVSync::new(MVSyncSpecs {
+            thread_count: threads,
+            workers_per_thread: 1,
+        }));
+    }
+
+    pub fn is_parallel(&self) -> bool {
+        self.executor.is_some()
+    }
+
+    pub fn add_system<M>(&mut self, stage: Stage, system: impl IntoScheduled<M>) -> SystemConfig<'_> {
+        let system = system.into_scheduled(self.storage.clone());
+        let access = system.access();
+        self.systems.push(SystemEntry {
+            stage,
+            system,
+            access,
+            after: Vec::new(),
+            before: Vec::new(),
+        });
+        self.batches = None;
+        SystemConfig {
+            id: SystemId(self.systems.len() - 1),
+            schedule: self,
+        }
+    }
+
+    pub fn system_name(&self, id: SystemId) -> Option<&str> {
+        self.systems.get(id.0).map(|entry| entry.system.name())
+    }
+
+    pub fn access(&self, id: SystemId) -> Option<&Access> {
+        self.systems.get(id.0).map(|entry| &entry.access)
+    }
+
+    /// Returns the groups of systems of `stage` that run at the same time, in execution order.
+    pub fn batches(&mut self, stage: Stage) -> Vec<Vec<SystemId>> {
+        self.ensure_batches();
+        self.batches.as_ref().and_then(|b| b.get(&stage)).map_or_else(Vec::new, |batches| {
+            batches.iter().map(|batch| batch.iter().map(|idx| SystemId(*idx)).collect()).collect()
+        })
+    }
+
+    /// Runs every stage in order.
+    pub fn run(&mut self) {
+        for stage in Stage::ALL {
+            self.run_stage(stage);
+        }
+    }
+
+    pub fn run_stage(&mut self, stage: Stage) {
+        self.ensure_batches();
+        let Some(batches) = self.batches.as_ref().and_then(|b| b.get(&stage)).cloned() else { return; };
+        for batch in batches {
+            match &self.executor {
+                Some(executor) if batch.len() > 1 => {
+                    let executor = executor.clone();
+                    self.run_parallel(&executor, &batch);
+                }
+                _ => {
+                    for idx in batch {
+                        self.systems[idx].system.run();
+                    }
+                }
+            }
+        }
+    }
+
+    fn run_parallel(&mut self, executor: &Arc<MVSync>, batch: &[usize]) {
+        let queue = executor.get_queue();
+        let handles: Vec<_> = batch.iter().map(|idx| {
+            let system = SystemPtr(&mut *self.systems[*idx].system as *mut dyn ScheduledSystem);
+            let (task, handle) = executor.create_task(move || unsafe { system.run() });
+            queue.submit(task);
+            handle
+        }).collect();
+
+        let mut panic = None;
+        for handle in handles {
+            if let TaskResult::Panicked(payload) = handle.wait() {
+                panic.get_or_insert(payload);
+            }
+        }
+        if let Some(payload) = panic {
+            std::panic::resume_unwind(payload);
+        }
+    }
+
+    fn ensure_batches(&mut self) {
+        if self.batches.is_none() {
+            for entry in &mut self.systems {
+                entry.system.configure(&entry.access);
+            }
+            let batches = Stage::ALL.iter().map(|stage| (*stage, self.build_batches(*stage))).collect();
+            self.batches = Some(batches);
+        }
+    }
+
+    fn build_batches(&self, stage: Stage) -> Vec<Vec<usize>> {
+        let members: Vec<usize> = (0..self.systems.len()).filter(|idx| self.systems[*idx].stage == stage).collect();
+        let mut dependencies: HashMap<usize, Vec<usize>> = members.iter().map(|idx| (*idx, Vec::new())).collect();
+        for idx in &members {
+            let entry = &self.systems[*idx];
+            for SystemId(other) in &entry.after {
+                if let Some(deps) = self.dependency_of(stage, *idx, *other, &mut dependencies) {
+                    deps.push(*other);
+                }
+            }
+            for SystemId(other) in &entry.before {
+                if let Some(deps) = self.dependency_of(stage, *other, *idx, &mut dependencies) {
+                    deps.push(*idx);
+                }
+            }
+        }
+
+        let mut order = Vec::with_capacity(members.len());
+        let mut remaining = members.clone();
+        while !remaining.is_empty() {
+            let Some(pos) = remaining.iter().position(|idx| dependencies[idx].iter().all(|dep| order.contains(dep))) else {
+                let names: Vec<&str> = remaining.iter().map(|idx| self.systems[*idx].system.name()).collect();
+                panic!("Cyclic system ordering in stage {stage:?} between: {}", names.join(", "));
+            };
+            order.push(remaining.remove(pos));
+        }
+
+        let mut batch_of: HashMap<usize, usize> = HashMap::new();
+        let mut batches: Vec<Vec<usize>> = Vec::new();
+        for (pos, idx) in order.iter().enumerate() {
+            let mut batch = dependencies[idx].iter().map(|dep| batch_of[dep] + 1).max().unwrap_or(0);
+            for earlier in &order[..pos] {
+                if !self.systems[*idx].access.is_compatible(&self.systems[*earlier].access) {
+                    batch = batch.max(batch_of[earlier] + 1);
+                }
+            }
+            if batch == batches.len() {
+                batches.push(Vec::new());
+            }
+            batches[batch].push(*idx);
+            batch_of.insert(*idx, batch);
+        }
+        batches
+    }
+
+    fn dependency_of<'a>(&self, stage: Stage, system: usize, dependency: usize, dependencies: &'a mut HashMap<usize, Vec<usize>>) -> Option<&'a mut Vec<usize>> {
+        if self.systems.get(dependency).is_some_and(|entry| entry.stage == stage) {
+            dependencies.get_mut(&system)
+        } else {
+            warn!("Ignoring ordering constraint between {} and a system of another stage", self.systems[system].system.name());
+            None
+        }
+    }
+}
+
+/// Configures a system right after it was added to a [`Schedule`].
+pub struct SystemConfig<'a> {
+    id: SystemId,
+    schedule: &'a mut Schedule,
+}
+
+impl SystemConfig<'_> {
+    pub fn id(&self) -> SystemId {
+        self.id
+    }
+
+    /// Runs this system after `other` has finished. Both have to be in the same stage.
+    pub fn after(self, other: SystemId) -> Self {
+        self.schedule.systems[self.id.0].after.push(other);
+        self.schedule.batches = None;
+        self
+    }
+
+    /// Runs this system before `other` starts. Both have to be in the same stage.
+    pub fn before(self, other: SystemId) -> Self {
+        self.schedule.systems[self.id.0].before.push(other);
+        self.schedule.batches = None;
+        self
+    }
+
+    pub fn reads_resource<R: Resource>(self) -> Self {
+        self.schedule.systems[self.id.0].access.read_resource(TypeId::of::<R>());
+        self.schedule.batches = None;
+        self
+    }
+
+    pub fn writes_resource<R: Resource>(self) -> Self {
+        self.schedule.systems[self.id.0].access.write_resource(TypeId::of::<R>());
+        self.schedule.batches = None;
+        self
+    }
+
+    /// Never runs this system at the same time as any other system.
+    pub fn exclusive(self) -> Self {
+        self.schedule.systems[self.id.0].access.set_exclusive();
+        self.schedule.batches = None;
+        self
+    }
+}
+
+struct SystemPtr(*mut dyn ScheduledSystem);
+
+// The schedule only runs systems with compatible access at the same time and waits for all of them before the
+// pointers can be invalidated. Systems only get shared access to the storage and write components and resources
+// through interior mutability, so compatible systems never create aliasing `&mut` references.
+unsafe impl Send for SystemPtr {}
+
+impl SystemPtr {
+    unsafe fn run(self) {
+        (*self.0).run();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::ECS;
+    use crate::ecs::schedule::Stage;
+    use crate::ecs::system::System;
+    use crate::ecs::testing::{Health, Mana};
+
+    #[derive(Default)]
+    struct Ticks(u32);
+
+    #[test]
+    fn conflicting_systems_run_in_separate_batches() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        let read_health = world.add_system(Stage::Update, |_: &System<(Health,)>| {}).id();
+        let write_health = world.add_system(Stage::Update, |_: &mut System<(Health,)>| {}).id();
+        let read_mana = world.add_system(Stage::Update, |_: &System<(Mana,)>| {}).id();
+        let write_mana = world.add_system(Stage::Update, |_: &mut System<(Mana,)>| {}).id();
+        let render = world.add_system(Stage::Render, |_: &mut System<(Health,)>| {}).id();
+        let schedule = world.schedule_mut();
+        assert_eq!(schedule.batches(Stage::Update), [vec![read_health, read_mana], vec![write_health, write_mana]]);
+        assert_eq!(schedule.batches(Stage::Render), [vec![render]]);
+        assert!(schedule.batches(Stage::PreUpdate).is_empty());
+    }
+
+    #[test]
+    fn ordering_and_declared_resources_split_batches() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        let first = world.add_system(Stage::Update, |_: &System<(Health,)>| {}).id();
+        let second = world.add_system(Stage::Update, |_: &System<(Mana,)>| {}).after(first).id();
+        let third = world.add_system(Stage::Update, |_: &System<(Mana,)>| {}).writes_resource::<Ticks>().id();
+        let fourth = world.add_system(Stage::Update, |_: &System<(Mana,)>| {}).reads_resource::<Ticks>().before(first).id();
+        let alone = world.add_system(Stage::Update, |_: &System<(Mana,)>| {}).exclusive().id();
+        assert_eq!(world.schedule_mut().batches(Stage::Update), [vec![third], vec![fourth], vec![first], vec![second], vec![alone]]);
+    }
+
+    #[test]
+    fn parallel_schedules_run_every_system() {
+        let mut ecs = ECS::new();
+        let storage = ecs.storage();
+        let world = ecs.world_mut();
+        world.insert_resource(Ticks::default());
+        for _ in 0..3 {
+            let entity = storage.get_mut().spawn();
+            world.insert_component(entity, Health(0));
+            world.insert_component(entity, Mana(0));
+        }
+        world.schedule_mut().parallel(2);
+        world.add_system(Stage::Update, |system: &mut System<(Health,)>| {
+            for (_, health) in system.iter_mut() {
+                health.0 += 1;
+            }
+        });
+        world.add_system(Stage::Update, |system: &mut System<(Mana,)>| {
+            let count = system.iter().count() as u32;
+            system.resource_mut::<Ticks>().expect("Ticks were not inserted!").0 += count;
+        }).writes_resource::<Ticks>();
+        world.update();
+        world.update();
+
+        assert_eq!(world.resource::<Ticks>().map(|ticks| ticks.0), Some(6));
+        let system = System::<(Health,)>::new(storage);
+        assert!(system.iter().all(|(_, health)| health.0 == 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "did not declare it")]
+    fn undeclared_resources_panic() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        world.insert_resource(Ticks::default());
+        world.add_system(Stage::Update, |system: &System<(Health,)>| {
+            system.resource::<Ticks>();
+        });
+        world.update();
+    }
+
+    #[test]
+    #[should_panic(expected = "Cyclic system ordering")]
+    fn ordering_cycles_panic() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        let first = world.add_system(Stage::Update, |_: &System<(Health,)>| {}).id();
+        let second = world.add_system(Stage::Update, |_: &System<(Health,)>| {}).after(first).id();
+        world.add_system(Stage::Update, |_: &System<(Mana,)>| {}).after(second).before(first);
+        world.update();
+    }
+}
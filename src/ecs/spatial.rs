@@ -0,0 +1,510 @@
+use std::any::TypeId;
+use hashbrown::{HashMap, HashSet};
+use crate::ecs::{Component, StorageType};
+use crate::ecs::entity::EntityType;
+use crate::ecs::hierarchy::GlobalTransform;
+use crate::ecs::mem::storage::ComponentStorage;
+use crate::ecs::query::is_newer;
+use crate::math::vec::Vec2;
+use crate::rendering::Transform;
+
+/// An axis aligned box. As a component, it is relative to the position of its entity and makes the entity take up
+/// space in the [`SpatialIndex`], so it can be hit by raycasts and found by range queries that only overlap it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl Component for Aabb {}
+
+impl Aabb {
+    pub fn new(min: Vec2, max: Vec2) -> Self {
+        Self { min, max }
+    }
+
+    /// A box of `width` by `height` centered around zero.
+    pub fn centered(width: f32, height: f32) -> Self {
+        Self::new(Vec2::new(-width / 2.0, -height / 2.0), Vec2::new(width / 2.0, height / 2.0))
+    }
+
+    pub fn point(point: Vec2) -> Self {
+        Self::new(point, point)
+    }
+
+    pub fn translated(&self, by: Vec2) -> Self {
+        Self::new(Vec2::new(self.min.x + by.x, self.min.y + by.y), Vec2::new(self.max.x + by.x, self.max.y + by.y))
+    }
+
+    pub fn contains(&self, point: Vec2) -> bool {
+        point.x >= self.min.x && point.x <= self.max.x && point.y >= self.min.y && point.y <= self.max.y
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x && self.max.x >= other.min.x && self.min.y <= other.max.y && self.max.y >= other.min.y
+    }
+
+    /// The distance from `point` to the closest point of the box, zero if the box contains it.
+    pub fn distance(&self, point: Vec2) -> f32 {
+        let dx = (self.min.x - point.x).max(point.x - self.max.x).max(0.0);
+        let dy = (self.min.y - point.y).max(point.y - self.max.y).max(0.0);
+        dx.hypot(dy)
+    }
+
+    /// The distance along the normalized `direction` at which the ray from `origin` enters the box, zero if it starts
+    /// inside.
+    pub fn ray_distance(&self, origin: Vec2, direction: Vec2) -> Option<f32> {
+        let mut near = f32::NEG_INFINITY;
+        let mut far = f32::INFINITY;
+        for (o, d, min, max) in [(origin.x, direction.x, self.min.x, self.max.x), (origin.y, direction.y, self.min.y, self.max.y)] {
+            if d == 0.0 {
+                if o < min || o > max {
+                    return None;
+                }
+            } else {
+                let (a, b) = ((min - o) / d, (max - o) / d);
+                near = near.max(a.min(b));
+                far = far.min(a.max(b));
+            }
+        }
+        (far >= near.max(0.0)).then_some(near.max(0.0))
+    }
+}
+
+/// The closest entity hit by [`SpatialIndex::raycast`].
+#[derive(Copy, Clone, Debug)]
+pub struct RayHit {
+    pub entity: EntityType,
+    pub distance: f32,
+    pub point: Vec2,
+}
+
+type Cell = (i32, i32);
+
+/// Entries whose bounds span more cells than this are not put into cells, but checked by every query. This keeps
+/// inserting huge or infinite boxes from walking billions of cells.
+const MAX_ENTRY_CELLS: i64 = 1024;
+
+struct Entry {
+    position: Vec2,
+    bounds: Aabb,
+    solid: bool,
+    min_cell: Cell,
+    max_cell: Cell,
+    oversized: bool,
+}
+
+/// A uniform grid of every entity with a [`Transform`], added to a world with
+/// [`World::add_spatial_index`](crate::ecs::world::World::add_spatial_index) and available as a resource.
+///
+/// The position of an entity is the translation of its [`GlobalTransform`], or of its [`Transform`] before the first
+/// update. Entities with an [`Aabb`] occupy every cell their box overlaps, all others only the cell of their
+/// position. The index is synced at the end of every update, entities that are despawned or lose their [`Transform`]
+/// are removed right away.
+pub struct SpatialIndex {
+    cell_size: f32,
+    cells: HashMap<Cell, Vec<EntityType>>,
+    entries: HashMap<EntityType, Entry>,
+    oversized: Vec<EntityType>,
+    last_sync: u32,
+}
+
+impl SpatialIndex {
+    pub fn new(cell_size: f32) -> Self {
+        assert!(cell_size > 0.0, "Cell size of a spatial index has to be positive!");
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+            entries: HashMap::new(),
+            oversized: Vec::new(),
+            last_sync: 0,
+        }
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, entity: EntityType) -> bool {
+        self.entries.contains_key(&entity)
+    }
+
+    pub fn position(&self, entity: EntityType) -> Option<Vec2> {
+        self.entries.get(&entity).map(|entry| entry.position)
+    }
+
+    /// The box `entity` occupies in world space, a single point for entities without an [`Aabb`].
+    pub fn bounds(&self, entity: EntityType) -> Option<Aabb> {
+        self.entries.get(&entity).map(|entry| entry.bounds)
+    }
+
+    /// Adds `entity` at `position` or moves it there. `aabb` is relative to `position`.
+    pub fn insert(&mut self, entity: EntityType, position: Vec2, aabb: Option<Aabb>) {
+        let bounds = aabb.map_or(Aabb::point(position), |aabb| aabb.translated(position));
+        let (min_cell, max_cell) = (self.cell_of(bounds.min), self.cell_of(bounds.max));
+        let finite = [bounds.min.x, bounds.min.y, bounds.max.x, bounds.max.y].iter().all(|v| v.is_finite());
+        let oversized = !finite || cell_count(min_cell, max_cell) > MAX_ENTRY_CELLS;
+        if let Some(entry) = self.entries.get_mut(&entity) {
+            entry.position = position;
+            entry.bounds = bounds;
+            entry.solid = aabb.is_some();
+            if entry.oversized == oversized && (oversized || (entry.min_cell, entry.max_cell) == (min_cell, max_cell)) {
+                (entry.min_cell, entry.max_cell) = (min_cell, max_cell);
+                return;
+            }
+        }
+        self.remove(entity);
+        if oversized {
+            self.oversized.push(entity);
+        } else {
+            for_cells(min_cell, max_cell, |cell| self.cells.entry(cell).or_default().push(entity));
+        }
+        self.entries.insert(entity, Entry {
+            position,
+            bounds,
+            solid: aabb.is_some(),
+            min_cell,
+            max_cell,
+            oversized,
+        });
+    }
+
+    pub fn remove(&mut self, entity: EntityType) -> bool {
+        let Some(entry) = self.entries.remove(&entity) else { return false; };
+        if entry.oversized {
+            self.oversized.retain(|e| *e != entity);
+            return true;
+        }
+        for_cells(entry.min_cell, entry.max_cell, |cell| {
+            if let Some(entities) = self.cells.get_mut(&cell) {
+                entities.retain(|e| *e != entity);
+                if entities.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        });
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.entries.clear();
+        self.oversized.clear();
+    }
+
+    /// Every entity whose bounds overlap `rect`.
+    pub fn query_rect(&self, rect: Aabb) -> Vec<EntityType> {
+        self.query_area(rect, |bounds| bounds.intersects(&rect))
+    }
+
+    /// Every entity whose bounds are at most `radius` away from `center`.
+    pub fn query_circle(&self, center: Vec2, radius: f32) -> Vec<EntityType> {
+        let rect = Aabb::new(Vec2::new(center.x - radius, center.y - radius), Vec2::new(center.x + radius, center.y + radius));
+        self.query_area(rect, |bounds| bounds.distance(center) <= radius)
+    }
+
+    /// Every entity whose bounds contain `point`.
+    pub fn query_point(&self, point: Vec2) -> Vec<EntityType> {
+        self.query_rect(Aabb::point(point))
+    }
+
+    /// The `k` entities whose bounds are closest to `point`, along with their distance, closest first.
+    pub fn nearest(&self, point: Vec2, k: usize) -> Vec<(EntityType, f32)> {
+        let mut best = Vec::with_capacity(k + 1);
+        if k == 0 {
+            return best;
+        }
+        let mut seen = HashSet::new();
+        let mut visit = |entity: EntityType, best: &mut Vec<(EntityType, f32)>| {
+            if seen.insert(entity) {
+                let distance = self.entries[&entity].bounds.distance(point);
+                let idx = best.partition_point(|(_, d)| *d <= distance);
+                if idx < k {
+                    best.insert(idx, (entity, distance));
+                    best.truncate(k);
+                }
+            }
+            seen.len()
+        };
+
+        let mut visited = 0;
+        for entity in &self.oversized {
+            visited = visit(*entity, &mut best);
+        }
+        let center = self.cell_of(point);
+        for ring in 0.. {
+            // Every cell outside the rings visited so far is at least `ring - 1` cells away from the point.
+            if visited == self.entries.len() || (best.len() == k && best[k - 1].1 <= (ring - 1) as f32 * self.cell_size) {
+                return best;
+            }
+            let side = 2 * ring as usize + 1;
+            if side * side > 4 * self.cells.len() + 9 {
+                break;
+            }
+            for_ring(center, ring, |cell| {
+                for entity in self.cells.get(&cell).into_iter().flatten() {
+                    visited = visit(*entity, &mut best);
+                }
+            });
+        }
+        // The remaining entities are too spread out for walking the rings to pay off.
+        for entity in self.entries.keys() {
+            visit(*entity, &mut best);
+        }
+        best
+    }
+
+    /// Casts a ray from `origin` along `direction` and returns the closest entity with an [`Aabb`] it hits within
+    /// `max_distance`. Entities without an [`Aabb`] are ignored.
+    pub fn raycast(&self, origin: Vec2, direction: Vec2, max_distance: f32) -> Option<RayHit> {
+        let length = direction.x.hypot(direction.y);
+        if length == 0.0 || max_distance < 0.0 {
+            return None;
+        }
+        let direction = Vec2::new(direction.x / length, direction.y / length);
+        let test = |entity: &EntityType, best: &mut Option<(EntityType, f32)>| {
+            let entry = &self.entries[entity];
+            if !entry.solid {
+                return;
+            }
+            if let Some(distance) = entry.bounds.ray_distance(origin, direction) {
+                if distance <= max_distance && best.is_none_or(|(_, d)| distance < d) {
+                    *best = Some((*entity, distance));
+                }
+            }
+        };
+        let hit = |(entity, distance): (EntityType, f32)| RayHit {
+            entity,
+            distance,
+            point: Vec2::new(origin.x + direction.x * distance, origin.y + direction.y * distance),
+        };
+
+        let mut best = None;
+        self.oversized.iter().for_each(|entity| test(entity, &mut best));
+        let steps = (direction.x.abs() + direction.y.abs()) * max_distance / self.cell_size;
+        if !steps.is_finite() || steps > self.cells.len() as f32 {
+            // Walking the cells along the ray would visit more cells than there are occupied ones.
+            self.entries.keys().for_each(|entity| test(entity, &mut best));
+            return best.map(hit);
+        }
+
+        // Walks the cells along the ray, see "A Fast Voxel Traversal Algorithm for Ray Tracing" by Amanatides & Woo.
+        let (mut x, mut y) = self.cell_of(origin);
+        let axis = |o: f32, d: f32, cell: i32| -> (i32, f32, f32) {
+            if d > 0.0 {
+                (1, ((cell + 1) as f32 * self.cell_size - o) / d, self.cell_size / d)
+            } else if d < 0.0 {
+                (-1, (cell as f32 * self.cell_size - o) / d, -self.cell_size / d)
+            } else {
+                (0, f32::INFINITY, f32::INFINITY)
+            }
+        };
+        let (step_x, mut next_x, delta_x) = axis(origin.x, direction.x, x);
+        let (step_y, mut next_y, delta_y) = axis(origin.y, direction.y, y);
+        loop {
+            self.cells.get(&(x, y)).into_iter().flatten().for_each(|entity| test(entity, &mut best));
+            let exit = next_x.min(next_y);
+            if best.is_some_and(|(_, d)| d <= exit) || exit > max_distance {
+                return best.map(hit);
+            }
+            if next_x < next_y {
+                x += step_x;
+                next_x += delta_x;
+            } else {
+                y += step_y;
+                next_y += delta_y;
+            }
+        }
+    }
+
+    fn cell_of(&self, point: Vec2) -> Cell {
+        ((point.x / self.cell_size).floor() as i32, (point.y / self.cell_size).floor() as i32)
+    }
+
+    fn query_area(&self, rect: Aabb, matches: impl Fn(&Aabb) -> bool) -> Vec<EntityType> {
+        let (min, max) = (self.cell_of(rect.min), self.cell_of(rect.max));
+        let mut found = Vec::new();
+        if cell_count(min, max) > self.cells.len() as i64 {
+            found.extend(self.entries.iter().filter(|(_, entry)| matches(&entry.bounds)).map(|(entity, _)| *entity));
+            return found;
+        }
+        found.extend(self.oversized.iter().filter(|entity| matches(&self.entries[*entity].bounds)));
+        for_cells(min, max, |cell| {
+            for entity in self.cells.get(&cell).into_iter().flatten() {
+                let entry = &self.entries[entity];
+                // Entities spanning several cells are only reported by the first cell the area shares with them.
+                if cell == (entry.min_cell.0.max(min.0), entry.min_cell.1.max(min.1)) && matches(&entry.bounds) {
+                    found.push(*entity);
+                }
+            }
+        });
+        found
+    }
+
+    /// Reinserts every entity whose [`Transform`], [`GlobalTransform`] or [`Aabb`] changed since the last sync.
+    pub(crate) fn sync(&mut self, storage: &ComponentStorage) {
+        let this_run = storage.next_tick();
+        let types = [TypeId::of::<Transform>(), TypeId::of::<GlobalTransform>(), TypeId::of::<Aabb>()];
+        let sparse = storage.storage_type(types[0]) == StorageType::SparseSet;
+        for archetype in storage.archetypes() {
+            if !sparse && !archetype.has(types[0]) {
+                continue;
+            }
+            for entity in archetype.entities() {
+                let changed = types.iter().any(|ty| {
+                    storage.changed_tick(*entity, *ty).is_some_and(|tick| is_newer(tick, self.last_sync, this_run))
+                });
+                if changed {
+                    self.refresh(storage, *entity);
+                }
+            }
+        }
+        self.last_sync = this_run;
+    }
+
+    /// Reinserts `entity`, or removes it if it no longer has a [`Transform`].
+    pub(crate) fn refresh(&mut self, storage: &ComponentStorage, entity: EntityType) {
+        let position = match storage.get_component::<GlobalTransform>(entity) {
+            Some(global) if storage.has_component::<Transform>(entity) => Some(global.translation),
+            _ => storage.get_component::<Transform>(entity).map(|transform| transform.translation),
+        };
+        match position {
+            Some(position) => self.insert(entity, position, storage.get_component::<Aabb>(entity).copied()),
+            None => {
+                self.remove(entity);
+            }
+        }
+    }
+}
+
+fn cell_count(min: Cell, max: Cell) -> i64 {
+    (i64::from(max.0) - i64::from(min.0) + 1) * (i64::from(max.1) - i64::from(min.1) + 1)
+}
+
+fn for_cells(min: Cell, max: Cell, mut f: impl FnMut(Cell)) {
+    for x in min.0..=max.0 {
+        for y in min.1..=max.1 {
+            f((x, y));
+        }
+    }
+}
+
+fn for_ring(center: Cell, ring: i32, mut f: impl FnMut(Cell)) {
+    if ring == 0 {
+        f(center);
+        return;
+    }
+    for x in center.0 - ring..=center.0 + ring {
+        f((x, center.1 - ring));
+        f((x, center.1 + ring));
+    }
+    for y in center.1 - ring + 1..center.1 + ring {
+        f((center.0 - ring, y));
+        f((center.0 + ring, y));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::entity::{Entity, EntityType, NoBehavior};
+    use crate::ecs::spatial::{Aabb, SpatialIndex};
+    use crate::ecs::world::World;
+    use crate::math::vec::Vec2;
+    use crate::rendering::Transform;
+
+    fn spawn(world: &mut World, count: usize) -> Vec<EntityType> {
+        (0..count).map(|_| world.create_entity(Entity::<NoBehavior, ()>::new).expect("Entity could not be created")).collect()
+    }
+
+    fn sorted(mut entities: Vec<EntityType>) -> Vec<EntityType> {
+        entities.sort();
+        entities
+    }
+
+    fn transform(x: f32, y: f32) -> Transform {
+        let mut transform = Transform::new();
+        transform.translation = Vec2::new(x, y);
+        transform
+    }
+
+    #[test]
+    fn queries_find_points_and_boxes() {
+        let mut world = World::new();
+        let entities = spawn(&mut world, 3);
+        let (point, boxed, far) = (entities[0], entities[1], entities[2]);
+        let mut index = SpatialIndex::new(5.0);
+        index.insert(point, Vec2::new(0.0, 0.0), None);
+        index.insert(boxed, Vec2::new(10.0, 0.0), Some(Aabb::centered(4.0, 4.0)));
+        index.insert(far, Vec2::new(100.0, 100.0), None);
+        assert_eq!(index.len(), 3);
+
+        assert_eq!(sorted(index.query_rect(Aabb::new(Vec2::new(-1.0, -1.0), Vec2::new(9.0, 1.0)))), sorted(vec![point, boxed]));
+        assert_eq!(index.query_circle(Vec2::new(10.0, 3.0), 1.5), [boxed]);
+        assert_eq!(index.query_point(Vec2::new(11.0, 1.0)), [boxed]);
+        assert_eq!(index.nearest(Vec2::new(1.0, 0.0), 2), [(point, 1.0), (boxed, 7.0)]);
+
+        let hit = index.raycast(Vec2::new(0.0, 0.0), Vec2::new(2.0, 0.0), 20.0).expect("Ray missed the box");
+        assert_eq!((hit.entity, hit.distance, hit.point), (boxed, 8.0, Vec2::new(8.0, 0.0)));
+        assert!(index.raycast(Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), 5.0).is_none());
+
+        assert!(index.remove(boxed));
+        assert!(index.query_point(Vec2::new(11.0, 1.0)).is_empty());
+        assert!(index.raycast(Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), 20.0).is_none());
+        assert_eq!(index.nearest(Vec2::new(1.0, 0.0), 5), [(point, 1.0), (far, 99.0f32.hypot(100.0))]);
+    }
+
+    #[test]
+    fn huge_boxes_are_found_without_filling_cells() {
+        let mut world = World::new();
+        let entities = spawn(&mut world, 2);
+        let (infinite, huge) = (entities[0], entities[1]);
+        let mut index = SpatialIndex::new(1.0);
+        index.insert(infinite, Vec2::new(0.0, 0.0), Some(Aabb::new(Vec2::new(0.0, 0.0), Vec2::new(f32::INFINITY, 1.0))));
+        index.insert(huge, Vec2::new(0.0, 0.0), Some(Aabb::centered(1e9, 1e9)));
+        assert!(index.cells.is_empty());
+
+        assert_eq!(sorted(index.query_point(Vec2::new(1e6, 0.5))), sorted(vec![infinite, huge]));
+        assert_eq!(index.query_circle(Vec2::new(0.0, -1e8), 1.0), [huge]);
+        assert_eq!(index.nearest(Vec2::new(-1e8, 0.0), 1), [(huge, 0.0)]);
+        let hit = index.raycast(Vec2::new(1e8, 0.5), Vec2::new(0.0, -1.0), 1.0).expect("Ray missed the boxes");
+        assert_eq!(hit.distance, 0.0);
+
+        index.insert(huge, Vec2::new(0.0, 0.0), None);
+        assert!(index.oversized.len() == 1 && index.cells.len() == 1);
+        index.clear();
+        assert!(index.is_empty() && index.oversized.is_empty());
+    }
+
+    #[test]
+    fn world_keeps_the_index_in_sync() {
+        let mut world = World::new();
+        world.add_spatial_index(5.0);
+        let moving = world.create_entity(Entity::<NoBehavior, (Transform,)>::new).expect("Entity could not be created");
+        let other = world.create_entity(Entity::<NoBehavior, (Transform,)>::new).expect("Entity could not be created");
+        world.insert_component(moving, transform(3.0, 3.0));
+        world.insert_component(moving, Aabb::centered(2.0, 2.0));
+        world.update();
+        let index = world.spatial_index().expect("Index was not added");
+        assert_eq!(index.position(moving), Some(Vec2::new(3.0, 3.0)));
+        assert_eq!(index.bounds(moving), Some(Aabb::new(Vec2::new(2.0, 2.0), Vec2::new(4.0, 4.0))));
+        assert!(index.contains(other));
+
+        world.insert_component(moving, transform(20.0, 0.0));
+        world.update();
+        assert_eq!(world.spatial_index().and_then(|index| index.position(moving)), Some(Vec2::new(20.0, 0.0)));
+
+        world.remove_component::<Aabb>(moving);
+        assert_eq!(world.spatial_index().and_then(|index| index.bounds(moving)), Some(Aabb::point(Vec2::new(20.0, 0.0))));
+        world.despawn(moving);
+        world.remove_component::<Transform>(other);
+        assert!(world.spatial_index().is_some_and(SpatialIndex::is_empty));
+    }
+}
@@ -1,166 +1,236 @@
 use crate::ecs::EcsStorage;
+use std::any::{type_name, TypeId};
+use std::cell::Cell;
 use std::marker::PhantomData;
-use std::mem;
+use crate::ecs::borrow::Borrow;
+use crate::ecs::command::Commands;
 use crate::ecs::entity::EntityType;
-
-pub struct System<C> {
-    phantom: PhantomData<C>,
+use crate::ecs::mem::storage::ComponentStorage;
+use crate::ecs::query::{clamp_tick, Filter, Query};
+use crate::ecs::resource::Resource;
+use crate::ecs::schedule::Access;
+
+/// Iterates all entities that have the components `C` and pass the filters `F`, e.g.
+/// `System<(Transform, Option<Sprite>), (Without<Dead>, Changed<Transform>)>`.
+///
+/// [`Added`](crate::ecs::query::Added) and [`Changed`](crate::ecs::query::Changed) are relative to the previous
+/// call of [`System::iter`] or [`System::iter_mut`] on the same system.
+///
+/// [`System::iter`] and [`System::iter_mut`] borrow the components of `C` and return a guard that is iterated by
+/// reference, e.g. `for (entity, transform) in system.iter_mut()`. Every item borrows from the guard, so the
+/// components stay borrowed as long as any item is alive, and borrowing `Transform` mutably through another system or
+/// an [`Entity`](crate::ecs::entity::Entity) in the meantime panics instead of aliasing.
+pub struct System<C, F = ()> {
+    phantom: PhantomData<(C, F)>,
     storage: EcsStorage,
+    last_run: Cell<u32>,
+    declared: Option<Access>,
 }
 
-impl<C> System<C> {
+impl<C, F> System<C, F> {
     pub fn new(storage: EcsStorage) -> Self {
         Self {
             phantom: PhantomData::default(),
             storage,
+            last_run: Cell::new(0),
+            declared: None,
         }
     }
-}
 
-pub struct Components<'a, C> {
-    phantom: PhantomData<&'a C>,
-    data: Vec<(EntityType, &'a C)>,
-    index: usize,
-}
+    /// Panics if the system runs in a [`Schedule`](crate::ecs::schedule::Schedule) and did not declare `R` with
+    /// [`SystemConfig::reads_resource`](crate::ecs::schedule::SystemConfig::reads_resource) or
+    /// [`SystemConfig::writes_resource`](crate::ecs::schedule::SystemConfig::writes_resource).
+    pub fn resource<R: Resource>(&self) -> Option<&R> {
+        self.check_declared::<R>(false);
+        self.storage.get().resource::<R>()
+    }
 
-pub struct ComponentsMut<'a, C> {
-    phantom: PhantomData<&'a mut C>,
-    data: Vec<(EntityType, &'a mut C)>,
-    index: usize,
-}
+    /// Panics if the system runs in a [`Schedule`](crate::ecs::schedule::Schedule) and did not declare `R` with
+    /// [`SystemConfig::writes_resource`](crate::ecs::schedule::SystemConfig::writes_resource).
+    pub fn resource_mut<R: Resource>(&mut self) -> Option<&mut R> {
+        self.check_declared::<R>(true);
+        // SAFETY: a scheduled system declared the write, so the schedule runs nothing that accesses `R` alongside it.
+        // Outside a schedule the system is used from the main thread like every other handle.
+        unsafe { self.storage.get().resources().get_unchecked_mut::<R>() }
+    }
 
-impl<'a, C> Iterator for Components<'a, C> {
-    type Item = (EntityType, &'a C);
+    /// Sets the access the schedule runs the system with.
+    pub(crate) fn declare(&mut self, access: Access) {
+        self.declared = Some(access);
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.index < self.data.len() {
-            let (entity, component) = &self.data[self.index];
-            self.index += 1;
-            Some((*entity, component))
-        } else {
-            None
+    fn check_declared<R: Resource>(&self, write: bool) {
+        if let Some(access) = &self.declared {
+            let ty = TypeId::of::<R>();
+            let declared = access.is_exclusive()
+                || access.resource_writes().contains(&ty)
+                || (!write && access.resource_reads().contains(&ty));
+            assert!(declared, "Resource {} is accessed by a system that did not declare it with {}!", type_name::<R>(),
+                if write { "SystemConfig::writes_resource" } else { "SystemConfig::reads_resource" });
         }
     }
-}
 
-impl<'a, C> Iterator for ComponentsMut<'a, C> {
-    type Item = (EntityType, &'a mut C);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.index < self.data.len() {
-            let (entity, component) = &mut self.data[self.index];
-            self.index += 1;
-            unsafe { Some((*entity, mem::transmute::<_, &'a mut C>(component))) }
-        } else {
-            None
-        }
+    /// Spawning, despawning and adding or removing components while iterating has to go through [`Commands`].
+    pub fn commands(&self) -> Commands {
+        Commands::new(self.storage.clone())
     }
 }
 
-impl<C: Sized + 'static> System<(C,)> {
-    pub fn iter(&self) -> Components<'_, C> {
-        let data = self.storage.get().get_components1::<C>().unwrap_or_default();
+impl<C: Query, F: Filter> System<C, F> {
+    /// Borrows the components immutably until the returned iterator is dropped. Panics if one of them is borrowed
+    /// mutably.
+    pub fn iter(&self) -> Components<'_, C, F> {
+        let storage = self.storage.get();
+        let this_run = storage.next_tick();
         Components {
-            data,
-            index: 0,
-            phantom: PhantomData,
+            _borrow: storage.borrow(&Self::access(false)),
+            cursor: Cursor::new(storage, clamp_tick(self.last_run.replace(this_run), this_run), this_run),
         }
     }
 
-    pub fn iter_mut(&mut self) -> ComponentsMut<'_, C> {
-        let data = self.storage.get_mut().get_components1_mut::<C>().unwrap_or_default();
+    /// Borrows the components mutably until the returned iterator is dropped. Every yielded component is marked as
+    /// changed. Panics if one of them is borrowed.
+    pub fn iter_mut(&mut self) -> ComponentsMut<'_, C, F> {
+        let storage = self.storage.get();
+        let this_run = storage.next_tick();
         ComponentsMut {
-            data,
-            index: 0,
-            phantom: PhantomData,
+            _borrow: storage.borrow(&Self::access(true)),
+            cursor: Cursor::new(storage, clamp_tick(self.last_run.replace(this_run), this_run), this_run),
         }
     }
+
+    fn access(mutable: bool) -> Access {
+        let mut access = Access::default();
+        C::access(&mut access, mutable);
+        F::access(&mut access);
+        access
+    }
 }
 
-macro_rules! impl_system_tuples {
-    ($($name:ident)*, $method:ident, $method_mut:ident, $iterator:ident, $iterator_mut:ident) => {
-        pub struct $iterator<'a, $($name: Sized + 'static),*> {
-            data: Vec<(EntityType, $(&'a $name),*)>,
-            index: usize,
-            _marker: PhantomData<&'a ($($name),*)>,
-        }
+pub(crate) struct Cursor<'a, C: Query, F: Filter> {
+    storage: &'a ComponentStorage,
+    next_archetype: usize,
+    columns: Option<(C::Columns, F::Columns)>,
+    entities: &'a [EntityType],
+    row: usize,
+    last_run: u32,
+    pub(crate) this_run: u32,
+}
 
-        pub struct $iterator_mut<'a, $($name: Sized + 'static),*> {
-            data: Vec<(EntityType, $(&'a mut $name),*)>,
-            index: usize,
-            _marker: PhantomData<&'a mut ($($name),*)>,
+impl<'a, C: Query, F: Filter> Cursor<'a, C, F> {
+    /// The caller has to hold a [`Borrow`] of the components of `C` and `F` for as long as the cursor and the items
+    /// fetched through it are alive.
+    pub(crate) fn new(storage: &'a ComponentStorage, last_run: u32, this_run: u32) -> Self {
+        Self {
+            storage,
+            next_archetype: 0,
+            columns: None,
+            entities: &[],
+            row: 0,
+            last_run,
+            this_run,
         }
+    }
 
-        impl<'a, $($name: Sized + 'static),*> Iterator for $iterator<'a, $($name),*> {
-            type Item = (EntityType, $(&'a $name),*);
+    fn advance(&mut self) -> bool {
+        while let Some(archetype) = self.storage.archetypes().get(self.next_archetype) {
+            self.next_archetype += 1;
+            if !archetype.is_empty() && C::matches(archetype, self.storage) && F::matches(archetype, self.storage) {
+                self.columns = Some(unsafe { (C::columns(archetype, self.storage), F::columns(archetype, self.storage)) });
+                self.entities = archetype.entities();
+                self.row = 0;
+                return true;
+            }
+        }
+        false
+    }
 
-            fn next(&mut self) -> Option<Self::Item> {
-                if self.index < self.data.len() {
-                    let (entity, $($name),*) = &self.data[self.index];
-                    self.index += 1;
-                    Some((*entity, $($name),*))
-                } else {
-                    None
+    /// Returns the next row that passes the filters, along with its entity and columns.
+    pub(crate) fn next_row(&mut self) -> Option<(EntityType, C::Columns, usize)> {
+        loop {
+            while self.row < self.entities.len() {
+                let row = self.row;
+                self.row += 1;
+                if let Some((columns, filters)) = self.columns {
+                    let entity = self.entities[row];
+                    if unsafe { F::filter(filters, entity, row, self.last_run, self.this_run) } {
+                        return Some((entity, columns, row));
+                    }
                 }
             }
+            if !self.advance() {
+                return None;
+            }
         }
+    }
+}
 
-        impl<'a, $($name: Sized + 'static),*> Iterator for $iterator_mut<'a, $($name),*> {
-            type Item = (EntityType, $(&'a mut $name),*);
+/// Iterates the components of a [`System`] immutably, returned by [`System::iter`]. Walks all archetypes that match
+/// `C` and `F`, yielding one item per row. The components stay borrowed until the iterator is dropped, the items
+/// borrow the system itself, like the ones of a [`QueryIter`](crate::ecs::param::QueryIter).
+pub struct Components<'a, C: Query, F: Filter = ()> {
+    cursor: Cursor<'a, C, F>,
+    _borrow: Borrow<'a>,
+}
 
-            fn next(&mut self) -> Option<Self::Item> {
-                if self.index < self.data.len() {
-                    let (entity, $($name),*) = &mut self.data[self.index];
-                    self.index += 1;
-                    unsafe { Some((*entity, $(mem::transmute::<_, &mut $name>($name)),*)) }
-                } else {
-                    None
-                }
+/// Iterates the components of a [`System`] mutably, returned by [`System::iter_mut`].
+pub struct ComponentsMut<'a, C: Query, F: Filter = ()> {
+    cursor: Cursor<'a, C, F>,
+    _borrow: Borrow<'a>,
+}
+
+impl<'a, C: Query, F: Filter> Iterator for Components<'a, C, F> {
+    type Item = C::Item<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (entity, columns, row) = self.cursor.next_row()?;
+            if let Some(item) = unsafe { C::fetch(entity, columns, row) } {
+                return Some(item);
             }
         }
+    }
+}
 
-        impl<$($name: Sized + 'static),*> System<($($name),*)> {
-            pub fn iter(&self) -> $iterator<'_, $($name),*> {
-                let data = self
-                    .storage
-                    .get()
-                    .$method::<$($name),*>()
-                    .unwrap_or_default();
-                $iterator {
-                    data,
-                    index: 0,
-                    _marker: PhantomData,
-                }
-            }
+impl<'a, C: Query, F: Filter> Iterator for ComponentsMut<'a, C, F> {
+    type Item = C::ItemMut<'a>;
 
-            pub fn iter_mut(&mut self) -> $iterator_mut<'_, $($name),*> {
-                let data = self
-                    .storage
-                    .get_mut()
-                    .$method_mut::<$($name),*>()
-                    .unwrap_or_default();
-                $iterator_mut {
-                    data,
-                    index: 0,
-                    _marker: PhantomData,
-                }
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (entity, columns, row) = self.cursor.next_row()?;
+            if let Some(item) = unsafe { C::fetch_mut(entity, columns, row, self.cursor.this_run) } {
+                return Some(item);
             }
         }
-    };
+    }
 }
 
-//impl_system_tuples!(C1, get_components1, get_components1_mut, Components1, Components1Mut);
-impl_system_tuples!(C1 C2, get_components2, get_components2_mut, Components2, Components2Mut);
-impl_system_tuples!(C1 C2 C3, get_components3, get_components3_mut, Components3, Components3Mut);
-impl_system_tuples!(C1 C2 C3 C4, get_components4, get_components4_mut, Components4, Components4Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5, get_components5, get_components5_mut, Components5, Components5Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6, get_components6, get_components6_mut, Components6, Components6Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7, get_components7, get_components7_mut, Components7, Components7Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7 C8, get_components8, get_components8_mut, Components8, Components8Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9, get_components9, get_components9_mut, Components9, Components9Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10, get_components10, get_components10_mut, Components10, Components10Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11, get_components11, get_components11_mut, Components11, Components11Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11 C12, get_components12, get_components12_mut, Components12, Components12Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11 C12 C13, get_components13, get_components13_mut, Components13, Components13Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11 C12 C13 C14, get_components14, get_components14_mut, Components14, Components14Mut);
-impl_system_tuples!(C1 C2 C3 C4 C5 C6 C7 C8 C9 C10 C11 C12 C13 C14 C15, get_components15, get_components15_mut, Components15, Components15Mut);
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use crate::ecs::ECS;
+    use crate::ecs::system::System;
+    use crate::ecs::testing::{Health, Mana, Name};
+
+    #[test]
+    fn systems_visit_every_archetype_with_their_components() {
+        let ecs = ECS::new();
+        let storage = ecs.storage();
+        let entities: Vec<_> = (0..4).map(|_| storage.get_mut().spawn()).collect();
+        for (i, entity) in entities.iter().enumerate() {
+            storage.get_mut().set_component(*entity, Health(i as u32));
+        }
+        storage.get_mut().set_component(entities[1], Mana(1));
+        storage.get_mut().set_component(entities[2], Mana(2));
+        storage.get_mut().set_component(entities[2], Name::new("named"));
+
+        let mut system = System::<(Health, Mana)>::new(ecs.storage());
+        for (_, health, mana) in system.iter_mut() {
+            health.0 += mana.0 * 10;
+        }
+        let mut found: Vec<_> = system.iter().map(|(entity, health, _)| (entity, health.0)).collect();
+        found.sort();
+        assert_eq!(found, [(entities[1], 11), (entities[2], 22)]);
+        assert_eq!(System::<(Health,)>::new(ecs.storage()).iter().count(), 4);
+        assert_eq!(System::<(Name, Health)>::new(ecs.storage()).iter().count(), 1);
+    }
+}
@@ -0,0 +1,68 @@
+//! Components and behaviors shared by the unit tests of the ECS.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::ecs::{Component, EcsStorage};
+use crate::ecs::entity::{BehaviorContext, EntityBehavior, EntityType};
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub(crate) struct Health(pub(crate) u32);
+impl Component for Health {}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub(crate) struct Mana(pub(crate) u32);
+impl Component for Mana {}
+
+/// A marker without data.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub(crate) struct Tag;
+impl Component for Tag {}
+
+/// Counts how often it was dropped.
+pub(crate) struct Tracked(pub(crate) Arc<AtomicUsize>);
+impl Component for Tracked {}
+
+impl Tracked {
+    pub(crate) fn counter() -> Arc<AtomicUsize> {
+        Arc::new(AtomicUsize::new(0))
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub(crate) struct Name(pub(crate) String);
+impl Component for Name {}
+
+impl Name {
+    pub(crate) fn new(name: &str) -> Self {
+        Self(name.to_string())
+    }
+}
+
+/// Adds one to the health of its entity every update.
+pub(crate) struct Regeneration {
+    storage: EcsStorage,
+}
+
+impl EntityBehavior for Regeneration {
+    fn new(storage: EcsStorage) -> Self {
+        Self { storage }
+    }
+
+    fn start(&mut self, _entity: EntityType, _ctx: &mut BehaviorContext) {}
+
+    fn update(&mut self, entity: EntityType, _ctx: &mut BehaviorContext) {
+        if let Some(health) = self.storage.get_mut().get_component_mut::<Health>(entity) {
+            health.0 += 1;
+        }
+    }
+
+    fn rebind(&mut self, storage: EcsStorage) {
+        self.storage = storage;
+    }
+}
@@ -1,64 +1,906 @@
-use std::alloc::Layout;
 use std::any::TypeId;
-use std::process::id;
+use std::fmt::Debug;
+use std::sync::Arc;
+use bytebuffer::ByteBuffer;
 use hashbrown::HashMap;
 use mvutils::hashers::U64IdentityHasher;
-use crate::ecs::EcsStorage;
-use crate::ecs::entity::{Entity, EntityBehavior, EntityType, NoBehavior};
+use mvutils::save::Savable;
+use mvutils::unsafe_utils::DangerousCell;
+use crate::ecs::{Component, EcsStorage, StorageType};
+use crate::ecs::hierarchy::{mark_detached, propagate_transforms, Children, Parent};
+use crate::ecs::inspect;
+use crate::ecs::inspect::{BehaviorStats, EntityView, WorldStats};
+use crate::ecs::event::{EventReader, Events};
+use crate::ecs::entity::{BehaviorContext, Entity, EntityBehavior, EntityType, NoBehavior};
+use crate::ecs::command::Commands;
 use crate::ecs::mem::conblob::ContinuousBlob;
+use crate::ecs::prefab::{PrefabComponent, PrefabRegistry};
+use crate::ecs::resource::{Resource, Resources};
+use crate::ecs::mem::storage::ComponentStorage;
+use crate::rendering::Transform;
+use crate::ecs::save::{remap_hierarchy, SaveRegistry};
+use crate::ecs::spatial::{Aabb, SpatialIndex};
+use crate::ecs::schedule::{IntoScheduled, Schedule, Stage, SystemConfig};
+
+/// Behaviors of one type: their packed storage, the vtable metadata to call them through and the entity that owns each
+/// slot.
+struct BehaviorBlob {
+    blob: ContinuousBlob,
+    meta: std::ptr::DynMetadata<dyn EntityBehavior>,
+    owners: Vec<EntityType>,
+    name: &'static str,
+}
+
+impl BehaviorBlob {
+    fn of<B: EntityBehavior + 'static>(behavior: &B) -> Self {
+        Self {
+            blob: ContinuousBlob::of::<B>(),
+            meta: std::ptr::metadata::<dyn EntityBehavior>(behavior),
+            owners: Vec::new(),
+            name: std::any::type_name::<B>(),
+        }
+    }
+
+    fn empty_like(&self) -> Self {
+        Self {
+            blob: self.blob.empty_like(),
+            meta: self.meta,
+            owners: Vec::new(),
+            name: self.name,
+        }
+    }
+
+    fn get_mut(&mut self, idx: usize) -> Option<&mut dyn EntityBehavior> {
+        let ptr = self.blob.get_ptr(idx)?;
+        Some(unsafe { &mut *std::ptr::from_raw_parts_mut::<dyn EntityBehavior>(ptr, self.meta) })
+    }
+}
 
 pub struct World {
     storage: EcsStorage,
-    entities: Vec<EntityType>,
-    behaviors: HashMap<TypeId, (ContinuousBlob, std::ptr::DynMetadata<dyn EntityBehavior>)>,
-    behavior_indices: HashMap<EntityType, usize, U64IdentityHasher>,
-    behavior_indices_rev: HashMap<u64, EntityType, U64IdentityHasher>
+    behaviors: HashMap<TypeId, BehaviorBlob>,
+    behavior_indices: HashMap<EntityType, (TypeId, usize), U64IdentityHasher>,
+    schedule: Schedule,
+    event_updaters: Vec<fn(&mut Resources)>,
+    savables: SaveRegistry,
+    prefabs: PrefabRegistry,
+    last_propagation: u32,
 }
 
 impl World {
-    pub(crate) fn new(storage: EcsStorage) -> Self {
+    /// Creates a world with its own storage, independent of every other world.
+    pub fn new() -> Self {
+        Self::with_storage(Arc::new(DangerousCell::new(ComponentStorage::new())))
+    }
+
+    pub(crate) fn with_storage(storage: EcsStorage) -> Self {
         Self {
-            storage,
-            entities: Vec::new(),
             behaviors: HashMap::new(),
             behavior_indices: HashMap::with_hasher(U64IdentityHasher::default()),
-            behavior_indices_rev: HashMap::with_hasher(U64IdentityHasher::default()),
+            schedule: Schedule::new(storage.clone()),
+            storage,
+            event_updaters: Vec::new(),
+            savables: SaveRegistry::new(),
+            prefabs: PrefabRegistry::new(),
+            last_propagation: 0,
         }
     }
 
+    pub fn storage(&self) -> EcsStorage {
+        self.storage.clone()
+    }
+
+    pub fn is_alive(&self, entity: EntityType) -> bool {
+        self.storage.get().is_alive(entity)
+    }
+
+    /// Wraps the world so it can be sent to another thread, e.g. to build a level off-thread and move its entities
+    /// into the main world afterward with [`World::move_all_to`].
+    ///
+    /// # Safety
+    /// The storage is shared through an [`EcsStorage`] whose cell is not synchronized, so the world is only safe to
+    /// send if it takes every handle to its storage along. The caller has to guarantee that:
+    /// - no handle to the storage of this world is kept outside of it, neither an [`EcsStorage`] nor anything holding
+    ///   one, like an [`Entity`], [`Commands`], [`System`](crate::ecs::system::System),
+    ///   [`LocalComponent`](crate::ecs::entity::LocalComponent) or
+    ///   [`LocalResource`](crate::ecs::resource::LocalResource). Handles held by the world's own behaviors and
+    ///   systems are fine, they move with it.
+    /// - every behavior of the world, and everything captured by its systems, is safe to send to another thread.
+    ///   Components and resources are always `Send + Sync`.
+    pub unsafe fn into_send(self) -> SendWorld {
+        SendWorld(self)
+    }
+
+    /// Swaps the buffers of every [`Events`] channel, then runs the [`Stage::PreUpdate`] systems,
+    /// [`EntityBehavior::update`], the [`Stage::Update`] and [`Stage::PostUpdate`] systems and
+    /// [`EntityBehavior::late_update`]. Recorded [`Commands`] are applied after each of these steps. Finally, the
+    /// transforms of the hierarchy are propagated and the [`SpatialIndex`] is synced.
     pub fn update(&mut self) {
-        for (behavior_blob, meta) in self.behaviors.values_mut() {
-            let mut behaviors = behavior_blob.get_all_traits_mut::<dyn EntityBehavior>(meta);
-            for (idx, behavior) in behaviors.iter_mut().enumerate() {
-                if let Some(en_ty) = self.behavior_indices_rev.get(&(idx as u64)) {
-                    behavior.update(*en_ty);
+        self.storage.get_mut().check_ticks();
+        for updater in &self.event_updaters {
+            updater(self.storage.get_mut().resources_mut());
+        }
+        self.run_stage(Stage::PreUpdate);
+        self.for_each_behavior(|behavior, entity, ctx| behavior.update(entity, ctx));
+        self.apply_commands();
+        self.run_stage(Stage::Update);
+        self.run_stage(Stage::PostUpdate);
+        self.for_each_behavior(|behavior, entity, ctx| behavior.late_update(entity, ctx));
+        self.apply_commands();
+        self.propagate_transforms();
+        self.sync_spatial_index();
+    }
+
+    /// Runs [`EntityBehavior::fixed_update`] of every behavior and applies the recorded [`Commands`]. Meant to be
+    /// called at a fixed rate, with `dt` being the time between two calls in seconds.
+    pub fn fixed_update(&mut self, dt: f32) {
+        self.for_each_behavior(|behavior, entity, ctx| behavior.fixed_update(entity, dt, ctx));
+        self.apply_commands();
+    }
+
+    fn for_each_behavior(&mut self, mut f: impl FnMut(&mut dyn EntityBehavior, EntityType, &mut BehaviorContext)) {
+        let storage = self.storage.clone();
+        let mut ctx = BehaviorContext::new(&storage);
+        for BehaviorBlob { blob, meta, owners, .. } in self.behaviors.values_mut() {
+            for (behavior, entity) in blob.get_all_traits_mut::<dyn EntityBehavior>(meta).into_iter().zip(owners.iter()) {
+                f(behavior, *entity, &mut ctx);
+            }
+        }
+        self.attach_spawned(ctx);
+    }
+
+    /// Stores and starts the behaviors of the entities spawned through `ctx`.
+    fn attach_spawned(&mut self, ctx: BehaviorContext) {
+        for attach in ctx.into_spawned() {
+            attach(self);
+        }
+    }
+
+    /// Recomputes the [`GlobalTransform`](crate::ecs::hierarchy::GlobalTransform) of every entity whose
+    /// [`Transform`] or place in the hierarchy changed since the last call, together with its descendants.
+    pub fn propagate_transforms(&mut self) {
+        self.last_propagation = propagate_transforms(self.storage.get_mut(), self.last_propagation);
+    }
+
+    /// Inserts a [`SpatialIndex`] with cells of `cell_size` by `cell_size` units and keeps it in sync with the
+    /// [`Transform`] and [`Aabb`] of every entity. Replaces the existing index.
+    pub fn add_spatial_index(&mut self, cell_size: f32) {
+        let existed = self.insert_resource(SpatialIndex::new(cell_size)).is_some();
+        if !existed {
+            // The index is updated right away, so despawned entities never show up in a query. Nothing else can reach
+            // the index while the hooks run, see `ComponentStorage::on_remove_with_resources`.
+            let storage = self.storage.get_mut();
+            storage.on_remove_with_resources::<Transform>(|entity, _, resources| {
+                if let Some(index) = unsafe { resources.get_unchecked_mut::<SpatialIndex>() } {
+                    index.remove(entity);
+                }
+            });
+            storage.on_remove_with_resources::<Aabb>(|entity, _, resources| {
+                if let Some(index) = unsafe { resources.get_unchecked_mut::<SpatialIndex>() } {
+                    if let Some(position) = index.position(entity) {
+                        index.insert(entity, position, None);
+                    }
                 }
+            });
+        }
+        self.sync_spatial_index();
+    }
+
+    pub fn spatial_index(&self) -> Option<&SpatialIndex> {
+        self.resource::<SpatialIndex>()
+    }
+
+    fn sync_spatial_index(&mut self) {
+        let storage = self.storage.get_mut();
+        if let Some(mut index) = storage.resources_mut().remove::<SpatialIndex>() {
+            index.sync(storage);
+            storage.resources_mut().insert(index);
+        }
+    }
+
+    /// Runs the [`Stage::Render`] systems.
+    pub fn render(&mut self) {
+        self.run_stage(Stage::Render);
+    }
+
+    fn run_stage(&mut self, stage: Stage) {
+        self.schedule.run_stage(stage);
+        self.apply_commands();
+    }
+
+    pub fn commands(&self) -> Commands {
+        Commands::new(self.storage.clone())
+    }
+
+    /// Applies all recorded [`Commands`] in order, including the ones recorded while applying.
+    pub fn apply_commands(&mut self) {
+        let queue = self.storage.get().commands().clone();
+        loop {
+            let commands = queue.take();
+            if commands.is_empty() {
+                break;
+            }
+            for command in commands {
+                command(self);
             }
         }
     }
 
-    pub fn create_entity<B: EntityBehavior + 'static, C>(&mut self, entity: fn(EcsStorage) -> Entity<B, C>) -> Option<()> {
-        let mut entity = entity(self.storage.clone());
+    pub fn add_system<M>(&mut self, stage: Stage, system: impl IntoScheduled<M>) -> SystemConfig<'_> {
+        self.schedule.add_system(stage, system)
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    pub fn schedule_mut(&mut self) -> &mut Schedule {
+        &mut self.schedule
+    }
+
+    /// Creates an entity and calls [`EntityBehavior::start`] of its behavior. Every behavior type is stored in its own
+    /// blob, along with the entity that owns each behavior. [`NoBehavior`] is not stored at all.
+    pub fn create_entity<B: EntityBehavior + 'static, C>(&mut self, entity: fn(EcsStorage) -> Entity<B, C>) -> Option<EntityType> {
+        let entity = entity(self.storage.clone());
+        match entity.behavior {
+            Some(behavior) => self.attach_behavior(entity.ty, behavior),
+            None => Some(entity.ty),
+        }
+    }
+
+    /// Stores `behavior` as the behavior of `entity`, which already exists in the storage, and starts it.
+    pub(crate) fn attach_behavior<B: EntityBehavior + 'static>(&mut self, entity: EntityType, behavior: B) -> Option<EntityType> {
         let type_id = TypeId::of::<B>();
-        let entity_ty = entity.ty;
-        let b = entity.behavior;
-        if b.is_some() {
-            let mut b = b.unwrap();
-            let idx = if let Some((cb, _)) = self.behaviors.get_mut(&type_id) {
-                cb.push_next(b)?
-            } else {
-                let mut cb = ContinuousBlob::new(Layout::for_value(&b));
-                let meta = std::ptr::metadata::<dyn EntityBehavior>(&b);
-                let idx = cb.push_next(b)?;
-                self.behaviors.insert(type_id, (cb, meta));
-                idx
-            };
-            let br = self.behaviors.get_mut(&type_id).unwrap().0.get_mut::<B>(idx).unwrap();
-            self.behavior_indices.insert(entity_ty, idx);
-            self.behavior_indices_rev.insert(idx as u64, entity_ty);
-            br.start(entity_ty);
-        }
-
-        Some(())
-    }
-}
\ No newline at end of file
+        if type_id == TypeId::of::<NoBehavior>() {
+            return Some(entity);
+        }
+
+        let BehaviorBlob { blob, owners, .. } = self.behaviors.entry(type_id).or_insert_with(|| BehaviorBlob::of(&behavior));
+        let idx = blob.push_next(behavior)?;
+        owners.push(entity);
+        self.behavior_indices.insert(entity, (type_id, idx));
+        self.start_behavior(entity, type_id, idx);
+        Some(entity)
+    }
+
+    fn start_behavior(&mut self, entity: EntityType, type_id: TypeId, idx: usize) {
+        let storage = self.storage.clone();
+        let mut ctx = BehaviorContext::new(&storage);
+        if let Some(behavior) = self.behaviors.get_mut(&type_id).and_then(|behaviors| behaviors.get_mut(idx)) {
+            behavior.start(entity, &mut ctx);
+        }
+        self.attach_spawned(ctx);
+    }
+
+    /// Registers `T` as described by its [`Component`] implementation, see [`Component::register`].
+    pub fn register<T: Component>(&mut self) {
+        T::register(self);
+    }
+
+    /// Chooses how `T` is stored. Has to be called before the first `T` is attached to any entity, returns false if
+    /// `T` is already in use with another storage type. Types that are not registered are stored as
+    /// [`Component::STORAGE`] says.
+    pub fn register_component<T: Component>(&mut self, storage: StorageType) -> bool {
+        self.storage.get_mut().register_component::<T>(storage)
+    }
+
+    /// Attaches `component` to `entity`, replacing the existing component of the same type. Does nothing if the
+    /// entity is dead.
+    pub fn insert_component<T: Component>(&mut self, entity: EntityType, component: T) {
+        self.storage.get_mut().set_component(entity, component);
+    }
+
+    /// Detaches the component `T` from `entity` and returns it, or `None` if the entity is dead or has no `T`.
+    pub fn remove_component<T: Component>(&mut self, entity: EntityType) -> Option<T> {
+        self.storage.get_mut().remove_component::<T>(entity)
+    }
+
+    pub fn has_component<T: Component>(&self, entity: EntityType) -> bool {
+        self.storage.get().has_component::<T>(entity)
+    }
+
+    /// Registers a hook that runs right after a `T` was attached to an entity that did not have one. Hooks can record
+    /// structural changes through the given [`Commands`].
+    pub fn on_add<T: Component>(&mut self, hook: impl Fn(EntityType, &T, &Commands) + Send + Sync + 'static) {
+        self.storage.get_mut().on_add(hook);
+    }
+
+    /// Registers a hook that runs with the old value right before a `T` is overwritten by a new one.
+    pub fn on_replace<T: Component>(&mut self, hook: impl Fn(EntityType, &T, &Commands) + Send + Sync + 'static) {
+        self.storage.get_mut().on_replace(hook);
+    }
+
+    /// Registers a hook that runs right before a `T` is removed from an entity, including when the entity is
+    /// despawned. This is where external resources owned by the component should be released.
+    pub fn on_remove<T: Component>(&mut self, hook: impl Fn(EntityType, &T, &Commands) + Send + Sync + 'static) {
+        self.storage.get_mut().on_remove(hook);
+    }
+
+    /// Inserts a resource that every system and behavior of this world can access, returning the previous resource
+    /// of the same type.
+    pub fn insert_resource<R: Resource>(&mut self, resource: R) -> Option<R> {
+        self.storage.get_mut().resources_mut().insert(resource)
+    }
+
+    pub fn remove_resource<R: Resource>(&mut self) -> Option<R> {
+        self.storage.get_mut().resources_mut().remove::<R>()
+    }
+
+    pub fn resource<R: Resource>(&self) -> Option<&R> {
+        self.storage.get().resource::<R>()
+    }
+
+    pub fn resource_mut<R: Resource>(&mut self) -> Option<&mut R> {
+        self.storage.get_mut().resource_mut::<R>()
+    }
+
+    /// Inserts the [`Events`] resource for `E` and swaps its buffers on every update. Adding the same event type
+    /// twice does nothing.
+    pub fn add_event<E: Send + Sync + 'static>(&mut self) {
+        let resources = self.storage.get_mut().resources_mut();
+        if !resources.contains::<Events<E>>() {
+            resources.insert(Events::<E>::new());
+            self.event_updaters.push(|resources| {
+                if let Some(events) = resources.get_mut::<Events<E>>() {
+                    events.update();
+                }
+            });
+        }
+    }
+
+    pub fn send_event<E: Send + Sync + 'static>(&mut self, event: E) {
+        if let Some(events) = self.resource_mut::<Events<E>>() {
+            events.send(event);
+        }
+    }
+
+    /// Creates a reader that only sees events sent from now on, or `None` if `E` was not added.
+    pub fn event_reader<E: Send + Sync + 'static>(&self) -> Option<EventReader<E>> {
+        self.resource::<Events<E>>().map(Events::reader)
+    }
+
+    /// Includes the component `T` in snapshots, identified by `name`. The name has to stay the same for old snapshots
+    /// to load, even if `T` is renamed.
+    pub fn register_savable<T: Component + Savable>(&mut self, name: &str) {
+        self.savables.register::<T>(name);
+    }
+
+    pub fn save_registry(&self) -> &SaveRegistry {
+        &self.savables
+    }
+
+    /// Writes a snapshot of every entity that has at least one component registered with
+    /// [`World::register_savable`]. Behaviors, resources and unregistered components are not saved.
+    pub fn save(&self, buffer: &mut ByteBuffer) {
+        self.savables.save(self.storage.get(), buffer);
+    }
+
+    /// Spawns the entities of a snapshot written by [`World::save`] in addition to the existing ones, and returns the
+    /// new handle of every saved handle. Components of unknown types are skipped. If the snapshot is truncated, an
+    /// error is returned and nothing is spawned.
+    pub fn load(&mut self, buffer: &mut ByteBuffer) -> Result<HashMap<EntityType, EntityType>, String> {
+        self.savables.load(self.storage.get_mut(), buffer)
+    }
+
+    /// Registers how elements with the tag `tag` in prefab files are turned into the component `T`.
+    pub fn register_prefab_component<T: Component>(&mut self, tag: &str, deserializer: fn(&PrefabComponent) -> Result<T, String>) {
+        self.prefabs.register(tag, deserializer);
+    }
+
+    /// Adds the prefabs defined in `xml`, see [`PrefabRegistry`].
+    pub fn load_prefabs(&mut self, xml: &str) -> Result<Vec<String>, String> {
+        self.prefabs.load(xml)
+    }
+
+    pub fn prefabs(&self) -> &PrefabRegistry {
+        &self.prefabs
+    }
+
+    pub fn prefabs_mut(&mut self) -> &mut PrefabRegistry {
+        &mut self.prefabs
+    }
+
+    /// Creates an entity with the components of the prefab `name`. Nothing is spawned if the prefab is unknown or
+    /// one of its components fails to deserialize.
+    pub fn spawn_prefab(&mut self, name: &str) -> Result<EntityType, String> {
+        self.prefabs.spawn(self.storage.get_mut(), name)
+    }
+
+    /// Attaches `child` to `parent`, detaching it from its previous parent. Returns false and does nothing if either
+    /// entity is dead or `parent` is `child` itself or one of its descendants.
+    pub fn set_parent(&mut self, child: EntityType, parent: EntityType) -> bool {
+        let storage = self.storage.get_mut();
+        if !storage.is_alive(child) || !storage.is_alive(parent) {
+            return false;
+        }
+        let mut ancestor = Some(parent);
+        while let Some(current) = ancestor {
+            if current == child {
+                return false;
+            }
+            ancestor = storage.get_component::<Parent>(current).map(Parent::get);
+        }
+
+        self.remove_parent(child);
+        let storage = self.storage.get_mut();
+        storage.set_component(child, Parent(parent));
+        match storage.get_component_mut::<Children>(parent) {
+            Some(children) => children.0.push(child),
+            None => storage.set_component(parent, Children(vec![child])),
+        }
+        true
+    }
+
+    /// Detaches `child` from its parent, making it a root. Returns the previous parent.
+    pub fn remove_parent(&mut self, child: EntityType) -> Option<EntityType> {
+        let storage = self.storage.get_mut();
+        let parent = storage.remove_component::<Parent>(child)?.get();
+        mark_detached(storage, child);
+        if let Some(children) = storage.get_component_mut::<Children>(parent) {
+            children.0.retain(|c| *c != child);
+            if children.is_empty() {
+                storage.remove_component::<Children>(parent);
+            }
+        }
+        Some(parent)
+    }
+
+    pub fn parent(&self, entity: EntityType) -> Option<EntityType> {
+        self.storage.get().get_component::<Parent>(entity).map(Parent::get)
+    }
+
+    pub fn children(&self, entity: EntityType) -> &[EntityType] {
+        self.storage.get().get_component::<Children>(entity).map_or(&[], |children| children)
+    }
+
+    /// Lets [`World::inspect`] and [`World::dump`] show the values of the components of type `T`.
+    pub fn register_debug<T: Component + Debug>(&mut self) {
+        self.storage.get_mut().register_debug::<T>();
+    }
+
+    /// Every living entity, sorted by index.
+    pub fn entities(&self) -> Vec<EntityType> {
+        let mut entities: Vec<EntityType> = self.storage.get().archetypes().iter()
+            .flat_map(|archetype| archetype.entities().iter().copied())
+            .collect();
+        entities.sort_by_key(|entity| entity.index());
+        entities
+    }
+
+    /// Counts entities, archetypes, components and behaviors, along with their memory use.
+    pub fn stats(&self) -> WorldStats {
+        let storage = self.storage.get();
+        let mut behaviors: Vec<BehaviorStats> = self.behaviors.iter().map(|(ty, BehaviorBlob { blob, owners, name, .. })| BehaviorStats {
+            name,
+            type_id: *ty,
+            size: blob.layout().size(),
+            len: owners.len(),
+            capacity: blob.capacity(),
+        }).collect();
+        behaviors.sort_by(|a, b| a.name.cmp(b.name));
+        WorldStats {
+            entities: storage.entity_count(),
+            archetypes: storage.archetypes().len(),
+            components: inspect::component_stats(storage),
+            behaviors,
+        }
+    }
+
+    /// Lists the components and the behavior of `entity`, or returns `None` if it is dead.
+    pub fn inspect(&self, entity: EntityType) -> Option<EntityView> {
+        let behavior = self.behavior_indices.get(&entity)
+            .and_then(|(ty, _)| self.behaviors.get(ty))
+            .map(|behaviors| behaviors.name);
+        inspect::inspect(self.storage.get(), entity, behavior)
+    }
+
+    /// Formats [`World::stats`] and [`World::inspect`] of every entity as text, meant for printing or for comparing
+    /// worlds in tests.
+    pub fn dump(&self) -> String {
+        let mut out = self.stats().to_string();
+        out.push_str("Entities:\n");
+        for view in self.entities().into_iter().filter_map(|entity| self.inspect(entity)) {
+            for line in view.to_string().lines() {
+                out.push_str("  ");
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Destroys `entity` and all of its descendants, dropping their behaviors and components. The freed slots are
+    /// reused by entities created afterward. Despawning a handle that is already dead does nothing.
+    pub fn despawn(&mut self, entity: EntityType) {
+        if !self.storage.get().is_alive(entity) {
+            return;
+        }
+        self.remove_parent(entity);
+        let mut stack = vec![entity];
+        while let Some(entity) = stack.pop() {
+            if let Some(children) = self.storage.get_mut().remove_component::<Children>(entity) {
+                stack.extend(children.0);
+            }
+            self.despawn_single(entity);
+        }
+    }
+
+    /// Moves `entity` and all of its descendants into `other`, along with their components, and returns the new handle
+    /// of `entity`. The entity is detached from its parent first, the hierarchy below it is kept.
+    ///
+    /// The behavior of every moved entity moves along with its state. It gets [`EntityBehavior::rebind`] called with
+    /// the storage of `other` and is started again with [`EntityBehavior::start`] once the whole hierarchy has moved.
+    /// [`EntityBehavior::destroy`] is not called.
+    pub fn move_entity_to(&mut self, entity: EntityType, other: &mut World) -> Option<EntityType> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+        self.remove_parent(entity);
+        self.move_subtree(entity, other).get(&entity).copied()
+    }
+
+    /// Moves every entity into `other` like [`World::move_entity_to`]. Returns the new handle of every moved entity.
+    pub fn move_all_to(&mut self, other: &mut World) -> HashMap<EntityType, EntityType> {
+        let roots: Vec<EntityType> = self.entities().into_iter()
+            .filter(|entity| !self.storage.get().has_component::<Parent>(*entity))
+            .collect();
+        let mut mapping = HashMap::new();
+        for root in roots {
+            mapping.extend(self.move_subtree(root, other));
+        }
+        mapping
+    }
+
+    fn move_subtree(&mut self, entity: EntityType, other: &mut World) -> HashMap<EntityType, EntityType> {
+        assert!(!Arc::ptr_eq(&self.storage, &other.storage), "Cannot move entities into the same world!");
+        let mut mapping = HashMap::new();
+        let mut behaviors = Vec::new();
+        let mut stack = vec![entity];
+        while let Some(entity) = stack.pop() {
+            stack.extend_from_slice(self.children(entity));
+            let Some(moved) = self.storage.get_mut().move_entity_to(entity, other.storage.get_mut()) else { continue; };
+            mapping.insert(entity, moved);
+            if self.move_behavior(entity, moved, other) {
+                behaviors.push(moved);
+            }
+        }
+        remap_hierarchy(other.storage.get_mut(), &mapping);
+        for moved in behaviors {
+            if let Some(&(type_id, idx)) = other.behavior_indices.get(&moved) {
+                other.start_behavior(moved, type_id, idx);
+            }
+        }
+        mapping
+    }
+
+    /// Moves the behavior of `entity` into `other` as the behavior of `moved` and rebinds it to the storage of
+    /// `other`. Returns false if `entity` has no behavior.
+    fn move_behavior(&mut self, entity: EntityType, moved: EntityType, other: &mut World) -> bool {
+        let Some((type_id, idx)) = self.behavior_indices.remove(&entity) else { return false; };
+        let Some(source) = self.behaviors.get_mut(&type_id) else { return false; };
+        let Some(ptr) = source.blob.get_ptr(idx) else { return false; };
+        let target = other.behaviors.entry(type_id).or_insert_with(|| source.empty_like());
+        // SAFETY: both blobs store behaviors of `type_id`, and the value is forgotten by `source` right after the copy.
+        let moved_idx = unsafe {
+            let moved_idx = target.blob.push_raw(ptr);
+            source.blob.swap_remove_forget(idx);
+            moved_idx
+        };
+        source.owners.swap_remove(idx);
+        if let Some(swapped) = source.owners.get(idx) {
+            self.behavior_indices.insert(*swapped, (type_id, idx));
+        }
+        target.owners.push(moved);
+        if let Some(behavior) = target.get_mut(moved_idx) {
+            behavior.rebind(other.storage.clone());
+        }
+        other.behavior_indices.insert(moved, (type_id, moved_idx));
+        true
+    }
+
+    /// Calls [`EntityBehavior::destroy`] of the behavior of `entity` and drops it. Returns the type of the behavior.
+    fn destroy_behavior(&mut self, entity: EntityType) -> Option<TypeId> {
+        let (type_id, idx) = self.behavior_indices.remove(&entity)?;
+        let storage = self.storage.clone();
+        let mut ctx = BehaviorContext::new(&storage);
+        let behaviors = self.behaviors.get_mut(&type_id)?;
+        if let Some(behavior) = behaviors.get_mut(idx) {
+            behavior.destroy(entity, &mut ctx);
+        }
+        behaviors.blob.swap_remove_drop(idx);
+        behaviors.owners.swap_remove(idx);
+        if let Some(moved) = behaviors.owners.get(idx) {
+            self.behavior_indices.insert(*moved, (type_id, idx));
+        }
+        self.attach_spawned(ctx);
+        Some(type_id)
+    }
+
+    fn despawn_single(&mut self, entity: EntityType) {
+        self.destroy_behavior(entity);
+        self.storage.get_mut().remove_entity(entity);
+    }
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A [`World`] that can be sent to another thread, created by [`World::into_send`].
+pub struct SendWorld(World);
+
+unsafe impl Send for SendWorld {}
+
+impl SendWorld {
+    pub fn into_inner(self) -> World {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use crate::ecs::{EcsStorage, ECS};
+    use crate::ecs::entity::{BehaviorContext, Entity, EntityBehavior, EntityType, NoBehavior};
+    use crate::ecs::schedule::Stage;
+    use crate::ecs::system::System;
+    use crate::ecs::testing::{Health, Regeneration, Tracked};
+    use crate::ecs::world::World;
+
+    /// What ran, in order, as "<entity index> <hook>".
+    #[derive(Default)]
+    struct Journal(Vec<String>);
+
+    fn record(ctx: &mut BehaviorContext, entity: EntityType, hook: &str) {
+        ctx.resource_mut::<Journal>().expect("Journal was not inserted").0.push(format!("{} {hook}", entity.index()));
+    }
+
+    /// Writes every hook that runs into the [`Journal`].
+    struct Recorder;
+
+    impl EntityBehavior for Recorder {
+        fn new(_: EcsStorage) -> Self {
+            Self
+        }
+
+        fn start(&mut self, entity: EntityType, ctx: &mut BehaviorContext) {
+            record(ctx, entity, "start");
+        }
+
+        fn update(&mut self, entity: EntityType, ctx: &mut BehaviorContext) {
+            record(ctx, entity, "update");
+        }
+
+        fn fixed_update(&mut self, entity: EntityType, dt: f32, ctx: &mut BehaviorContext) {
+            record(ctx, entity, &format!("fixed {dt}"));
+        }
+
+        fn late_update(&mut self, entity: EntityType, ctx: &mut BehaviorContext) {
+            record(ctx, entity, "late");
+        }
+
+        fn destroy(&mut self, entity: EntityType, ctx: &mut BehaviorContext) {
+            record(ctx, entity, "destroy");
+        }
+    }
+
+    /// Spawns an entity with a [`Recorder`] when it starts.
+    struct Spawner;
+
+    impl EntityBehavior for Spawner {
+        fn new(_: EcsStorage) -> Self {
+            Self
+        }
+
+        fn start(&mut self, _entity: EntityType, ctx: &mut BehaviorContext) {
+            ctx.spawn(Entity::<Recorder, (Health,)>::new);
+        }
+
+        fn update(&mut self, _entity: EntityType, _ctx: &mut BehaviorContext) {}
+    }
+
+    /// Counts its own updates and writes the count into the health of its entity.
+    struct Counter {
+        storage: EcsStorage,
+        updates: u32,
+    }
+
+    impl EntityBehavior for Counter {
+        fn new(storage: EcsStorage) -> Self {
+            Self { storage, updates: 0 }
+        }
+
+        fn start(&mut self, _entity: EntityType, _ctx: &mut BehaviorContext) {}
+
+        fn update(&mut self, entity: EntityType, _ctx: &mut BehaviorContext) {
+            self.updates += 1;
+            self.storage.get_mut().set_component(entity, Health(self.updates));
+        }
+
+        fn rebind(&mut self, storage: EcsStorage) {
+            self.storage = storage;
+        }
+    }
+
+    fn journal(world: &mut World) -> Vec<String> {
+        std::mem::take(&mut world.resource_mut::<Journal>().expect("Journal was not inserted").0)
+    }
+
+    #[test]
+    fn behavior_hooks_run_in_order_around_the_systems() {
+        let mut world = World::new();
+        world.insert_resource(Journal::default());
+        let entity = world.create_entity(Entity::<Recorder, (Health,)>::new).expect("Entity could not be created");
+        for (stage, name) in [(Stage::PostUpdate, "post update"), (Stage::Update, "update")] {
+            world.add_system(stage, move |system: &mut System<(Health,)>| {
+                system.resource_mut::<Journal>().expect("Journal was not inserted").0.push(format!("{name} system"));
+            }).writes_resource::<Journal>();
+        }
+        assert_eq!(journal(&mut world), ["0 start"]);
+
+        world.fixed_update(0.25);
+        world.update();
+        world.despawn(entity);
+        assert_eq!(journal(&mut world), ["0 fixed 0.25", "0 update", "update system", "post update system", "0 late", "0 destroy"]);
+    }
+
+    #[test]
+    fn spawned_entities_are_started_and_updated() {
+        let mut world = World::new();
+        world.insert_resource(Journal::default());
+        world.create_entity(Entity::<Spawner, ()>::new).expect("Entity could not be created");
+        assert_eq!(journal(&mut world), ["1 start"]);
+        assert_eq!(world.entities().len(), 2);
+        world.update();
+        assert_eq!(journal(&mut world), ["1 update", "1 late"]);
+    }
+
+    #[test]
+    fn behaviors_of_different_types_share_blob_indices() {
+        let mut world = World::new();
+        world.insert_resource(Journal::default());
+        let regenerating = world.create_entity(Entity::<Regeneration, (Health,)>::new).expect("Entity could not be created");
+        let recording = world.create_entity(Entity::<Recorder, (Health,)>::new).expect("Entity could not be created");
+        world.update();
+        world.despawn(regenerating);
+        world.update();
+        assert_eq!(journal(&mut world), ["1 start", "1 update", "1 late", "1 update", "1 late"]);
+        assert_eq!(world.storage().get().get_component::<Health>(recording), Some(&Health(0)));
+        world.despawn(recording);
+        assert_eq!(journal(&mut world), ["1 destroy"]);
+    }
+
+    #[test]
+    fn despawn_drops_the_components_and_behavior_of_one_entity() {
+        let drops = Tracked::counter();
+        let mut ecs = ECS::new();
+        let storage = ecs.storage();
+        let world = ecs.world_mut();
+        let entities: Vec<EntityType> = (0..3)
+            .map(|_| world.create_entity(Entity::<Regeneration, (Health,)>::new).expect("Entity could not be created"))
+            .collect();
+        for entity in &entities {
+            storage.get_mut().set_component(*entity, Tracked(drops.clone()));
+        }
+
+        world.despawn(entities[0]);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert!(storage.get().get_component::<Health>(entities[0]).is_none());
+        world.update();
+        world.despawn(entities[0]);
+        world.update();
+
+        // The behavior of the last entity was moved into the freed slot and still updates its own entity.
+        let health = |entity| storage.get().get_component::<Health>(entity).cloned();
+        assert_eq!((health(entities[1]), health(entities[2])), (Some(Health(2)), Some(Health(2))));
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+
+        let reused = world.create_entity(Entity::<Regeneration, (Health,)>::new).expect("Entity could not be created");
+        assert_eq!(reused.index(), entities[0].index());
+        assert!(world.is_alive(reused) && !world.is_alive(entities[0]));
+    }
+
+    #[test]
+    fn despawn_takes_descendants_along() {
+        let mut ecs = ECS::new();
+        let world = ecs.world_mut();
+        let [root, child, grandchild, other] = [(); 4]
+            .map(|_| world.create_entity(Entity::<NoBehavior, (Health,)>::new).expect("Entity could not be created"));
+        assert!(world.set_parent(child, root));
+        assert!(world.set_parent(grandchild, child));
+        assert!(world.set_parent(other, root));
+        assert!(!world.set_parent(root, grandchild), "Cycle was accepted");
+        assert_eq!(world.remove_parent(other), Some(root));
+
+        world.despawn(child);
+        assert!(!world.is_alive(child) && !world.is_alive(grandchild));
+        assert!(world.children(root).is_empty());
+        world.despawn(root);
+        assert!(world.is_alive(other));
+        assert_eq!(world.parent(other), None);
+        assert_eq!(ecs.storage().get().entity_count(), 1);
+    }
+
+    #[test]
+    fn created_worlds_have_their_own_storage() {
+        let mut ecs = ECS::new();
+        let level = ecs.create_world("level");
+        level.create_entity(Entity::<NoBehavior, (Health,)>::new).expect("Entity could not be created");
+        assert_eq!(ecs.get_world("level").map(|level| level.entities().len()), Some(1));
+        assert_eq!(ecs.storage().get().entity_count(), 0);
+
+        ecs.create_world("level");
+        assert_eq!(ecs.get_world("level").map(|level| level.entities().len()), Some(0));
+        assert!(ecs.get_world("menu").is_none());
+    }
+
+    #[test]
+    fn moved_entities_keep_their_descendants_and_behaviors() {
+        let mut source = World::new();
+        let mut target = World::new();
+        source.insert_resource(Journal::default());
+        target.insert_resource(Journal::default());
+        target.create_entity(Entity::<NoBehavior, (Health,)>::new).expect("Entity could not be created");
+        let holder = source.create_entity(Entity::<NoBehavior, (Health,)>::new).expect("Entity could not be created");
+        let root = source.create_entity(Entity::<Counter, (Health,)>::new).expect("Entity could not be created");
+        let child = source.create_entity(Entity::<Recorder, (Health,)>::new).expect("Entity could not be created");
+        assert!(source.set_parent(root, holder));
+        assert!(source.set_parent(child, root));
+        source.update();
+        source.update();
+        journal(&mut source);
+
+        let moved = source.move_entity_to(root, &mut target).expect("Entity was not moved");
+        assert!(!source.is_alive(root) && !source.is_alive(child));
+        assert!(source.children(holder).is_empty());
+        assert_eq!(source.entities(), [holder]);
+        assert_eq!(target.parent(moved), None);
+        let &[moved_child] = target.children(moved) else { panic!("Child was not moved along") };
+        assert_eq!(target.parent(moved_child), Some(moved));
+
+        // The behaviors are started again in the new world, but not destroyed in the old one.
+        assert!(journal(&mut source).is_empty());
+        assert_eq!(journal(&mut target), [format!("{} start", moved_child.index())]);
+        target.update();
+        assert_eq!(target.storage().get().get_component::<Health>(moved), Some(&Health(3)));
+        assert!(source.stats().behaviors.iter().all(|behavior| behavior.len == 0));
+    }
+
+    #[test]
+    fn moving_everything_remaps_the_whole_hierarchy() {
+        let mut source = World::new();
+        let mut target = World::new();
+        target.create_entity(Entity::<NoBehavior, ()>::new).expect("Entity could not be created");
+        let [root, child, other] = [(); 3]
+            .map(|_| source.create_entity(Entity::<Regeneration, (Health,)>::new).expect("Entity could not be created"));
+        assert!(source.set_parent(child, root));
+
+        let mapping = source.move_all_to(&mut target);
+        assert_eq!(mapping.len(), 3);
+        assert!(source.entities().is_empty());
+        assert_eq!(target.children(mapping[&root]), [mapping[&child]]);
+        assert_eq!(target.parent(mapping[&other]), None);
+
+        // The behaviors were rebound, so they update their entities in the new storage.
+        target.update();
+        let storage = target.storage();
+        assert!(mapping.values().all(|moved| storage.get().get_component::<Health>(*moved) == Some(&Health(1))));
+    }
+
+    #[test]
+    fn worlds_can_be_built_on_another_thread() {
+        let mut loaded = std::thread::spawn(|| {
+            let mut world = World::new();
+            world.create_entity(Entity::<Regeneration, (Health,)>::new).expect("Entity could not be created");
+            unsafe { world.into_send() }
+        }).join().expect("Loading thread panicked").into_inner();
+
+        let mut ecs = ECS::new();
+        let mapping = loaded.move_all_to(ecs.world_mut());
+        ecs.world_mut().update();
+        let moved = mapping.values().next().copied().expect("Entity was not moved");
+        assert_eq!(ecs.storage().get().get_component::<Health>(moved), Some(&Health(1)));
+    }
+}
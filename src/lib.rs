@@ -5,8 +5,10 @@
 #![cfg_attr(not(debug_assertions), deny(clippy::todo))]
 #![warn(clippy::pedantic)]
 
+// Lets the derive and attribute macros, which refer to `mvengine::...`, be used inside this crate too.
+extern crate self as mvengine;
 
-// pub mod net;
+pub mod net;
 pub mod ui;
 pub mod window;
 pub mod math;
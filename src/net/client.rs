@@ -1,71 +1,228 @@
-use std::io::Read;
+use std::io::{ErrorKind, Read, Write};
 use std::net::TcpStream;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use bytebuffer::ByteBuffer;
 use log::{info, warn};
 use mvutils::save::Savable;
-use mvutils::unsafe_utils::DangerousCell;
-use crate::net::{middleware, ConnectionHandler, DisconnectReason, PacketHandler};
+use parking_lot::{Condvar, Mutex};
+use crate::net::{middleware, ConnectionConfig, ConnectionHandler, DisconnectReason, PacketHandler, Server};
+use crate::net::middleware::{Middleware, Pipeline, Side};
+use crate::net::middleware::framing::LengthFraming;
 
 #[derive(Clone)]
 pub struct ClientEndpoint {
     pub(crate) id: u64,
-    pub(crate) socket: Arc<DangerousCell<TcpStream>>,
+    pub(crate) socket: Arc<TcpStream>,
     pub(crate) addr: String,
+    pub(crate) link: Arc<Link>,
 }
 
 impl ClientEndpoint {
-    pub(crate) fn new<In: Savable + 'static, Out: Savable + 'static, Handler: PacketHandler<In> + 'static>(socket: TcpStream, connection_handler: Arc<ConnectionHandler<In, Out, Server, Handler>>) -> Self {
+    pub(crate) fn new(socket: TcpStream, config: &ConnectionConfig) -> Self {
         let addr = socket.peer_addr().map(|a| a.to_string()).unwrap_or("<invalid address>".to_string());
         info!("Incoming connection from {addr}");
         let this = Self {
             id: mvutils::utils::next_id("MVEngine::Network::client_endpoint"),
-            socket: Arc::new(DangerousCell::new(socket)),
+            socket: Arc::new(socket),
             addr,
+            link: Arc::new(Link::with_handshake(config.middleware.for_side(Side::Server), middleware::handshake(config.protocol_version))),
         };
-        let _ = this.socket.get_mut().set_read_timeout(Some(Duration::from_secs(1)));
-        let _ = this.socket.get_mut().set_write_timeout(Some(Duration::from_secs(1)));
+        let _ = this.socket.set_read_timeout(Some(config.idle_timeout));
+        let _ = this.socket.set_write_timeout(Some(config.idle_timeout));
+        this
+    }
 
-        let this2 = this.clone();
-        std::thread::spawn(move || {
-            let socket = this2.get_socket();
+    /// Starts the thread that reads from the client. The endpoint has to be registered with the server before, so
+    /// packets and disconnects it reports always belong to a known client. The thread stops once the server was
+    /// dropped.
+    pub(crate) fn start<In: Savable + 'static, Out: Savable + 'static, Handler: PacketHandler<In> + 'static>(&self, connection_handler: &Arc<ConnectionHandler<In, Out, Server, Handler>>) {
+        let this2 = self.clone();
+        let handler = Arc::downgrade(connection_handler);
+        let config = connection_handler.config.clone();
+        let reader = std::thread::spawn(move || {
+            // Writing can block for as long as the idle timeout, which must not hold up the accept thread.
+            if this2.link.write(&this2.socket, |_| None).is_err() {
+                warn!("Couldn't send handshake to {}", this2.addr);
+            }
+            if let Err(reason) = read_handshake(&this2.socket, config.protocol_version, &this2.link) {
+                warn!("Handshake with {} failed", this2.addr);
+                if let Some(connection_handler) = handler.upgrade() {
+                    connection_handler.disconnect(this2.id, reason);
+                }
+                return;
+            }
             loop {
-                let mut len_buffer = [0u8; 4];
-                if let Err(_) = socket.get_mut().read_exact(len_buffer.as_mut()) {
-                    warn!("Failed to read from {}", this2.addr);
-                    connection_handler.disconnect(this2.id, DisconnectReason::TimedOut);
-                    break;
-                } else {
-                    let len = u32::from_le_bytes(len_buffer);
-                    let mut buffer = vec![0u8; len as usize];
-                    if let Err(_) = socket.get_mut().read_exact(buffer.as_mut()) {
+                let frame = read_frame::<In>(&this2.socket, &config, &this2.link);
+                let Some(connection_handler) = handler.upgrade() else { break; };
+                match frame {
+                    Ok(frame) => {
+                        if let Some(packet) = connection_handler.handle_frame(&this2.socket, &this2.link, frame) {
+                            connection_handler.handler.incoming(&*connection_handler, this2.id, packet);
+                        }
+                    }
+                    Err(reason) => {
                         warn!("Failed to read from {}", this2.addr);
-                        connection_handler.disconnect(this2.id, DisconnectReason::TimedOut);
+                        connection_handler.disconnect(this2.id, reason);
                         break;
-                    } else {
-                        let buffer = middleware::decode(buffer);
-                        let mut bytebuffer = ByteBuffer::from_bytes(buffer.as_slice());
-                        let packet = In::load(&mut bytebuffer);
-                        match packet {
-                            Ok(in_packet) => {
-                                connection_handler.handler.incoming(&*connection_handler, this2.id, in_packet);
-                            }
-                            Err(error) => {
-                                warn!("Malformed packet: {error}");
-                            }
-                        }
                     }
                 }
             }
         });
+        connection_handler.track(reader);
+    }
+
+    /// The round trip time measured by the last heartbeat, `None` until the client answered one.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.link.rtt()
+    }
+}
+
+/// State shared by every thread that uses one connection.
+pub(crate) struct Link {
+    /// Held while a frame is encoded and written, so frames sent from different threads don't interleave and arrive in
+    /// the order the middleware encoded them. Holds the handshake and the greeting of the middleware until they were
+    /// written, which happens before the first frame, whichever thread sends it.
+    write: Mutex<Option<Vec<u8>>>,
+    /// The middleware session of this connection, see [`Middleware::session`].
+    middleware: Pipeline,
+    /// Whether the middleware received the greeting of the other side, see [`Middleware::greet`]. Packets wait for it,
+    /// control frames skip the middleware and don't.
+    greeted: Mutex<bool>,
+    greeting: Condvar,
+    /// In microseconds, `u64::MAX` until the first pong arrived.
+    rtt: AtomicU64,
+}
+
+impl Link {
+    /// A link whose handshake and greeting were already written.
+    pub(crate) fn new(middleware: Pipeline) -> Self {
+        Self {
+            write: Mutex::new(None),
+            greeted: Mutex::new(middleware.greeting_len() == 0),
+            greeting: Condvar::new(),
+            middleware,
+            rtt: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    pub(crate) fn with_handshake(middleware: Pipeline, handshake: [u8; middleware::HANDSHAKE_LEN]) -> Self {
+        let this = Self::new(middleware);
+        *this.write.lock() = Some(this.hello(handshake));
         this
     }
 
-    fn get_socket(&self) -> Arc<DangerousCell<TcpStream>> {
-        self.socket.clone()
+    /// The handshake followed by the greeting of the middleware, which is the first thing sent on a connection.
+    pub(crate) fn hello(&self, handshake: [u8; middleware::HANDSHAKE_LEN]) -> Vec<u8> {
+        let mut hello = handshake.to_vec();
+        hello.extend(self.middleware.greeting());
+        hello
+    }
+
+    /// Passes the greeting of the other side to the middleware and wakes the threads waiting to send packets.
+    pub(crate) fn greet(&self, greeting: &[u8]) -> Result<(), String> {
+        self.middleware.greet(greeting)?;
+        *self.greeted.lock() = true;
+        self.greeting.notify_all();
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for the greeting of the other side. Returns whether it arrived.
+    pub(crate) fn wait_for_greeting(&self, timeout: Duration) -> bool {
+        let mut greeted = self.greeted.lock();
+        self.greeting.wait_while_for(&mut greeted, |greeted| !*greeted, timeout);
+        *greeted
     }
+
+    /// Writes the frame `frame` builds with the middleware of this connection, if it builds one.
+    pub(crate) fn write(&self, mut socket: &TcpStream, frame: impl FnOnce(&Pipeline) -> Option<Vec<u8>>) -> std::io::Result<()> {
+        let mut handshake = self.write.lock();
+        if let Some(handshake) = handshake.take() {
+            socket.write_all(&handshake)?;
+        }
+        match frame(&self.middleware) {
+            Some(frame) => socket.write_all(&frame),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        let micros = self.rtt.load(Ordering::Acquire);
+        (micros != u64::MAX).then(|| Duration::from_micros(micros))
+    }
+
+    pub(crate) fn set_rtt(&self, rtt: Duration) {
+        self.rtt.store(u64::try_from(rtt.as_micros()).unwrap_or(u64::MAX - 1), Ordering::Release);
+    }
+}
+
+/// The first byte of every frame, telling packets apart from heartbeats.
+pub(crate) const PACKET: u8 = 0;
+pub(crate) const PING: u8 = 1;
+pub(crate) const PONG: u8 = 2;
+
+/// A frame that was read from a connection. Pings and pongs carry the time the ping was sent at and are handled by
+/// the connection itself, only packets reach the [`PacketHandler`].
+pub(crate) enum Frame<In> {
+    Packet(In),
+    /// A packet that could not be loaded.
+    Malformed,
+    Ping(u64),
+    Pong(u64),
+}
+
+fn reason(error: std::io::Error) -> DisconnectReason {
+    match error.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => DisconnectReason::TimedOut,
+        ErrorKind::InvalidData => DisconnectReason::ProtocolViolation,
+        _ => DisconnectReason::Disconnected,
+    }
+}
+
+/// Reads the handshake of the other side, which has to be sent before anything else, and the greeting of its
+/// middleware right after.
+pub(crate) fn read_handshake(mut socket: &TcpStream, version: u32, link: &Link) -> Result<(), DisconnectReason> {
+    let mut buffer = [0u8; middleware::HANDSHAKE_LEN];
+    socket.read_exact(buffer.as_mut()).map_err(reason)?;
+    if !middleware::check_handshake(buffer, version) {
+        return Err(DisconnectReason::ProtocolViolation);
+    }
+    let mut greeting = vec![0u8; link.middleware.greeting_len()];
+    socket.read_exact(&mut greeting).map_err(reason)?;
+    link.greet(&greeting).map_err(|error| {
+        warn!("Couldn't greet middleware: {error}");
+        DisconnectReason::ProtocolViolation
+    })
 }
 
-unsafe impl Send for ClientEndpoint {}
-unsafe impl Sync for ClientEndpoint {}
+/// Reads one frame and runs packets through the middleware in reverse. Returns the reason to disconnect if the
+/// connection failed or timed out, the frame is too large or of an unknown kind, or the middleware rejected it.
+pub(crate) fn read_frame<In: Savable>(mut socket: &TcpStream, config: &ConnectionConfig, link: &Link) -> Result<Frame<In>, DisconnectReason> {
+    let mut frame = LengthFraming::new(config.max_frame_size).read_frame(&mut socket).map_err(reason)?;
+    if frame.is_empty() {
+        return Err(DisconnectReason::ProtocolViolation);
+    }
+    let kind = frame.remove(0);
+    if kind == PING || kind == PONG {
+        let time = <[u8; 8]>::try_from(frame.as_slice()).map_err(|_| DisconnectReason::ProtocolViolation)?;
+        let time = u64::from_le_bytes(time);
+        return Ok(if kind == PING { Frame::Ping(time) } else { Frame::Pong(time) });
+    }
+    if kind != PACKET {
+        warn!("Unknown frame kind {kind}");
+        return Err(DisconnectReason::ProtocolViolation);
+    }
+    let buffer = link.middleware.decode(frame).map_err(|error| {
+        warn!("Couldn't decode packet: {error}");
+        DisconnectReason::ProtocolViolation
+    })?;
+    let mut bytebuffer = ByteBuffer::from_bytes(buffer.as_slice());
+    match In::load(&mut bytebuffer) {
+        Ok(packet) => Ok(Frame::Packet(packet)),
+        Err(error) => {
+            warn!("Malformed packet: {error}");
+            Ok(Frame::Malformed)
+        }
+    }
+}
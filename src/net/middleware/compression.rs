@@ -0,0 +1,79 @@
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use crate::net::middleware::Middleware;
+
+const RAW: u8 = 0;
+const DEFLATED: u8 = 1;
+
+/// Deflates packets of at least `threshold` bytes. Every packet is prefixed with a flag, deflated ones also with
+/// their original length. Packets that would not get smaller are sent as they are.
+#[derive(Copy, Clone, Debug)]
+pub struct Compression {
+    threshold: usize,
+    level: u8,
+    max_size: usize,
+}
+
+impl Compression {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            level: 6,
+            max_size: 64 * 1024 * 1024,
+        }
+    }
+
+    /// From 0, no compression, to 10, the best compression. Defaults to 6.
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = level.min(10);
+        self
+    }
+
+    /// The largest size a packet may have after inflating it, which protects against compression bombs. Defaults to
+    /// 64 MiB.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+}
+
+impl Middleware for Compression {
+    fn encode(&self, data: Vec<u8>) -> Vec<u8> {
+        if data.len() >= self.threshold {
+            let deflated = compress_to_vec(&data, self.level);
+            if deflated.len() + 4 < data.len() {
+                let mut encoded = Vec::with_capacity(deflated.len() + 5);
+                encoded.push(DEFLATED);
+                encoded.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                encoded.extend(deflated);
+                return encoded;
+            }
+        }
+        let mut encoded = Vec::with_capacity(data.len() + 1);
+        encoded.push(RAW);
+        encoded.extend(data);
+        encoded
+    }
+
+    fn decode(&self, mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match data.first() {
+            Some(&RAW) => {
+                data.remove(0);
+                Ok(data)
+            }
+            Some(&DEFLATED) => {
+                let Some(len) = data.get(1..5) else { return Err("Compressed packet is missing its length".to_string()); };
+                let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+                if len > self.max_size {
+                    return Err(format!("Compressed packet of {len} bytes exceeds the maximum of {} bytes", self.max_size));
+                }
+                let inflated = decompress_to_vec_with_limit(&data[5..], len).map_err(|e| format!("Couldn't inflate packet: {e}"))?;
+                if inflated.len() != len {
+                    return Err(format!("Compressed packet should be {len} bytes long, but is {} bytes long", inflated.len()));
+                }
+                Ok(inflated)
+            }
+            _ => Err("Packet has an unknown compression flag".to_string()),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, XChaCha20Poly1305};
+use chacha20poly1305::aead::{Aead, Payload};
+use crate::net::middleware::{Middleware, Side};
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+/// The length of the counter in front of every packet, the last bytes of its nonce.
+pub const COUNTER_LEN: usize = 8;
+/// The length of the random salt every session sends as its greeting.
+pub const SALT_LEN: usize = 8;
+
+/// Encrypts and authenticates packets with XChaCha20-Poly1305 and a key both sides know in advance.
+///
+/// Both sessions of a connection pick a random salt and send it to each other as their greeting, see
+/// [`Middleware::greeting`]. The nonce of every packet is the salt of its sender, the salt of its receiver and a
+/// counter, which is sent in front of the ciphertext, followed by the tag. XChaCha20 derives its key from the key and
+/// both salts, so every connection and direction encrypts with a key of its own even though the key never changes.
+/// Packets that were encrypted with another key or for another connection, tampered with, replayed, reordered or
+/// reflected back to their sender fail to decode.
+///
+/// Every connection uses its own copy, see [`Middleware::session`]. Using the stage directly needs the greeting of
+/// another [`Encryption`] passed to [`Middleware::greet`] before the first packet.
+pub struct Encryption {
+    key: [u8; KEY_LEN],
+    cipher: XChaCha20Poly1305,
+    salt: [u8; SALT_LEN],
+    /// The salt of the other side, known once it greeted this one.
+    peer_salt: OnceLock<[u8; SALT_LEN]>,
+    /// The counter of the next packet that is sent.
+    sent: AtomicU64,
+    /// The lowest counter the next packet that is received may have.
+    received: AtomicU64,
+}
+
+impl Encryption {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self {
+            key,
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            salt: rand::random(),
+            peer_salt: OnceLock::new(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        }
+    }
+
+    /// A copy with the same key for one connection, with a new salt and fresh counters.
+    pub fn fresh(&self) -> Self {
+        Self::new(self.key)
+    }
+
+    /// Encrypts `data` with ChaCha20-Poly1305 (RFC 8439) and the key itself, and returns the ciphertext followed by
+    /// the tag, which also covers `aad`. Packets are not sealed this way, they use a key derived for their connection.
+    pub fn seal(&self, nonce: [u8; NONCE_LEN], aad: &[u8], data: &[u8]) -> Vec<u8> {
+        ChaCha20Poly1305::new(&self.key.into()).encrypt(&nonce.into(), Payload { msg: data, aad })
+            .expect("Packet is too long to encrypt!")
+    }
+
+    /// Reverts [`Encryption::seal`], failing if the tag does not match.
+    pub fn open(&self, nonce: [u8; NONCE_LEN], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < TAG_LEN {
+            return Err("Encrypted packet is missing its tag".to_string());
+        }
+        ChaCha20Poly1305::new(&self.key.into()).decrypt(&nonce.into(), Payload { msg: sealed, aad })
+            .map_err(|_| "Encrypted packet failed authentication".to_string())
+    }
+}
+
+/// The nonce of the packet with `counter` that the side with the salt `sender` sent to the one with `receiver`.
+fn nonce(sender: &[u8; SALT_LEN], receiver: &[u8; SALT_LEN], counter: u64) -> [u8; 24] {
+    let mut nonce = [0u8; 24];
+    nonce[..SALT_LEN].copy_from_slice(sender);
+    nonce[SALT_LEN..2 * SALT_LEN].copy_from_slice(receiver);
+    nonce[2 * SALT_LEN..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+impl Middleware for Encryption {
+    /// Panics if the other side did not greet this one yet.
+    fn encode(&self, data: Vec<u8>) -> Vec<u8> {
+        let peer_salt = self.peer_salt.get().expect("Encryption has to be greeted before encoding!");
+        let counter = self.sent.fetch_update(Ordering::AcqRel, Ordering::Acquire, |counter| counter.checked_add(1))
+            .expect("Ran out of nonces, the connection has to be renewed!");
+        let sealed = self.cipher.encrypt(&nonce(&self.salt, peer_salt, counter).into(), data.as_slice())
+            .expect("Packet is too long to encrypt!");
+        let mut encoded = Vec::with_capacity(COUNTER_LEN + sealed.len());
+        encoded.extend_from_slice(&counter.to_le_bytes());
+        encoded.extend(sealed);
+        encoded
+    }
+
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        let Some(peer_salt) = self.peer_salt.get() else {
+            return Err("Encrypted packet arrived before the greeting".to_string());
+        };
+        let Some((counter, sealed)) = data.split_first_chunk::<COUNTER_LEN>() else {
+            return Err("Encrypted packet is missing its counter".to_string());
+        };
+        if sealed.len() < TAG_LEN {
+            return Err("Encrypted packet is missing its tag".to_string());
+        }
+        let counter = u64::from_le_bytes(*counter);
+        if counter < self.received.load(Ordering::Acquire) {
+            return Err("Encrypted packet was replayed or reordered".to_string());
+        }
+        let data = self.cipher.decrypt(&nonce(peer_salt, &self.salt, counter).into(), sealed)
+            .map_err(|_| "Encrypted packet failed authentication".to_string())?;
+        // Only authenticated packets may move the counter, anyone could send a large one.
+        self.received.store(counter.saturating_add(1), Ordering::Release);
+        Ok(data)
+    }
+
+    fn session(&self, _side: Side) -> Option<Arc<dyn Middleware>> {
+        Some(Arc::new(self.fresh()))
+    }
+
+    fn greeting(&self) -> Vec<u8> {
+        self.salt.to_vec()
+    }
+
+    fn greeting_len(&self) -> usize {
+        SALT_LEN
+    }
+
+    fn greet(&self, greeting: &[u8]) -> Result<(), String> {
+        let salt = <[u8; SALT_LEN]>::try_from(greeting).map_err(|_| "Encryption greeting has the wrong length".to_string())?;
+        // Both directions would share their keys, and packets could be reflected back to their sender.
+        if salt == self.salt {
+            return Err("Encryption greeting repeats the own salt".to_string());
+        }
+        self.peer_salt.set(salt).map_err(|_| "Encryption was greeted twice".to_string())
+    }
+}
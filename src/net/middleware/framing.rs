@@ -0,0 +1,76 @@
+use std::io::{Error, ErrorKind, Read};
+use crate::net::middleware::Middleware;
+
+/// Prefixes every frame with its length as a little endian `u32`, so frames can be told apart on a stream. Frames
+/// longer than `max_frame_size` are rejected before anything is allocated for them.
+#[derive(Copy, Clone, Debug)]
+pub struct LengthFraming {
+    max_frame_size: u32,
+}
+
+impl LengthFraming {
+    pub fn new(max_frame_size: u32) -> Self {
+        Self { max_frame_size }
+    }
+
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    /// Reads the next frame and returns it without the prefix. Frames that are too long fail with
+    /// [`ErrorKind::InvalidData`].
+    pub fn read_frame(&self, reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+        let mut len_buffer = [0u8; 4];
+        reader.read_exact(&mut len_buffer)?;
+        let len = self.check(u32::from_le_bytes(len_buffer)).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut buffer = vec![0u8; len];
+        reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Prefixes `data` with its length, unless it is longer than `max_frame_size` and the other side would reject
+    /// it.
+    pub fn frame(&self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match u32::try_from(data.len()) {
+            Ok(len) if len <= self.max_frame_size => Ok(prefix(len, data)),
+            _ => Err(format!("Frame of {} bytes exceeds the maximum of {} bytes", data.len(), self.max_frame_size)),
+        }
+    }
+
+    fn check(&self, len: u32) -> Result<usize, String> {
+        if len > self.max_frame_size {
+            Err(format!("Frame of {len} bytes exceeds the maximum of {} bytes", self.max_frame_size))
+        } else {
+            Ok(len as usize)
+        }
+    }
+}
+
+fn prefix(len: u32, data: Vec<u8>) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(data.len() + 4);
+    framed.extend_from_slice(&len.to_le_bytes());
+    framed.extend(data);
+    framed
+}
+
+impl Middleware for LengthFraming {
+    /// Prefixes frames longer than `max_frame_size` as well, [`LengthFraming::frame`] rejects them instead.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is longer than `u32::MAX` bytes, which the prefix cannot hold.
+    fn encode(&self, data: Vec<u8>) -> Vec<u8> {
+        let len = u32::try_from(data.len()).expect("Frame is too long for its length prefix!");
+        prefix(len, data)
+    }
+
+    fn decode(&self, mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+        let Some(prefix) = data.first_chunk::<4>() else { return Err("Frame is missing its length".to_string()); };
+        let len = self.check(u32::from_le_bytes(*prefix))?;
+        if len != data.len() - 4 {
+            return Err(format!("Frame should be {len} bytes long, but is {} bytes long", data.len() - 4));
+        }
+        data.drain(..4);
+        Ok(data)
+    }
+}
@@ -0,0 +1,154 @@
+pub mod compression;
+pub mod encryption;
+pub mod framing;
+
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// The first four bytes both sides send after connecting, `MVNT` in little endian.
+pub const MAGIC: u32 = u32::from_le_bytes(*b"MVNT");
+
+pub const HANDSHAKE_LEN: usize = 8;
+
+/// The side of a connection a [`Middleware`] session runs on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    /// The other end of the connection.
+    pub fn peer(self) -> Side {
+        match self {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        }
+    }
+}
+
+/// One stage of a [`Pipeline`], transforming every packet after it was saved and before it is loaded.
+pub trait Middleware: Send + Sync {
+    fn encode(&self, data: Vec<u8>) -> Vec<u8>;
+
+    /// Reverts [`Middleware::encode`]. Returning an error disconnects the peer with
+    /// [`DisconnectReason::ProtocolViolation`](crate::net::DisconnectReason::ProtocolViolation).
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, String>;
+
+    /// The stage one connection uses on `side`, for stages that keep state per connection, like counters. Packets
+    /// of a connection are encoded in the order they are sent and decoded in the order they arrive. `None` shares
+    /// this stage between every connection.
+    fn session(&self, _side: Side) -> Option<Arc<dyn Middleware>> {
+        None
+    }
+
+    /// Bytes a session sends to the other side right after the handshake, e.g. a random salt. Has to be
+    /// [`Middleware::greeting_len`] bytes long.
+    fn greeting(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// The length of the greeting both sides send, which has to be the same on both sides.
+    fn greeting_len(&self) -> usize {
+        0
+    }
+
+    /// Receives the greeting of the other side, before the first packet is encoded or decoded. Returning an error
+    /// disconnects the peer like [`Middleware::decode`] does.
+    fn greet(&self, _greeting: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The stages every packet passes through, e.g. `Pipeline::new().stage(Compression::new(256)).stage(Encryption::new(key))`.
+/// Outgoing packets run through the stages in order, incoming ones in reverse order, so both sides of a connection
+/// need the same pipeline. Every frame is length prefixed by [`framing::LengthFraming`] after the last stage.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    stages: Vec<Arc<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stage(mut self, stage: impl Middleware + 'static) -> Self {
+        self.push(stage);
+        self
+    }
+
+    pub fn push(&mut self, stage: impl Middleware + 'static) {
+        self.stages.push(Arc::new(stage));
+    }
+
+    /// The pipeline one connection uses on `side`, see [`Middleware::session`].
+    pub fn for_side(&self, side: Side) -> Self {
+        Self {
+            stages: self.stages.iter().map(|stage| stage.session(side).unwrap_or_else(|| stage.clone())).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl Middleware for Pipeline {
+    fn encode(&self, data: Vec<u8>) -> Vec<u8> {
+        self.stages.iter().fold(data, |data, stage| stage.encode(data))
+    }
+
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        self.stages.iter().rev().try_fold(data, |data, stage| stage.decode(data))
+    }
+
+    fn session(&self, side: Side) -> Option<Arc<dyn Middleware>> {
+        Some(Arc::new(self.for_side(side)))
+    }
+
+    fn greeting(&self) -> Vec<u8> {
+        self.stages.iter().flat_map(|stage| stage.greeting()).collect()
+    }
+
+    fn greeting_len(&self) -> usize {
+        self.stages.iter().map(|stage| stage.greeting_len()).sum()
+    }
+
+    /// Splits the greeting between the stages, in the order they greet.
+    fn greet(&self, mut greeting: &[u8]) -> Result<(), String> {
+        if greeting.len() != self.greeting_len() {
+            return Err("Greeting has the wrong length".to_string());
+        }
+        for stage in &self.stages {
+            let (own, rest) = greeting.split_at(stage.greeting_len());
+            stage.greet(own)?;
+            greeting = rest;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Pipeline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline").field("stages", &self.stages.len()).finish()
+    }
+}
+
+/// The [`MAGIC`] value followed by the protocol version. Both sides send it first, followed by the greeting of their
+/// middleware, see [`Middleware::greeting`].
+pub fn handshake(version: u32) -> [u8; HANDSHAKE_LEN] {
+    let mut bytes = [0u8; HANDSHAKE_LEN];
+    bytes[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    bytes[4..].copy_from_slice(&version.to_le_bytes());
+    bytes
+}
+
+/// Whether the handshake of the other side has the right magic value and `version`.
+pub fn check_handshake(bytes: [u8; HANDSHAKE_LEN], version: u32) -> bool {
+    bytes == handshake(version)
+}
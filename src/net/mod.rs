@@ -1,46 +1,139 @@
 pub mod client;
 pub mod middleware;
 
-use std::io::Write;
+use std::io::{Error, ErrorKind, Write};
 use std::marker::PhantomData;
-use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
-use std::sync::{Arc};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use bytebuffer::ByteBuffer;
 use hashbrown::HashMap;
 use log::warn;
 use mvutils::hashers::U64IdentityHasher;
 use mvutils::save::Savable;
-use mvutils::unsafe_utils::DangerousCell;
 use parking_lot::Mutex;
-use crate::net::sealed::ConnectionType;
+use crate::net::client::{read_frame, read_handshake, ClientEndpoint, Frame, Link, PACKET, PING, PONG};
+use crate::net::middleware::{Middleware, Pipeline, Side};
+use crate::net::middleware::framing::LengthFraming;
 
 mod sealed {
-    pub trait ConnectionType {}
+    pub trait Sealed {}
 }
 
+/// Either [`Server`] or [`Client`]. It is sealed, but can be named to implement [`PacketHandler`].
+pub trait ConnectionType: sealed::Sealed {}
+
 pub struct Server;
 pub struct Client;
 
+impl sealed::Sealed for Server {}
+impl sealed::Sealed for Client {}
 impl ConnectionType for Server {}
 impl ConnectionType for Client {}
 
 pub type ClientId = u64;
 
+/// The id a client ConnectionHandler passes to its [`PacketHandler`] for the server. Client endpoints on the server
+/// start at 1.
+pub const SERVER_ID: ClientId = 0;
+
+/// Settings both sides of a connection have to agree on.
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    /// Frames with a larger payload are never allocated, the peer is disconnected with
+    /// [`DisconnectReason::ProtocolViolation`] instead.
+    pub max_frame_size: u32,
+    /// Sent in the handshake, peers with another version are disconnected before any packet is loaded.
+    pub protocol_version: u32,
+    /// The stages every packet passes through, like compression and encryption.
+    pub middleware: Pipeline,
+    /// How often both sides ping each other. Pings keep quiet connections alive, measure the round trip time and
+    /// never reach the [`PacketHandler`]. Has to be shorter than [`ConnectionConfig::idle_timeout`], otherwise
+    /// listening and connecting fail with [`ErrorKind::InvalidInput`].
+    pub heartbeat_interval: Duration,
+    /// Peers that send nothing for this long, not even a ping, are disconnected with [`DisconnectReason::TimedOut`].
+    /// It is also the longest a write may block for.
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 16 * 1024 * 1024,
+            protocol_version: 1,
+            middleware: Pipeline::new(),
+            heartbeat_interval: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    fn validate(&self) -> std::io::Result<()> {
+        if self.heartbeat_interval >= self.idle_timeout {
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "Heartbeat interval of {:?} has to be shorter than the idle timeout of {:?}",
+                self.heartbeat_interval, self.idle_timeout
+            )));
+        }
+        Ok(())
+    }
+}
+
 pub struct ConnectionHandler<In: Savable, Out: Savable, Type: ConnectionType, Handler: PacketHandler<In>> {
     pub(crate) handler: Handler,
     _phantom: PhantomData<(In, Out, Type)>,
+    pub(crate) config: ConnectionConfig,
 
     endpoints: Option<Arc<Mutex<HashMap<u64, ClientEndpoint, U64IdentityHasher>>>>,
-    connection: Option<Arc<DangerousCell<TcpStream>>>,
+    connection: Option<Arc<TcpStream>>,
+    /// The link of the client's connection, unused by the server which has one per endpoint.
+    link: Arc<Link>,
+    address: Option<SocketAddr>,
+    /// Pings carry the time since this instant, which the pong sends back.
+    started: Instant,
+    /// Whether a client is connected or a server accepts connections.
+    running: AtomicBool,
+    threads: Mutex<Vec<JoinHandle<()>>>,
 }
 
 unsafe impl<In: Savable, Out: Savable, Type: ConnectionType, Handler: PacketHandler<In>> Send for ConnectionHandler<In, Out, Type, Handler> {}
 unsafe impl<In: Savable, Out: Savable, Type: ConnectionType, Handler: PacketHandler<In>> Sync for ConnectionHandler<In, Out, Type, Handler> {}
 
+impl<In: Savable, Out: Savable, Type: ConnectionType, Handler: PacketHandler<In>> Drop for ConnectionHandler<In, Out, Type, Handler> {
+    fn drop(&mut self) {
+        // The threads only upgrade to the handler while they use it, so this runs once the last handle outside of
+        // them is gone. Closing the sockets wakes the readers, which then fail to upgrade and stop.
+        self.running.store(false, Ordering::Release);
+        if let Some(socket) = &self.connection {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        if let Some(endpoints) = &self.endpoints {
+            endpoints.lock().values().for_each(|endpoint| { let _ = endpoint.socket.shutdown(Shutdown::Both); });
+        }
+        self.join_threads();
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum DisconnectReason {
     Disconnected,
     TimedOut,
     Kicked,
+    /// The peer sent a bad handshake, spoke another protocol version or sent a frame larger than
+    /// [`ConnectionConfig::max_frame_size`].
+    ProtocolViolation,
+}
+
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Waits for `thread`, unless it is the current one.
+fn join(thread: JoinHandle<()>) {
+    if thread.thread().id() != std::thread::current().id() && thread.join().is_err() {
+        warn!("A network thread panicked");
+    }
 }
 
 pub trait PacketHandler<In: Savable>: Sized {
@@ -53,63 +146,132 @@ pub trait PacketHandler<In: Savable>: Sized {
 }
 
 impl<In: Savable + 'static, Out: Savable + 'static, Handler: PacketHandler<In> + 'static> ConnectionHandler<In, Out, Server, Handler> {
-    pub fn listen(port: u16, handler: Handler) -> Arc<Self> {
-        let listener = TcpListener::bind(("127.0.0.1", port)).expect("Couldn't startup server!");
+    /// Binds to `address` and accepts connections on a new thread until [`ConnectionHandler::shutdown`] is called.
+    /// Use port 0 to let the system choose a free port, [`ConnectionHandler::local_addr`] returns the one it chose.
+    pub fn listen(address: impl ToSocketAddrs, handler: Handler) -> std::io::Result<Arc<Self>> {
+        Self::listen_with(address, ConnectionConfig::default(), handler)
+    }
+
+    pub fn listen_with(address: impl ToSocketAddrs, config: ConnectionConfig, handler: Handler) -> std::io::Result<Arc<Self>> {
+        config.validate()?;
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
         let this = Arc::new(Self {
             handler,
-            _phantom: PhantomData::default(),
+            _phantom: PhantomData,
+            link: Arc::new(Link::new(config.middleware.for_side(Side::Server))),
+            config,
             endpoints: Some(Arc::new(Mutex::new(HashMap::with_hasher(U64IdentityHasher::default())))),
             connection: None,
+            address: Some(listener.local_addr()?),
+            started: Instant::now(),
+            running: AtomicBool::new(true),
+            threads: Mutex::new(Vec::new()),
         });
 
-        let this2 = this.clone();
-        std::thread::spawn(move || {
-            loop {
-                if let Ok((socket, _)) = listener.accept() {
-                    let endpoint = ClientEndpoint::new(socket, this2.clone());
-                    let id = endpoint.id;
-                    let map = this2.endpoints.clone().unwrap();
-                    map.lock().insert(endpoint.id, endpoint);
-                    this2.handler.connection(&*this2, id);
+        let weak = Arc::downgrade(&this);
+        let accept = std::thread::spawn(move || {
+            // Only holds on to the server while accepting, so it can still be dropped.
+            while let Some(this2) = weak.upgrade().filter(|this| this.running.load(Ordering::Acquire)) {
+                match listener.accept() {
+                    Ok((socket, _)) => {
+                        if socket.set_nonblocking(false).is_err() {
+                            warn!("Couldn't accept connection, the socket cannot block");
+                            continue;
+                        }
+                        let endpoint = ClientEndpoint::new(socket, &this2.config);
+                        this2.endpoints().lock().insert(endpoint.id, endpoint.clone());
+                        this2.handler.connection(&*this2, endpoint.id);
+                        endpoint.start(&this2);
+                    }
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                        drop(this2);
+                        std::thread::sleep(ACCEPT_INTERVAL);
+                    }
+                    Err(error) => warn!("Couldn't accept connection: {error}"),
                 }
             }
         });
-        this
+        this.track(accept);
+        Self::start_heartbeat(&this, Self::ping_all);
+        Ok(this)
+    }
+
+    /// Whether the server still accepts connections.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    /// Stops accepting connections, disconnects every client with [`DisconnectReason::Disconnected`] and waits for
+    /// the threads of the server to finish. Threads are not waited for if this is called from one of them, e.g. from
+    /// a [`PacketHandler`].
+    pub fn shutdown(&self) {
+        if !self.running.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        // The accept thread has to finish first, otherwise it could add an endpoint after they were drained.
+        let accept = {
+            let mut threads = self.threads.lock();
+            (!threads.is_empty()).then(|| threads.remove(0))
+        };
+        if let Some(accept) = accept {
+            join(accept);
+        }
+        let endpoints: Vec<ClientEndpoint> = self.endpoints().lock().drain().map(|(_, endpoint)| endpoint).collect();
+        for endpoint in endpoints {
+            if endpoint.socket.shutdown(Shutdown::Both).is_err() {
+                warn!("Couldn't shutdown connection with {}", endpoint.addr);
+            }
+            self.handler.disconnection(self, endpoint.id, DisconnectReason::Disconnected);
+        }
+        self.join_threads();
+    }
+
+    fn endpoints(&self) -> &Mutex<HashMap<u64, ClientEndpoint, U64IdentityHasher>> {
+        self.endpoints.as_ref().expect("Server has no endpoints!")
     }
 
     pub fn get_client_endpoint(&self, id: ClientId) -> Option<ClientEndpoint> {
-        self.endpoints.clone().unwrap().lock().get(&id).cloned()
+        self.endpoints().lock().get(&id).cloned()
     }
 
     pub fn pop_client_endpoint(&self, id: ClientId) -> Option<ClientEndpoint> {
-        self.endpoints.clone().unwrap().lock().remove(&id)
+        self.endpoints().lock().remove(&id)
     }
 
     pub fn send_all(&self, out: Out) {
-        let mut buffer = ByteBuffer::new();
-        out.save(&mut buffer);
-        let bytes = middleware::encode(buffer.into_vec());
-
-        let map = self.endpoints.clone().unwrap();
-        for endpoint in map.lock().values() {
-            self.send_raw(endpoint.socket.get_mut(), &bytes);
+        let payload = Self::save(out);
+        let endpoints: Vec<ClientEndpoint> = self.endpoints().lock().values().cloned().collect();
+        for endpoint in endpoints {
+            self.send_packet(&endpoint.socket, &endpoint.link, &payload);
         }
     }
 
     pub fn send(&self, id: ClientId, out: Out) {
         if let Some(endpoint) = self.get_client_endpoint(id) {
-            let mut buffer = ByteBuffer::new();
-            out.save(&mut buffer);
-            let bytes = middleware::encode(buffer.into_vec());
-            self.send_raw(endpoint.socket.get_mut(), &bytes);
+            let payload = Self::save(out);
+            self.send_packet(&endpoint.socket, &endpoint.link, &payload);
         }
     }
 
-    pub fn disconnect_all(&self) {
-        let map = self.endpoints.clone().unwrap();
+    /// The round trip time to a client, measured by the last heartbeat. `None` if the client is not connected or
+    /// has not answered a heartbeat yet.
+    pub fn rtt(&self, id: ClientId) -> Option<Duration> {
+        self.get_client_endpoint(id).and_then(|endpoint| endpoint.rtt())
+    }
+
+    fn ping_all(&self) {
+        let endpoints: Vec<ClientEndpoint> = self.endpoints().lock().values().cloned().collect();
+        let Some(ping) = self.control(PING, self.now()) else { return; };
+        for endpoint in endpoints {
+            self.send_raw(&endpoint.socket, &endpoint.link, |_| Some(ping.clone()));
+        }
+    }
 
-        for endpoint in map.lock().values() {
-            if let Err(_) = endpoint.socket.get().shutdown(Shutdown::Both) {
+    pub fn disconnect_all(&self) {
+        let endpoints: Vec<ClientEndpoint> = self.endpoints().lock().values().cloned().collect();
+        for endpoint in endpoints {
+            if endpoint.socket.shutdown(Shutdown::Both).is_err() {
                 warn!("Couldn't shutdown connection with {}", endpoint.addr);
             }
         }
@@ -117,7 +279,7 @@ impl<In: Savable + 'static, Out: Savable + 'static, Handler: PacketHandler<In> +
 
     pub fn disconnect(&self, id: ClientId, reason: DisconnectReason) {
         if let Some(endpoint) = self.pop_client_endpoint(id) {
-            if let Err(_) = endpoint.socket.get().shutdown(Shutdown::Both) {
+            if endpoint.socket.shutdown(Shutdown::Both).is_err() {
                 warn!("Couldn't shutdown connection with {}", endpoint.addr);
             }
             self.handler.disconnection(self, id, reason);
@@ -125,36 +287,205 @@ impl<In: Savable + 'static, Out: Savable + 'static, Handler: PacketHandler<In> +
     }
 }
 
-impl<In: Savable, Out: Savable, Handler: PacketHandler<In>> ConnectionHandler<In, Out, Client, Handler> {
+impl<In: Savable + 'static, Out: Savable + 'static, Handler: PacketHandler<In> + 'static> ConnectionHandler<In, Out, Client, Handler> {
+    /// Connects to a server and starts a thread that passes every packet it sends to [`PacketHandler::incoming`], with
+    /// [`SERVER_ID`] as the id.
     pub fn connect(address: impl ToSocketAddrs, handler: Handler) -> std::io::Result<Arc<Self>> {
+        Self::connect_with(address, ConnectionConfig::default(), handler)
+    }
+
+    pub fn connect_with(address: impl ToSocketAddrs, config: ConnectionConfig, handler: Handler) -> std::io::Result<Arc<Self>> {
+        config.validate()?;
+        let mut socket = TcpStream::connect(address)?;
+        let _ = socket.set_read_timeout(Some(config.idle_timeout));
+        let _ = socket.set_write_timeout(Some(config.idle_timeout));
+        let link = Link::new(config.middleware.for_side(Side::Client));
+        socket.write_all(&link.hello(middleware::handshake(config.protocol_version)))?;
         let this = Arc::new(Self {
             handler,
-            _phantom: PhantomData::default(),
+            _phantom: PhantomData,
+            link: Arc::new(link),
+            config,
             endpoints: None,
-            connection: Some(Arc::new(DangerousCell::new(TcpStream::connect(address)?))),
+            address: socket.local_addr().ok(),
+            connection: Some(Arc::new(socket)),
+            started: Instant::now(),
+            running: AtomicBool::new(true),
+            threads: Mutex::new(Vec::new()),
+        });
+
+        let weak = Arc::downgrade(&this);
+        let socket = this.connection.clone().expect("Client has no connection!");
+        let config = this.config.clone();
+        let link = this.link.clone();
+        let reader = std::thread::spawn(move || {
+            if let Err(reason) = read_handshake(&socket, config.protocol_version, &link) {
+                if let Some(this2) = weak.upgrade() {
+                    this2.close(reason);
+                }
+                return;
+            }
+            loop {
+                let frame = read_frame::<In>(&socket, &config, &link);
+                let Some(this2) = weak.upgrade() else { break; };
+                match frame {
+                    Ok(frame) => {
+                        if let Some(packet) = this2.handle_frame(&socket, &this2.link, frame) {
+                            this2.handler.incoming(&*this2, SERVER_ID, packet);
+                        }
+                    }
+                    Err(reason) => {
+                        this2.close(reason);
+                        break;
+                    }
+                }
+            }
         });
+        this.track(reader);
+        Self::start_heartbeat(&this, Self::ping);
         Ok(this)
     }
 
     pub fn send(&self, out: Out) {
-        let mut buffer = ByteBuffer::new();
-        out.save(&mut buffer);
-        let tcp = self.connection.clone().unwrap();
-        self.send_raw(tcp.get_mut(), &middleware::encode(buffer.into_vec()));
+        let payload = Self::save(out);
+        self.send_packet(self.socket(), &self.link, &payload);
     }
 
-    pub fn disconnect(self) {
-        let tcp = self.connection.clone().unwrap();
-        if let Err(_) = tcp.get_mut().shutdown(Shutdown::Both) {
+    /// The round trip time to the server, measured by the last heartbeat. `None` until the server answered one.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.link.rtt()
+    }
+
+    fn ping(&self) {
+        self.send_raw(self.socket(), &self.link, |_| self.control(PING, self.now()));
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    fn socket(&self) -> &TcpStream {
+        self.connection.as_deref().expect("Client has no connection!")
+    }
+
+    /// Disconnects and waits for the reader thread to finish, unless this is called from it.
+    pub fn disconnect(&self) {
+        self.close(DisconnectReason::Disconnected);
+        self.join_threads();
+    }
+
+    /// Shuts the connection down and fires [`PacketHandler::disconnection`], unless that already happened.
+    fn close(&self, reason: DisconnectReason) {
+        if !self.running.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        if self.socket().shutdown(Shutdown::Both).is_err() {
             warn!("Couldn't shutdown connection");
         }
+        self.wake_threads();
+        self.handler.disconnection(self, SERVER_ID, reason);
     }
 }
 
 impl<In: Savable, Out: Savable, Type: ConnectionType, Handler: PacketHandler<In>> ConnectionHandler<In, Out, Type, Handler> {
-    fn send_raw(&self, socket: &mut TcpStream, data: &[u8]) {
+    /// The address the server listens on, or the local address of the client's connection.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.address
+    }
+
+    fn save(out: Out) -> Vec<u8> {
+        let mut buffer = ByteBuffer::new();
+        out.save(&mut buffer);
+        buffer.into_vec()
+    }
+
+    /// Runs a saved packet through the middleware of a connection and the length framing. `None` if the frame is
+    /// larger than [`ConnectionConfig::max_frame_size`], the peer would disconnect instead of reading it.
+    fn packet(&self, middleware: &Pipeline, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut data = vec![PACKET];
+        data.extend(middleware.encode(payload.to_vec()));
+        self.frame(data)
+    }
+
+    /// A ping or pong frame, which skips the middleware.
+    fn control(&self, kind: u8, time: u64) -> Option<Vec<u8>> {
+        let mut data = vec![kind];
+        data.extend(time.to_le_bytes());
+        self.frame(data)
+    }
+
+    fn frame(&self, data: Vec<u8>) -> Option<Vec<u8>> {
+        match LengthFraming::new(self.config.max_frame_size).frame(data) {
+            Ok(frame) => Some(frame),
+            Err(error) => {
+                warn!("Frame was not sent: {error}");
+                None
+            }
+        }
+    }
+
+    /// Microseconds since the handler was created.
+    fn now(&self) -> u64 {
+        u64::try_from(self.started.elapsed().as_micros()).unwrap_or(u64::MAX)
+    }
+
+    /// Answers pings and records round trip times, returning the packet if `frame` carried one.
+    pub(crate) fn handle_frame(&self, socket: &TcpStream, link: &Link, frame: Frame<In>) -> Option<In> {
+        match frame {
+            Frame::Packet(packet) => return Some(packet),
+            Frame::Malformed => {}
+            Frame::Ping(time) => self.send_raw(socket, link, |_| self.control(PONG, time)),
+            Frame::Pong(time) => link.set_rtt(Duration::from_micros(self.now().saturating_sub(time))),
+        }
+        None
+    }
+
+    /// Runs `ping` every [`ConnectionConfig::heartbeat_interval`] on a new thread until the handler stops running or
+    /// was dropped.
+    fn start_heartbeat(this: &Arc<Self>, ping: fn(&Self)) where Self: 'static {
+        let weak = Arc::downgrade(this);
+        let interval = this.config.heartbeat_interval;
+        let heartbeat = std::thread::spawn(move || {
+            loop {
+                // Unparked when the handler stops, so it doesn't have to wait out the interval.
+                std::thread::park_timeout(interval);
+                let Some(this) = weak.upgrade().filter(|this| this.running.load(Ordering::Acquire)) else { break; };
+                ping(&this);
+            }
+        });
+        this.track(heartbeat);
+    }
+
+    pub(crate) fn track(&self, thread: JoinHandle<()>) {
+        let mut threads = self.threads.lock();
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(thread);
+    }
+
+    fn wake_threads(&self) {
+        self.threads.lock().iter().for_each(|thread| thread.thread().unpark());
+    }
+
+    fn join_threads(&self) {
+        self.wake_threads();
+        let threads: Vec<JoinHandle<()>> = self.threads.lock().drain(..).collect();
+        threads.into_iter().for_each(join);
+    }
+
+    /// Sends a saved packet once the middleware of the connection received the greeting of the other side. Drops the
+    /// packet if the greeting does not arrive within the idle timeout.
+    fn send_packet(&self, socket: &TcpStream, link: &Link, payload: &[u8]) {
+        if !link.wait_for_greeting(self.config.idle_timeout) {
+            let addr = socket.peer_addr().map(|a| a.to_string()).unwrap_or("<invalid address>".to_string());
+            warn!("Packet for {addr} was dropped, the middleware was never greeted");
+            return;
+        }
+        self.send_raw(socket, link, |middleware| self.packet(middleware, payload));
+    }
+
+    fn send_raw(&self, socket: &TcpStream, link: &Link, frame: impl FnOnce(&Pipeline) -> Option<Vec<u8>>) {
         let addr = socket.peer_addr().map(|a| a.to_string()).unwrap_or("<invalid address>".to_string());
-        if let Err(_) = socket.write_all(data) {
+        if link.write(socket, frame).is_err() {
             warn!("Data could not be written to {addr}");
         }
     }
@@ -0,0 +1,157 @@
+use mvengine::net::middleware::compression::Compression;
+use mvengine::net::middleware::encryption::Encryption;
+use mvengine::net::middleware::framing::LengthFraming;
+use mvengine::net::middleware::{Middleware, Pipeline, Side};
+
+fn hex(text: &str) -> Vec<u8> {
+    text.split_whitespace().map(|byte| u8::from_str_radix(byte, 16).expect("Invalid hex!")).collect()
+}
+
+fn repetitive(len: usize) -> Vec<u8> {
+    b"position update ".iter().copied().cycle().take(len).collect()
+}
+
+#[test]
+fn framing_prefixes_length() {
+    let framing = LengthFraming::new(16);
+    let framed = framing.encode(b"hello".to_vec());
+    assert_eq!(framed, [5, 0, 0, 0, b'h', b'e', b'l', b'l', b'o']);
+    assert_eq!(framing.decode(framed.clone()).expect("Frame was rejected!"), b"hello");
+    assert_eq!(framing.read_frame(&mut framed.as_slice()).expect("Frame was rejected!"), b"hello");
+}
+
+#[test]
+fn framing_rejects_bad_frames() {
+    let framing = LengthFraming::new(16);
+    assert!(framing.decode(vec![1, 0]).is_err());
+    assert!(framing.decode(vec![4, 0, 0, 0, 1]).is_err());
+    assert!(framing.decode(framing.encode(vec![0; 17])).is_err());
+    assert!(framing.frame(vec![0; 17]).is_err());
+    assert_eq!(framing.frame(vec![0; 16]).map(|frame| frame.len()), Ok(20));
+    let oversize = u32::MAX.to_le_bytes();
+    assert_eq!(framing.read_frame(&mut oversize.as_slice()).map_err(|e| e.kind()), Err(std::io::ErrorKind::InvalidData));
+}
+
+#[test]
+fn compression_round_trips() {
+    let compression = Compression::new(64);
+    for data in [Vec::new(), b"tiny".to_vec(), repetitive(10_000), (0..=255).collect()] {
+        assert_eq!(compression.decode(compression.encode(data.clone())).expect("Packet was rejected!"), data);
+    }
+}
+
+#[test]
+fn compression_respects_threshold() {
+    let compression = Compression::new(64);
+    assert_eq!(compression.encode(repetitive(63)).len(), 64);
+    assert!(compression.encode(repetitive(10_000)).len() < 500);
+}
+
+#[test]
+fn compression_rejects_bombs() {
+    let data = Compression::new(0).encode(vec![0; 1024 * 1024]);
+    assert!(Compression::new(0).max_size(1024).decode(data.clone()).is_err());
+    assert_eq!(Compression::new(0).decode(data).map(|data| data.len()), Ok(1024 * 1024));
+}
+
+/// The AEAD test vector from section 2.8.2 of RFC 8439.
+#[test]
+fn encryption_matches_rfc_8439() {
+    let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+    let nonce = [0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+    let aad = hex("50 51 52 53 c0 c1 c2 c3 c4 c5 c6 c7");
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+    let mut expected = hex("
+        d3 1a 8d 34 64 8e 60 db 7b 86 af bc 53 ef 7e c2 a4 ad ed 51 29 6e 08 fe a9 e2 b5 a7 36 ee 62 d6
+        3d be a4 5e 8c a9 67 12 82 fa fb 69 da 92 72 8b 1a 71 de 0a 9e 06 0b 29 05 d6 a5 b6 7e cd 3b 36
+        92 dd bd 7f 2d 77 8b 8c 98 03 ae e3 28 09 1b 58 fa b3 24 e4 fa d6 75 94 55 85 80 8b 48 31 d7 bc
+        3f f4 de f0 8e 4b 7a 9d e5 76 d2 65 86 ce c6 4b 61 16
+    ");
+    expected.extend(hex("1a e1 0b 59 4f 09 e2 6a 7e 90 2e cb d0 60 06 91"));
+
+    let encryption = Encryption::new(key);
+    let sealed = encryption.seal(nonce, &aad, plaintext);
+    assert_eq!(sealed, expected);
+    assert_eq!(encryption.open(nonce, &aad, &sealed).expect("Packet was rejected!"), plaintext);
+}
+
+/// Two sessions with `key` that greeted each other, like both sides of one connection.
+fn sessions(key: [u8; 32]) -> (Encryption, Encryption) {
+    let client = Encryption::new(key);
+    let server = client.fresh();
+    client.greet(&server.greeting()).expect("Greeting was rejected!");
+    server.greet(&client.greeting()).expect("Greeting was rejected!");
+    (client, server)
+}
+
+#[test]
+fn encryption_round_trips_with_fresh_nonces() {
+    let (client, server) = sessions([7; 32]);
+    let data = repetitive(1000);
+    let first = client.encode(data.clone());
+    let second = client.encode(data.clone());
+    assert_ne!(first, second);
+    assert_eq!(server.decode(first).expect("Packet was rejected!"), data);
+    assert_eq!(server.decode(second).expect("Packet was rejected!"), data);
+    assert_eq!(client.decode(server.encode(data.clone())).expect("Packet was rejected!"), data);
+}
+
+#[test]
+fn encryption_rejects_tampering_and_wrong_keys() {
+    let (client, server) = sessions([7; 32]);
+    let stranger = Encryption::new([8; 32]);
+    stranger.greet(&client.greeting()).expect("Greeting was rejected!");
+    let mut encoded = client.encode(b"secret".to_vec());
+    assert!(stranger.decode(encoded.clone()).is_err());
+    encoded[10] ^= 1;
+    assert!(server.decode(encoded).is_err());
+    assert!(server.decode(vec![0; 20]).is_err());
+    assert_eq!(server.decode(client.encode(b"secret".to_vec())).expect("Packet was rejected!"), b"secret");
+}
+
+#[test]
+fn encryption_rejects_replays_and_reflections() {
+    let (client, server) = sessions([7; 32]);
+    let first = client.encode(b"first".to_vec());
+    let second = client.encode(b"second".to_vec());
+    assert!(client.decode(first.clone()).is_err(), "Packet was reflected back to its sender");
+    assert_eq!(server.decode(second.clone()).expect("Packet was rejected!"), b"second");
+    assert!(server.decode(second).is_err(), "Packet was replayed");
+    assert!(server.decode(first).is_err(), "Packet was reordered");
+    assert_eq!(server.decode(client.encode(b"third".to_vec())).expect("Packet was rejected!"), b"third");
+}
+
+#[test]
+fn encryption_keys_differ_between_sessions() {
+    let (first_client, first_server) = sessions([7; 32]);
+    let (second_client, second_server) = sessions([7; 32]);
+    let captured = first_client.encode(b"secret".to_vec());
+    assert_ne!(captured, second_client.encode(b"secret".to_vec()));
+    assert!(second_server.decode(captured.clone()).is_err(), "Packet was replayed into another session");
+    assert_eq!(first_server.decode(captured).expect("Packet was rejected!"), b"secret");
+}
+
+#[test]
+fn encryption_needs_one_greeting_from_the_other_side() {
+    let client = Encryption::new([7; 32]);
+    let server = client.fresh();
+    assert!(server.decode(vec![0; 24]).is_err());
+    assert!(server.greet(&server.greeting()).is_err(), "Own salt was accepted");
+    assert!(server.greet(&[1, 2, 3]).is_err());
+    server.greet(&client.greeting()).expect("Greeting was rejected!");
+    assert!(server.greet(&client.greeting()).is_err(), "Second greeting was accepted");
+}
+
+#[test]
+fn pipeline_runs_stages_in_order() {
+    let pipeline = Pipeline::new().stage(Compression::new(64)).stage(Encryption::new([1; 32]));
+    let (client, server) = (pipeline.for_side(Side::Client), pipeline.for_side(Side::Server));
+    assert_eq!(client.greeting_len(), 8);
+    client.greet(&server.greeting()).expect("Greeting was rejected!");
+    server.greet(&client.greeting()).expect("Greeting was rejected!");
+    let data = repetitive(10_000);
+    let encoded = client.encode(data.clone());
+    assert!(encoded.len() < 500, "Packet was encrypted before it was compressed");
+    assert_eq!(server.decode(encoded).expect("Packet was rejected!"), data);
+    assert_eq!(Pipeline::new().encode(data.clone()), data);
+}
@@ -0,0 +1,334 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use mvengine::net::{Client, ClientId, ConnectionConfig, ConnectionHandler, ConnectionType, DisconnectReason, PacketHandler, Server, SERVER_ID};
+use mvengine::net::middleware::compression::Compression;
+use mvengine::net::middleware::encryption::Encryption;
+use mvengine::net::middleware::{handshake, Pipeline};
+use mvutils::save::{Loader, Savable, Saver};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Message(String);
+
+impl Savable for Message {
+    fn save(&self, saver: &mut impl Saver) {
+        self.0.save(saver);
+    }
+
+    fn load(loader: &mut impl Loader) -> Result<Self, String> {
+        String::load(loader).map(Message)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Connected(ClientId),
+    Disconnected(ClientId, DisconnectReason),
+    Incoming(ClientId, Message),
+}
+
+struct Recorder(Sender<Event>);
+
+impl PacketHandler<Message> for Recorder {
+    fn connection<Out: Savable, Type: ConnectionType>(&self, _: &ConnectionHandler<Message, Out, Type, Self>, id: ClientId) {
+        let _ = self.0.send(Event::Connected(id));
+    }
+
+    fn disconnection<Out: Savable, Type: ConnectionType>(&self, _: &ConnectionHandler<Message, Out, Type, Self>, id: ClientId, reason: DisconnectReason) {
+        let _ = self.0.send(Event::Disconnected(id, reason));
+    }
+
+    fn incoming<Out: Savable, Type: ConnectionType>(&self, _: &ConnectionHandler<Message, Out, Type, Self>, id: ClientId, packet: Message) {
+        let _ = self.0.send(Event::Incoming(id, packet));
+    }
+}
+
+type TestServer = Arc<ConnectionHandler<Message, Message, Server, Recorder>>;
+type TestClient = Arc<ConnectionHandler<Message, Message, Client, Recorder>>;
+
+fn next(events: &Receiver<Event>) -> Event {
+    events.recv_timeout(TIMEOUT).expect("No event arrived in time!")
+}
+
+fn listen() -> (TestServer, Receiver<Event>) {
+    listen_with(ConnectionConfig::default())
+}
+
+fn listen_with(config: ConnectionConfig) -> (TestServer, Receiver<Event>) {
+    let (server_tx, server_rx) = unbounded();
+    let server = ConnectionHandler::listen_with(("127.0.0.1", 0), config, Recorder(server_tx)).expect("Server could not bind!");
+    (server, server_rx)
+}
+
+/// Connects a client to `server`, returning the id the server gave it.
+fn join(server: &TestServer, server_rx: &Receiver<Event>) -> (TestClient, Receiver<Event>, ClientId) {
+    join_with(server, server_rx, ConnectionConfig::default())
+}
+
+fn join_with(server: &TestServer, server_rx: &Receiver<Event>, config: ConnectionConfig) -> (TestClient, Receiver<Event>, ClientId) {
+    let (client_tx, client_rx) = unbounded();
+    let address = server.local_addr().expect("Server has no address!");
+    let client = ConnectionHandler::connect_with(address, config, Recorder(client_tx)).expect("Client could not connect!");
+    let Event::Connected(id) = next(server_rx) else { panic!("Server did not see the connection!") };
+    (client, client_rx, id)
+}
+
+fn connect() -> (TestServer, Receiver<Event>, TestClient, Receiver<Event>, ClientId) {
+    let (server, server_rx) = listen();
+    let (client, client_rx, id) = join(&server, &server_rx);
+    (server, server_rx, client, client_rx, id)
+}
+
+#[test]
+fn packets_reach_both_sides() {
+    let (server, server_rx, client, client_rx, id) = connect();
+
+    client.send(Message("ping".to_string()));
+    assert_eq!(next(&server_rx), Event::Incoming(id, Message("ping".to_string())));
+
+    server.send(id, Message("pong".to_string()));
+    assert_eq!(next(&client_rx), Event::Incoming(SERVER_ID, Message("pong".to_string())));
+
+    server.send_all(Message("everyone".to_string()));
+    assert_eq!(next(&client_rx), Event::Incoming(SERVER_ID, Message("everyone".to_string())));
+
+    client.disconnect();
+    server.shutdown();
+}
+
+#[test]
+fn connection_is_reported_before_the_first_packet() {
+    let (server, server_rx) = listen();
+    for _ in 0..20 {
+        let mut socket = TcpStream::connect(server.local_addr().expect("Server has no address!")).expect("Could not connect!");
+        socket.write_all(b"not a handshake").expect("Could not write!");
+        let Event::Connected(id) = next(&server_rx) else { panic!("Disconnect was reported before the connection!") };
+        assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::ProtocolViolation));
+    }
+    server.shutdown();
+}
+
+#[test]
+fn client_disconnect_reaches_server() {
+    let (server, server_rx, client, client_rx, id) = connect();
+
+    client.disconnect();
+    assert!(!client.is_connected());
+    assert_eq!(next(&client_rx), Event::Disconnected(SERVER_ID, DisconnectReason::Disconnected));
+    assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::Disconnected));
+    assert!(client_rx.recv_timeout(Duration::from_millis(200)).is_err());
+    server.shutdown();
+}
+
+#[test]
+fn kicked_client_is_disconnected() {
+    let (server, server_rx, client, client_rx, id) = connect();
+
+    server.disconnect(id, DisconnectReason::Kicked);
+    assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::Kicked));
+    assert_eq!(next(&client_rx), Event::Disconnected(SERVER_ID, DisconnectReason::Disconnected));
+    assert!(!client.is_connected());
+    assert!(server.get_client_endpoint(id).is_none());
+    server.shutdown();
+}
+
+#[test]
+fn shutdown_disconnects_everyone() {
+    let (server, server_rx) = listen();
+    let (first, first_rx, first_id) = join(&server, &server_rx);
+    let (second, second_rx, second_id) = join(&server, &server_rx);
+    let address = server.local_addr().expect("Server has no address!");
+
+    server.shutdown();
+    assert!(!server.is_running());
+    let disconnected = [next(&server_rx), next(&server_rx)];
+    assert!(disconnected.contains(&Event::Disconnected(first_id, DisconnectReason::Disconnected)));
+    assert!(disconnected.contains(&Event::Disconnected(second_id, DisconnectReason::Disconnected)));
+    assert_eq!(next(&first_rx), Event::Disconnected(SERVER_ID, DisconnectReason::Disconnected));
+    assert_eq!(next(&second_rx), Event::Disconnected(SERVER_ID, DisconnectReason::Disconnected));
+    assert!(!first.is_connected() && !second.is_connected());
+
+    let (client_tx, _client_rx) = unbounded();
+    assert!(ConnectionHandler::<Message, Message, Client, Recorder>::connect(address, Recorder(client_tx)).is_err());
+    assert!(server_rx.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn binding_a_taken_address_fails() {
+    let (server, _server_rx) = listen();
+    let (tx, _rx) = unbounded();
+    let address = server.local_addr().expect("Server has no address!");
+    assert!(ConnectionHandler::<Message, Message, Server, Recorder>::listen(address, Recorder(tx)).is_err());
+    server.shutdown();
+}
+
+#[test]
+fn protocol_version_mismatch_disconnects_both_sides() {
+    let (server, server_rx) = listen_with(ConnectionConfig { protocol_version: 2, ..ConnectionConfig::default() });
+    let (client, client_rx, id) = join(&server, &server_rx);
+
+    client.send(Message("never loaded".to_string()));
+    assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::ProtocolViolation));
+    assert_eq!(next(&client_rx), Event::Disconnected(SERVER_ID, DisconnectReason::ProtocolViolation));
+    assert!(server_rx.recv_timeout(Duration::from_millis(200)).is_err());
+    server.shutdown();
+}
+
+#[test]
+fn oversize_frame_disconnects() {
+    let (server, server_rx) = listen_with(ConnectionConfig { max_frame_size: 16, ..ConnectionConfig::default() });
+    let (client, client_rx, id) = join(&server, &server_rx);
+
+    client.send(Message("short".to_string()));
+    assert_eq!(next(&server_rx), Event::Incoming(id, Message("short".to_string())));
+    client.send(Message("far too long for sixteen bytes".to_string()));
+    assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::ProtocolViolation));
+    assert_eq!(next(&client_rx), Event::Disconnected(SERVER_ID, DisconnectReason::Disconnected));
+    server.shutdown();
+}
+
+#[test]
+fn oversize_frame_is_not_sent() {
+    let small = || ConnectionConfig { max_frame_size: 16, ..ConnectionConfig::default() };
+    let (server, server_rx) = listen_with(small());
+    let (client, client_rx, id) = join_with(&server, &server_rx, small());
+
+    client.send(Message("far too long for sixteen bytes".to_string()));
+    client.send(Message("short".to_string()));
+    assert_eq!(next(&server_rx), Event::Incoming(id, Message("short".to_string())));
+    assert!(client.is_connected());
+    assert!(client_rx.try_recv().is_err());
+    client.disconnect();
+    server.shutdown();
+}
+
+#[test]
+fn foreign_protocol_is_rejected() {
+    let (server, server_rx) = listen();
+    let mut socket = TcpStream::connect(server.local_addr().expect("Server has no address!")).expect("Could not connect!");
+    let Event::Connected(id) = next(&server_rx) else { panic!("Server did not see the connection!") };
+
+    socket.write_all(b"GET / HTTP/1.1\r\n\r\n").expect("Could not write!");
+    assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::ProtocolViolation));
+    server.shutdown();
+}
+
+fn secure(key: [u8; 32]) -> ConnectionConfig {
+    ConnectionConfig {
+        middleware: Pipeline::new().stage(Compression::new(64)).stage(Encryption::new(key)),
+        ..ConnectionConfig::default()
+    }
+}
+
+#[test]
+fn middleware_applies_on_both_sides() {
+    let (server, server_rx) = listen_with(secure([3; 32]));
+    let (client, client_rx, id) = join_with(&server, &server_rx, secure([3; 32]));
+    let long = "compress me ".repeat(1000);
+
+    client.send(Message(long.clone()));
+    assert_eq!(next(&server_rx), Event::Incoming(id, Message(long.clone())));
+    server.send(id, Message("short".to_string()));
+    assert_eq!(next(&client_rx), Event::Incoming(SERVER_ID, Message("short".to_string())));
+    server.send_all(Message(long.clone()));
+    assert_eq!(next(&client_rx), Event::Incoming(SERVER_ID, Message(long)));
+
+    client.disconnect();
+    server.shutdown();
+}
+
+#[test]
+fn encrypted_packets_wait_for_the_greeting() {
+    let (server, server_rx) = listen_with(secure([3; 32]));
+    let (client, client_rx, id) = join_with(&server, &server_rx, secure([3; 32]));
+
+    // The server may not have read the salt of the client yet.
+    server.send(id, Message("welcome".to_string()));
+    assert_eq!(next(&client_rx), Event::Incoming(SERVER_ID, Message("welcome".to_string())));
+    client.disconnect();
+    server.shutdown();
+}
+
+#[test]
+fn wrong_key_disconnects() {
+    let (server, server_rx) = listen_with(secure([3; 32]));
+    let (client, client_rx, id) = join_with(&server, &server_rx, secure([4; 32]));
+
+    client.send(Message("who am i".to_string()));
+    assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::ProtocolViolation));
+    assert_eq!(next(&client_rx), Event::Disconnected(SERVER_ID, DisconnectReason::Disconnected));
+    server.shutdown();
+}
+
+#[test]
+fn dropped_handlers_close_their_connections() {
+    let (server, server_rx, client, client_rx, id) = connect();
+    let weak = Arc::downgrade(&client);
+    drop(client);
+    assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::Disconnected));
+    assert!(weak.upgrade().is_none());
+    assert!(client_rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+    let (client, client_rx, _) = join(&server, &server_rx);
+    let weak = Arc::downgrade(&server);
+    drop(server);
+    assert_eq!(next(&client_rx), Event::Disconnected(SERVER_ID, DisconnectReason::Disconnected));
+    assert!(weak.upgrade().is_none());
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn heartbeat_has_to_be_shorter_than_the_idle_timeout() {
+    let config = ConnectionConfig { heartbeat_interval: Duration::from_secs(10), ..ConnectionConfig::default() };
+    let (tx, _rx) = unbounded();
+    let error = ConnectionHandler::<Message, Message, Server, Recorder>::listen_with(("127.0.0.1", 0), config.clone(), Recorder(tx.clone()));
+    assert_eq!(error.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+
+    let (server, _server_rx) = listen();
+    let address = server.local_addr().expect("Server has no address!");
+    let error = ConnectionHandler::<Message, Message, Client, Recorder>::connect_with(address, config, Recorder(tx));
+    assert_eq!(error.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+    server.shutdown();
+}
+
+fn heartbeat() -> ConnectionConfig {
+    ConnectionConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        idle_timeout: Duration::from_millis(300),
+        ..ConnectionConfig::default()
+    }
+}
+
+#[test]
+fn idle_clients_stay_connected() {
+    let (server, server_rx) = listen_with(heartbeat());
+    let (client, client_rx, id) = join_with(&server, &server_rx, heartbeat());
+
+    // Heartbeats are never passed to the handler.
+    assert!(server_rx.recv_timeout(Duration::from_secs(1)).is_err());
+    assert!(client_rx.try_recv().is_err());
+    assert!(client.is_connected());
+    assert!(server.rtt(id).is_some());
+    assert!(client.rtt().is_some());
+
+    client.send(Message("still here".to_string()));
+    assert_eq!(next(&server_rx), Event::Incoming(id, Message("still here".to_string())));
+    client.disconnect();
+    server.shutdown();
+}
+
+#[test]
+fn silent_peer_times_out() {
+    let (server, server_rx) = listen_with(heartbeat());
+    let mut socket = TcpStream::connect(server.local_addr().expect("Server has no address!")).expect("Could not connect!");
+    let Event::Connected(id) = next(&server_rx) else { panic!("Server did not see the connection!") };
+
+    socket.write_all(&handshake(1)).expect("Could not write!");
+    assert_eq!(next(&server_rx), Event::Disconnected(id, DisconnectReason::TimedOut));
+    assert!(server.rtt(id).is_none());
+    server.shutdown();
+}